@@ -0,0 +1,82 @@
+//! Bit-level writer and reader, most significant bit first.
+
+/// Writes bits into a fixed buffer.
+pub struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    /// Continue writing `buf` at bit `pos`.
+    pub fn new(buf: &'a mut [u8], pos: usize) -> Self {
+        Self { buf, pos }
+    }
+
+    /// Number of bits written into the buffer.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u64, 1)
+    }
+
+    /// Write the lowest `n` bits of `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer doesn't have `n` bits left.
+    pub fn write_bits(&mut self, value: u64, mut n: u32) {
+        debug_assert!(n <= 64);
+        while n > 0 {
+            let byte = self.pos / 8;
+            let free = 8 - (self.pos % 8) as u32;
+            let take = free.min(n);
+            let mask = ((1u16 << take) - 1) as u8;
+            let chunk = (value >> (n - take)) as u8 & mask;
+            let shift = free - take;
+            self.buf[byte] = (self.buf[byte] & !(mask << shift)) | (chunk << shift);
+            self.pos += take as usize;
+            n -= take;
+        }
+    }
+}
+
+/// Reads bits written by [`BitWriter`].
+pub struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    #[inline]
+    pub fn read_bit(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    /// Read `n` bits into the low bits of the result.
+    pub fn read_bits(&mut self, mut n: u32) -> Option<u64> {
+        debug_assert!(n <= 64);
+        if self.buf.len() * 8 - self.pos < n as usize {
+            return None;
+        }
+
+        let mut value = 0u64;
+        while n > 0 {
+            let byte = self.buf[self.pos / 8];
+            let available = 8 - (self.pos % 8) as u32;
+            let take = available.min(n);
+            let chunk = (byte >> (available - take)) as u64 & ((1 << take) - 1);
+            value = (value << take) | chunk;
+            self.pos += take as usize;
+            n -= take;
+        }
+
+        Some(value)
+    }
+}
@@ -1 +1,10 @@
-pub use crate::cell::CacheCell as Cache;
+use crate::cell::{Bytes, CacheCell, Id};
+use crate::pool::HeapPool;
+use crate::sync::Local;
+
+/// The byte-oriented cache used by loads.
+pub type Cache<K = Id, V = Bytes, P = HeapPool> = CacheCell<K, V, P>;
+
+/// A [`Cache`] owned by one thread, for shards that never share it. It has
+/// the same API without taking locks or atomics, and is not `Sync`.
+pub type LocalCache<K = Id, V = Bytes, P = HeapPool> = CacheCell<K, V, P, Local>;
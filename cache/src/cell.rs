@@ -1,40 +1,1279 @@
+use std::borrow::Borrow;
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
-use std::sync::RwLock;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::ops::RangeBounds;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::item::Item;
+use crate::codec::{BlockCodec, Codec, CodecStats};
+use crate::error::Error;
+use crate::evict::{EvictionMode, EvictionPolicy};
+use crate::item::{Block, Blocks, Fragmentation, Item, SharedBytes, BLOCK_SIZE};
+use crate::pool::{BlockPool, HeapPool};
+use crate::scan::{ScanCursor, ScanPage};
+use crate::series::{Point, SeriesItem};
+use crate::snapshot::{read_snapshot, Record, SnapshotWriter};
+use crate::stats::{CacheStats, ItemStats};
+use crate::sync::{Counter, Lock, SyncMode, Threaded};
+use crate::time::{RealTime, TimeSource};
+use crate::value::{Key, Value};
 
 pub type Bytes = Vec<u8>;
 pub type BytesRef<'a> = &'a [u8];
 pub type Id = usize;
 
-#[derive(Default)]
-pub struct CacheCell {
+/// Accesses buffered per thread before the policy is told of them.
+const ACCESS_BATCH: usize = 64;
+/// Buffers of accesses, threads share one when there are more.
+const ACCESS_STRIPES: usize = 16;
+
+thread_local! {
+    /// The access buffer of the current thread.
+    static ACCESS_STRIPE: usize = {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        NEXT.fetch_add(1, Relaxed) % ACCESS_STRIPES
+    };
+}
+
+struct Eviction<K, S: SyncMode> {
+    /// Bytes of blocks the cache may hold.
+    capacity: usize,
+    mode: EvictionMode,
+    policy: S::Mutex<Box<dyn EvictionPolicy<K>>>,
+    /// Keys accessed and not yet told to the policy, so reads and appends
+    /// take its lock once per [`ACCESS_BATCH`] accesses.
+    accesses: Vec<S::Mutex<Vec<K>>>,
+}
+
+impl<K, S: SyncMode> Eviction<K, S> {
+    /// Tell the policy all buffered accesses, before it picks victims.
+    fn drain(&self, policy: &mut dyn EvictionPolicy<K>) -> Result<(), Error> {
+        for stripe in &self.accesses {
+            stripe
+                .write()?
+                .drain(..)
+                .for_each(|key| policy.on_access(&key));
+        }
+        Ok(())
+    }
+}
+
+struct Retention<K> {
+    /// Applies to ids without their own setting.
+    default: Option<Duration>,
+    /// `None` keeps the id forever.
+    ids: BTreeMap<K, Option<Duration>>,
+}
+
+impl<K: Key> Retention<K> {
+    /// Blocks last written before the returned instant are expired.
+    fn cutoff(&self, key: &K, now: Instant) -> Option<Instant> {
+        let ttl = self.ids.get(key).copied().unwrap_or(self.default)?;
+        now.checked_sub(ttl)
+    }
+}
+
+/// Items whose blocks expire by retention.
+trait Expire {
+    fn has_expired(&self, cutoff: Instant) -> bool;
+
+    fn expire<P: BlockPool>(&mut self, cutoff: Instant, pool: &P) -> usize;
+
+    fn memory(&self) -> usize;
+}
+
+impl<V> Expire for Item<V> {
+    fn has_expired(&self, cutoff: Instant) -> bool {
+        Item::has_expired(self, cutoff)
+    }
+
+    fn expire<P: BlockPool>(&mut self, cutoff: Instant, pool: &P) -> usize {
+        Item::expire(self, cutoff, pool)
+    }
+
+    fn memory(&self) -> usize {
+        Item::memory(self)
+    }
+}
+
+impl Expire for SeriesItem {
+    fn has_expired(&self, cutoff: Instant) -> bool {
+        SeriesItem::has_expired(self, cutoff)
+    }
+
+    fn expire<P: BlockPool>(&mut self, cutoff: Instant, pool: &P) -> usize {
+        SeriesItem::expire(self, cutoff, pool)
+    }
+
+    fn memory(&self) -> usize {
+        SeriesItem::memory(self)
+    }
+}
+
+/// Counters of a cache since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounters {
+    /// Lookups that found the id.
+    pub hits: u64,
+    /// Lookups that didn't find the id.
+    pub misses: u64,
+    /// Items or blocks dropped to stay in capacity.
+    pub evictions: u64,
+    /// Bytes allocated for blocks now.
+    pub memory: usize,
+}
+
+/// A cache of items appended by id. With `S` as [`Threaded`] it's shared
+/// between threads, with [`Local`](crate::Local) it's owned by one and pays
+/// for no synchronization, see [`LocalCache`](crate::LocalCache).
+pub struct CacheCell<K = Id, V = Bytes, P = HeapPool, S: SyncMode = Threaded> {
     // todo: only keep [Item]'s reference.
-    items: RwLock<BTreeMap<Id, RwLock<Item>>>,
+    items: S::RwLock<BTreeMap<K, S::RwLock<Item<V>>>>,
+    series: S::RwLock<BTreeMap<K, S::RwLock<SeriesItem>>>,
+    eviction: Option<Eviction<K, S>>,
+    /// Locked before `items` and `series` by everything that takes both.
+    retention: S::RwLock<Retention<K>>,
+    time: Arc<dyn TimeSource>,
+    memory: S::Counter,
+    hits: S::Counter,
+    misses: S::Counter,
+    evictions: S::Counter,
+    pool: P,
+    block_size: usize,
+    codec: Option<Arc<Codec>>,
+    /// Last id compacted, [`CacheCell::compact`] goes on after it.
+    compacted: S::Mutex<Option<K>>,
 }
 
-impl CacheCell {
+impl<K, V, S: SyncMode> Default for CacheCell<K, V, HeapPool, S> {
+    fn default() -> Self {
+        Self {
+            items: Lock::new(BTreeMap::new()),
+            series: Lock::new(BTreeMap::new()),
+            eviction: None,
+            retention: Lock::new(Retention {
+                default: None,
+                ids: BTreeMap::new(),
+            }),
+            time: Arc::new(RealTime),
+            memory: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            evictions: Default::default(),
+            pool: HeapPool,
+            block_size: BLOCK_SIZE,
+            codec: None,
+            compacted: Lock::new(None),
+        }
+    }
+}
+
+impl<K: Key, V: Value, S: SyncMode> CacheCell<K, V, HeapPool, S> {
+    /// A cache holding at most `capacity` bytes of blocks. Appends going over
+    /// it evict victims picked by `policy` until it fits again.
+    pub fn with_capacity<P>(capacity: usize, mode: EvictionMode, policy: P) -> Self
+    where
+        P: EvictionPolicy<K> + 'static,
+    {
+        Self {
+            eviction: Some(Eviction {
+                capacity,
+                mode,
+                policy: Lock::new(Box::new(policy) as Box<dyn EvictionPolicy<K>>),
+                accesses: (0..ACCESS_STRIPES)
+                    .map(|_| Lock::new(Vec::with_capacity(ACCESS_BATCH)))
+                    .collect(),
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+impl<K: Key, V: Value, P: BlockPool, S: SyncMode> CacheCell<K, V, P, S> {
+    /// Drop blocks of every id when they are not written for `ttl`, unless
+    /// the id has its own retention.
+    pub fn with_retention(self, ttl: Duration) -> Self {
+        self.retention.write().unwrap().default = Some(ttl);
+        self
+    }
+
+    /// Read time from `time` instead of the system clock.
+    pub fn with_time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Take blocks from `pool` instead of the global allocator.
+    pub fn with_block_pool<Q: BlockPool>(self, pool: Q) -> CacheCell<K, V, Q, S> {
+        CacheCell {
+            items: self.items,
+            series: self.series,
+            eviction: self.eviction,
+            retention: self.retention,
+            time: self.time,
+            memory: self.memory,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            pool,
+            block_size: self.block_size,
+            codec: self.codec,
+            compacted: self.compacted,
+        }
+    }
+
+    /// Allocate blocks of `size` bytes instead of 16 KiB. Small blocks waste
+    /// less on small items, large ones take fewer allocations. Blocks
+    /// already written keep their size until compacted. Blocks of points
+    /// are at least 19 bytes, enough for any point.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "block size of 0");
+        self.block_size = size;
+        self
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Compress blocks with `codec` once they are full, which is when the
+    /// next block of their item is started. Compressed blocks are
+    /// decompressed on every read, trading CPU for memory. Blocks that
+    /// compress poorly are kept as they are.
+    pub fn with_codec(mut self, codec: impl BlockCodec + 'static) -> Self {
+        self.codec = Some(Arc::new(Codec::new(Box::new(codec))));
+        self
+    }
+
+    /// What the codec did so far, `None` without one.
+    pub fn codec_stats(&self) -> Option<CodecStats> {
+        self.codec.as_ref().map(|codec| codec.stats())
+    }
+
+    /// Where blocks of this cache come from.
+    pub fn block_pool(&self) -> &P {
+        &self.pool
+    }
+
+    /// Like [`CacheCell::with_retention`], on a cache in use. `None` keeps
+    /// ids without their own retention forever.
+    pub fn set_default_retention(&self, ttl: Option<Duration>) -> Result<(), Error> {
+        self.retention.write()?.default = ttl;
+        Ok(())
+    }
+
+    /// Set retention of `id`, overriding the cache's. `None` keeps it forever.
+    /// The setting goes with the id, once it's removed, evicted or swept
+    /// away: written again, it has the cache's retention.
+    pub fn set_retention(&self, id: K, ttl: Option<Duration>) -> Result<(), Error> {
+        self.retention.write()?.ids.insert(id, ttl);
+        Ok(())
+    }
+
     /// Get random `size`.
-    pub fn get(&self, id: Id, size: usize) -> Option<Bytes> {
-        Some(
-            self.items
-                .read()
-                .unwrap()
-                .get(&id)?
-                .read()
-                .unwrap()
-                .get(size),
-        )
+    pub fn get<Q>(&self, id: &Q, size: usize) -> Result<V, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.items, id, |item| item.get(size))
+    }
+
+    /// Read `len` bytes of `id` starting at `offset`, in the order they were
+    /// appended. The result is shorter than `len` if it reaches the end.
+    pub fn read<Q>(&self, id: &Q, offset: usize, len: usize) -> Result<Bytes, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.items, id, |item| Ok(item.read(offset, len)?))
+    }
+
+    /// Everything appended to `id`.
+    pub fn read_all<Q>(&self, id: &Q) -> Result<V, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.items, id, |item| item.read_all())
+    }
+
+    /// Like [`CacheCell::get`], but shares the cached blocks instead of
+    /// copying them.
+    pub fn get_shared<Q>(&self, id: &Q, size: usize) -> Result<SharedBytes, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.items, id, |item| Ok(item.get_shared(size)?))
+    }
+
+    /// Like [`CacheCell::read`], but shares the cached blocks instead of
+    /// copying them. Later writes to `id` don't change the returned bytes.
+    pub fn read_shared<Q>(&self, id: &Q, offset: usize, len: usize) -> Result<SharedBytes, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.items, id, |item| Ok(item.read_shared(offset, len)?))
+    }
+
+    /// Everything appended to `id`, sharing the cached blocks.
+    pub fn read_all_shared<Q>(&self, id: &Q) -> Result<SharedBytes, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.read_shared(id, 0, usize::MAX)
+    }
+
+    /// Appended bytes of `id`.
+    pub fn len<Q>(&self, id: &Q) -> Result<usize, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let retention = self.retention.read()?;
+        let items = self.items.read()?;
+        let (key, item) = items.get_key_value(id).ok_or(Error::NotFound)?;
+        let len = self.read_live(&retention, key, item)?.len();
+        Ok(len)
+    }
+
+    /// Call `f` with the blocks of `id`, while holding the item's read lock.
+    /// Blocks are checked against their checksum first.
+    pub fn with_blocks<Q, F, R>(&self, id: &Q, f: F) -> Result<R, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        F: FnOnce(Blocks<'_>) -> R,
+    {
+        self.lookup(&self.items, id, |item| Ok(f(item.blocks()?)))
+    }
+
+    /// Append `value` to `id`. Fails without writing if `value` alone needs
+    /// more blocks than the capacity.
+    pub fn append(&self, id: K, value: V) -> Result<(), Error> {
+        let bytes = value.encode();
+        self.check_capacity(bytes.len())?;
+        let now = self.time.now();
+        self.update(&self.items, id, |item| {
+            item.put_bytes(
+                &bytes,
+                now,
+                &self.pool,
+                self.block_size,
+                self.codec.as_ref(),
+            )
+        })
+    }
+
+    /// Drop everything of `id`, both bytes and points.
+    pub fn remove<Q>(&self, id: &Q) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut retention = self.retention.write()?;
+        let mut items = self.items.write()?;
+        let mut series = self.series.write()?;
+        retention.ids.remove(id);
+        let item = items.remove_entry(id);
+        let points = series.remove_entry(id);
+
+        let mut freed = 0;
+        let mut key = None;
+        if let Some((k, item)) = item {
+            freed += item.into_inner()?.clear(&self.pool);
+            key = Some(k);
+        }
+        if let Some((k, points)) = points {
+            freed += points.into_inner()?.clear(&self.pool);
+            key = Some(k);
+        }
+        self.memory.sub(freed);
+
+        match key {
+            Some(key) => self.on_remove(&key),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Keep the first `len` bytes of `id` and release the rest.
+    pub fn truncate<Q>(&self, id: &Q, len: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let items = self.items.read()?;
+        let item = items.get(id).ok_or(Error::NotFound)?;
+        Ok(self.apply(item, |item| item.truncate(len, &self.pool))??)
+    }
+
+    /// Replace bytes of `id` with `value`, releasing the old blocks. Fails
+    /// without writing if `value` alone needs more blocks than the capacity.
+    pub fn replace(&self, id: K, value: V) -> Result<(), Error> {
+        let bytes = value.encode();
+        self.check_capacity(bytes.len())?;
+        let now = self.time.now();
+        self.update(&self.items, id, |item| {
+            item.clear(&self.pool);
+            item.put_bytes(
+                &bytes,
+                now,
+                &self.pool,
+                self.block_size,
+                self.codec.as_ref(),
+            );
+        })
+    }
+
+    /// Append a point to the series of `id`. Series are kept apart from the
+    /// bytes appended by [`CacheCell::append`].
+    pub fn append_point(&self, id: K, timestamp: i64, value: f64) -> Result<(), Error> {
+        let now = self.time.now();
+        self.update(&self.series, id, |item| {
+            item.append_at(timestamp, value, now, &self.pool, self.block_size)
+        })
+    }
+
+    /// Fail if `len` bytes need more blocks than the cache may hold.
+    fn check_capacity(&self, len: usize) -> Result<(), Error> {
+        match &self.eviction {
+            Some(eviction) => {
+                let needed = len.div_ceil(self.block_size) * Block::memory_of(self.block_size);
+                if needed > eviction.capacity {
+                    return Err(Error::CapacityExceeded {
+                        needed,
+                        capacity: eviction.capacity,
+                    });
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run `f` on the item of `id` in `map`, then evict if the cache grows
+    /// over capacity. Existing ids only take the map's read lock and the
+    /// item's write lock, so writers of different ids run in parallel. The
+    /// map's write lock is taken only to insert `id`.
+    fn update<T: Expire + Default>(
+        &self,
+        map: &S::RwLock<BTreeMap<K, S::RwLock<T>>>,
+        id: K,
+        f: impl FnOnce(&mut T),
+    ) -> Result<(), Error> {
+        {
+            let items = map.read()?;
+            if let Some((key, item)) = items.get_key_value(&id) {
+                self.access(key)?;
+                self.apply(item, f)?;
+            } else {
+                drop(items);
+                let mut items = map.write()?;
+                // Others may insert `id` between the two locks.
+                let item = match items.entry(id) {
+                    Entry::Vacant(entry) => {
+                        self.on_insert(entry.key())?;
+                        entry.insert(Lock::new(T::default()))
+                    }
+                    Entry::Occupied(entry) => {
+                        self.access(entry.key())?;
+                        entry.into_mut()
+                    }
+                };
+                self.apply(item, f)?;
+            }
+        }
+
+        self.evict()
     }
 
-    pub fn append(&self, id: usize, bytes: Bytes) {
-        self.items
-            .write()
-            .unwrap()
-            .entry(id)
-            .or_default()
-            .write()
-            .unwrap()
-            .put(bytes)
+    /// Run `f` on `item` under its write lock, accounting memory it changes.
+    fn apply<T: Expire, R>(
+        &self,
+        item: &S::RwLock<T>,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Error> {
+        let mut item = item.write()?;
+        let before = item.memory();
+        let result = f(&mut item);
+        let after = item.memory();
+        if after >= before {
+            self.memory.add(after - before);
+        } else {
+            self.memory.sub(before - after);
+        }
+        Ok(result)
+    }
+
+    /// Run `f` on the live item of `id` in `map`, counting the lookup.
+    fn lookup<T: Expire, Q, R>(
+        &self,
+        map: &S::RwLock<BTreeMap<K, S::RwLock<T>>>,
+        id: &Q,
+        f: impl FnOnce(&T) -> Result<R, Error>,
+    ) -> Result<R, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let retention = self.retention.read()?;
+        let items = map.read()?;
+        // An id whose blocks all expired is missing, until a sweep drops it.
+        let found = match items.get_key_value(id) {
+            Some((key, item)) => self
+                .read_live(&retention, key, item)
+                .map(|item| (key, item)),
+            None => Err(Error::NotFound),
+        };
+        drop(retention);
+        let (key, item) = found.inspect_err(|err| {
+            if let Error::NotFound = err {
+                self.record_lookup(false);
+            }
+        })?;
+        self.record_lookup(true);
+        self.access(key)?;
+        f(&item)
+    }
+
+    /// Ids in `range` and everything appended to them, at most `limit` in
+    /// id order. Pass the returned cursor as range to get the next page.
+    /// Scans don't count as lookups, nor as accesses for eviction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0, or if `range` starts after it ends.
+    pub fn scan<R: RangeBounds<K>>(&self, range: R, limit: usize) -> Result<ScanPage<K, V>, Error> {
+        assert!(limit > 0, "scan of 0 items");
+        let retention = self.retention.read()?;
+        let items = self.items.read()?;
+        let mut entries = items.range::<K, _>((range.start_bound(), range.end_bound()));
+        let mut page = vec![];
+        for (key, item) in entries.by_ref() {
+            let item = match self.read_live(&retention, key, item) {
+                Ok(item) if !item.is_empty() => item,
+                Ok(_) | Err(Error::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            page.push((key.clone(), item.read_all()?));
+            if page.len() == limit {
+                break;
+            }
+        }
+
+        let next = match page.last() {
+            Some((last, _)) if entries.next().is_some() => Some(ScanCursor::after(last, &range)),
+            _ => None,
+        };
+        Ok(ScanPage { items: page, next })
+    }
+
+    /// Points of `id` with timestamp in `[start, end)`, in append order.
+    pub fn query<Q>(&self, id: &Q, start: i64, end: i64) -> Result<Vec<Point>, Error>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.lookup(&self.series, id, |item| Ok(item.query(start, end)?))
+    }
+
+    /// Drop expired blocks of every id, and ids left without block. Returns
+    /// the bytes released.
+    pub fn sweep(&self) -> Result<usize, Error> {
+        let now = self.time.now();
+        let retention = self.retention.read()?;
+        let mut freed = 0;
+        let mut emptied = vec![];
+        for (key, item) in self.items.read()?.iter() {
+            freed += self.sweep_item(&retention, now, key, item, &mut emptied)?;
+        }
+        for (key, item) in self.series.read()?.iter() {
+            freed += self.sweep_item(&retention, now, key, item, &mut emptied)?;
+        }
+        drop(retention);
+        self.memory.sub(freed);
+
+        if !emptied.is_empty() {
+            let mut retention = self.retention.write()?;
+            let mut items = self.items.write()?;
+            let mut series = self.series.write()?;
+            for key in emptied {
+                if let Some(item) = items.get(&key) {
+                    if item.read()?.memory() == 0 {
+                        items.remove(&key);
+                    }
+                }
+                if let Some(item) = series.get(&key) {
+                    if item.read()?.memory() == 0 {
+                        series.remove(&key);
+                    }
+                }
+                if !items.contains_key(&key) && !series.contains_key(&key) {
+                    retention.ids.remove(&key);
+                    self.on_remove(&key)?;
+                }
+            }
+        }
+
+        Ok(freed)
+    }
+
+    fn sweep_item<T: Expire>(
+        &self,
+        retention: &Retention<K>,
+        now: Instant,
+        key: &K,
+        item: &S::RwLock<T>,
+        emptied: &mut Vec<K>,
+    ) -> Result<usize, Error> {
+        let cutoff = match retention.cutoff(key, now) {
+            Some(cutoff) => cutoff,
+            None => return Ok(0),
+        };
+        let mut item = item.write()?;
+        let freed = item.expire(cutoff, &self.pool);
+        if item.memory() == 0 {
+            emptied.push(key.clone());
+        }
+        Ok(freed)
+    }
+
+    /// Rewrite up to `max_items` items densely, merging their under-filled
+    /// blocks, going on after the last item of the previous call and
+    /// starting over once all are done. Items only shrink, see
+    /// [`Fragmentation::compactable`]. Returns the bytes released.
+    pub fn compact(&self, max_items: usize) -> Result<usize, Error> {
+        let mut compacted = self.compacted.write()?;
+        let items = self.items.read()?;
+        let range = match compacted.take() {
+            Some(key) => items.range::<K, _>((Excluded(&key), Unbounded)),
+            None => items.range::<K, _>(..),
+        };
+
+        let mut freed = 0;
+        let mut visited = 0;
+        for (key, item) in range.take(max_items) {
+            let mut item = item.write()?;
+            freed += item.compact(self.block_size, &self.pool, self.codec.as_ref())?;
+            visited += 1;
+            *compacted = Some(key.clone());
+        }
+        if visited < max_items {
+            *compacted = None;
+        }
+        self.memory.sub(freed);
+        Ok(freed)
+    }
+
+    /// How full the blocks of all items are.
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        let mut fragmentation = Fragmentation::default();
+        for item in self.items.read()?.values() {
+            fragmentation = fragmentation + item.read()?.fragmentation(self.block_size);
+        }
+        Ok(fragmentation)
+    }
+
+    /// Lock `item` for reading, after dropping its expired blocks. Takes the
+    /// retention locked by the caller, before the map holding `item`. Fails
+    /// with [`Error::NotFound`] if no block is left unexpired.
+    fn read_live<'a, T: Expire>(
+        &self,
+        retention: &Retention<K>,
+        key: &K,
+        item: &'a S::RwLock<T>,
+    ) -> Result<<S::RwLock<T> as Lock<T>>::Read<'a>, Error> {
+        let cutoff = match retention.cutoff(key, self.time.now()) {
+            Some(cutoff) => cutoff,
+            None => return item.read(),
+        };
+
+        let mut guard = item.read()?;
+        if guard.has_expired(cutoff) {
+            drop(guard);
+            let freed = item.write()?.expire(cutoff, &self.pool);
+            self.memory.sub(freed);
+            guard = item.read()?;
+        }
+        match guard.memory() {
+            0 => Err(Error::NotFound),
+            _ => Ok(guard),
+        }
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        CacheCounters {
+            hits: self.hits.get() as u64,
+            misses: self.misses.get() as u64,
+            evictions: self.evictions.get() as u64,
+            memory: self.memory.get(),
+        }
+    }
+
+    /// Counters, fragmentation and a summary of every id, bytes and points.
+    /// Writers of new ids wait until every item is read.
+    pub fn stats(&self) -> Result<CacheStats<K>, Error> {
+        let counters = self.counters();
+        let items = self.items.read()?;
+        let series = self.series.read()?;
+        let mut fragmentation = Fragmentation::default();
+        let mut per_id = BTreeMap::new();
+        for (key, item) in items.iter() {
+            let item = item.read()?;
+            let item_fragmentation = item.fragmentation(self.block_size);
+            fragmentation = fragmentation + item_fragmentation;
+            per_id.insert(
+                key,
+                ItemStats {
+                    id: key.clone(),
+                    len: item_fragmentation.used,
+                    blocks: item_fragmentation.blocks,
+                    points: 0,
+                    memory: item.memory(),
+                },
+            );
+        }
+        for (key, points) in series.iter() {
+            let points = points.read()?;
+            let stats = per_id.entry(key).or_insert_with(|| ItemStats {
+                id: key.clone(),
+                len: 0,
+                blocks: 0,
+                points: 0,
+                memory: 0,
+            });
+            stats.points = points.len();
+            stats.memory += points.memory();
+        }
+
+        Ok(CacheStats {
+            series: series.len(),
+            memory: counters.memory,
+            hits: counters.hits,
+            misses: counters.misses,
+            evictions: counters.evictions,
+            fragmentation,
+            codec: self.codec_stats().unwrap_or_default(),
+            per_item: per_id.into_values().collect(),
+        })
+    }
+
+    /// Buffer the access of `key`, telling the policy once the buffer of
+    /// the thread is full. The buffer is swapped out before the policy is
+    /// locked, so other threads keep buffering meanwhile.
+    #[inline]
+    fn access(&self, key: &K) -> Result<(), Error> {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction,
+            None => return Ok(()),
+        };
+        let batch = {
+            let stripe = ACCESS_STRIPE.with(|stripe| *stripe);
+            let mut accesses = eviction.accesses[stripe].write()?;
+            accesses.push(key.clone());
+            if accesses.len() < ACCESS_BATCH {
+                return Ok(());
+            }
+            mem::replace(&mut *accesses, Vec::with_capacity(ACCESS_BATCH))
+        };
+        let mut policy = eviction.policy.write()?;
+        batch.iter().for_each(|key| policy.on_access(key));
+        Ok(())
+    }
+
+    #[inline]
+    fn on_insert(&self, key: &K) -> Result<(), Error> {
+        if let Some(eviction) = &self.eviction {
+            eviction.policy.write()?.on_insert(key);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn on_remove(&self, key: &K) -> Result<(), Error> {
+        if let Some(eviction) = &self.eviction {
+            eviction.policy.write()?.on_remove(key);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn record_lookup(&self, hit: bool) {
+        if hit {
+            self.hits.add(1);
+        } else {
+            self.misses.add(1);
+        }
+    }
+
+    /// Drop victims until memory is within capacity.
+    fn evict(&self) -> Result<(), Error> {
+        let eviction = match &self.eviction {
+            Some(eviction) if self.memory.get() > eviction.capacity => eviction,
+            _ => return Ok(()),
+        };
+
+        let mut retention = self.retention.write()?;
+        let mut items = self.items.write()?;
+        let mut series = self.series.write()?;
+        let mut policy = eviction.policy.write()?;
+        eviction.drain(&mut **policy)?;
+        while self.memory.get() > eviction.capacity {
+            let victim = match policy.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+
+            let mut freed = 0;
+            match eviction.mode {
+                EvictionMode::Item => {
+                    if let Some(item) = items.remove(&victim) {
+                        freed += item.into_inner()?.clear(&self.pool);
+                    }
+                    if let Some(points) = series.remove(&victim) {
+                        freed += points.into_inner()?.clear(&self.pool);
+                    }
+                }
+                EvictionMode::OldestBlock => {
+                    if let Entry::Occupied(mut entry) = items.entry(victim.clone()) {
+                        let item = entry.get_mut().get_mut()?;
+                        freed = item.pop_front_block(&self.pool);
+                        if item.memory() == 0 {
+                            entry.remove();
+                        }
+                    }
+                    if freed == 0 {
+                        if let Entry::Occupied(mut entry) = series.entry(victim.clone()) {
+                            let points = entry.get_mut().get_mut()?;
+                            freed = points.pop_front_block(&self.pool);
+                            if points.memory() == 0 {
+                                entry.remove();
+                            }
+                        }
+                    }
+                }
+            }
+
+            let gone = !items.contains_key(&victim) && !series.contains_key(&victim);
+            if gone {
+                retention.ids.remove(&victim);
+            }
+            // A victim freeing nothing would be picked again forever.
+            if gone || freed == 0 {
+                policy.on_remove(&victim);
+            }
+            if freed > 0 {
+                self.memory.sub(freed);
+                self.evictions.add(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K: Key + Value, V: Value, P: BlockPool, S: SyncMode> CacheCell<K, V, P, S> {
+    /// Write bytes and points of every id to `writer`. Writers of new ids
+    /// wait until it's done. Write times are not kept, restored blocks count
+    /// as written when they are restored. Fails with [`Error::Corrupted`]
+    /// instead of writing a corrupted block.
+    pub fn snapshot<W: Write>(&self, writer: W) -> Result<(), Error> {
+        self.snapshot_at(writer, 0)
+    }
+
+    /// Like [`CacheCell::snapshot`], for a cache holding every record of a
+    /// write-ahead log before `log_seq`. [`CacheCell::restore`] returns it,
+    /// to replay only the records after.
+    pub fn snapshot_at<W: Write>(&self, writer: W, log_seq: u64) -> Result<(), Error> {
+        let mut writer = SnapshotWriter::new(writer, log_seq)?;
+        for (key, item) in self.items.read()?.iter() {
+            let item = item.read()?;
+            writer.write_bytes(&key.clone().encode(), item.blocks()?)?;
+        }
+        for (key, item) in self.series.read()?.iter() {
+            let item = item.read()?;
+            writer.write_points(&key.clone().encode(), item.points()?)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Load a snapshot written by [`CacheCell::snapshot`], replacing ids in
+    /// it. The whole snapshot is checked first, nothing changes if it's
+    /// corrupted. Returns the log seq it was taken at, see
+    /// [`CacheCell::snapshot_at`].
+    pub fn restore<R: Read>(&self, reader: R) -> Result<u64, Error> {
+        let snapshot = read_snapshot(reader)?;
+        // Keys are decoded first too, so none is restored if one is malformed.
+        let records = snapshot
+            .records
+            .into_iter()
+            .map(|mut record| {
+                let key = match &mut record {
+                    Record::Bytes { key, .. } | Record::Points { key, .. } => mem::take(key),
+                };
+                Ok((K::decode(key)?, record))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let now = self.time.now();
+        for (key, record) in records {
+            match record {
+                Record::Bytes { blocks, .. } => self.update(&self.items, key, |item| {
+                    item.clear(&self.pool);
+                    for block in &blocks {
+                        item.put_bytes(
+                            block,
+                            now,
+                            &self.pool,
+                            self.block_size,
+                            self.codec.as_ref(),
+                        );
+                    }
+                })?,
+                Record::Points { points, .. } => self.update(&self.series, key, |item| {
+                    item.clear(&self.pool);
+                    for point in points {
+                        item.append_at(
+                            point.timestamp,
+                            point.value,
+                            now,
+                            &self.pool,
+                            self.block_size,
+                        );
+                    }
+                })?,
+            }
+        }
+        Ok(snapshot.log_seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, Lru, ManualTime, SharedPool};
+
+    #[test]
+    fn small_block_size() {
+        let cache = Cache::default().with_block_size(16);
+        for i in 0..100u8 {
+            cache.append(i as Id % 3, vec![i; 5]).unwrap();
+        }
+        cache.truncate(&0, 7).unwrap();
+        let before: Vec<_> = (0..3).map(|id| cache.read_all(&id).unwrap()).collect();
+
+        cache.compact(10).unwrap();
+        let fragmentation = cache.fragmentation().unwrap();
+        let stats = cache.stats().unwrap();
+
+        let after: Vec<_> = (0..3).map(|id| cache.read_all(&id).unwrap()).collect();
+        assert_eq!(before, after);
+        assert_eq!(fragmentation.used, 7 + 2 * 33 * 5);
+        assert!(fragmentation.capacity <= fragmentation.blocks * 16);
+        assert_eq!(stats.memory, cache.counters().memory);
+    }
+
+    #[test]
+    fn series_blocks_come_from_the_pool() {
+        let cache = Cache::<Id, Bytes>::default().with_block_pool(SharedPool::default());
+        for i in 0..3000 {
+            cache.append_point(0, i, (i % 7) as f64 / 3.0).unwrap();
+        }
+        let allocs = cache.block_pool().stats().allocs;
+        assert!(allocs > 1);
+        assert_eq!(cache.stats().unwrap().memory, cache.counters().memory);
+
+        cache.remove(&0).unwrap();
+        let stats = cache.block_pool().stats();
+        assert_eq!(stats.frees, allocs);
+        assert_eq!(cache.counters().memory, 0);
+        cache.append_point(1, 0, 0.0).unwrap();
+        assert_eq!(cache.block_pool().stats().reuses, stats.reuses + 1);
+    }
+
+    #[test]
+    fn series_blocks_take_the_block_size() {
+        for size in [1, 19, 64, 4096] {
+            let cache: Cache = Cache::default().with_block_size(size);
+            for i in 0..1000 {
+                cache.append_point(0, i * 10, i as f64).unwrap();
+            }
+            let points = cache.query(&0, 0, 10_000).unwrap();
+            assert_eq!(points.len(), 1000);
+            assert!(points
+                .iter()
+                .enumerate()
+                .all(|(i, point)| point.value == i as f64));
+            assert_eq!(cache.stats().unwrap().memory, cache.counters().memory);
+        }
+        let small: Cache = Cache::default().with_block_size(64);
+        let large: Cache = Cache::default().with_block_size(4096);
+        for cache in [&small, &large] {
+            cache.append_point(0, 0, 1.0).unwrap();
+        }
+        assert!(small.counters().memory < large.counters().memory);
+    }
+
+    /// A cache of 16 byte blocks keeping them for 10 seconds, holding
+    /// two full blocks of 1s and a point written at 0s, and a block of 2s
+    /// written at 6s.
+    fn expiring_cache(time: &Arc<ManualTime>) -> Cache {
+        let cache = Cache::default()
+            .with_block_size(16)
+            .with_retention(Duration::from_secs(10))
+            .with_time_source(time.clone());
+        cache.append(0, vec![1; 32]).unwrap();
+        cache.append_point(0, 1, 1.0).unwrap();
+        time.advance(Duration::from_secs(6));
+        cache.append(0, vec![2; 16]).unwrap();
+        cache
+    }
+
+    #[test]
+    fn sweep_drops_expired_blocks() {
+        let time = Arc::new(ManualTime::new());
+        let cache = expiring_cache(&time);
+        cache.set_retention(1, None).unwrap();
+        cache.append(1, vec![3; 16]).unwrap();
+        let memory = cache.counters().memory;
+
+        time.advance(Duration::from_secs(4));
+        assert_eq!(cache.sweep().unwrap(), 0);
+        time.advance(Duration::from_secs(1));
+        let freed = cache.sweep().unwrap();
+        assert!(freed > 0);
+        assert_eq!(cache.counters().memory, memory - freed);
+        assert_eq!(cache.read_all(&0).unwrap(), vec![2; 16]);
+        assert!(matches!(cache.query(&0, 0, 10), Err(Error::NotFound)));
+
+        time.advance(Duration::from_secs(6));
+        cache.sweep().unwrap();
+        assert!(matches!(cache.read_all(&0), Err(Error::NotFound)));
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.per_item.len(), stats.series), (1, 0));
+
+        time.advance(Duration::from_secs(3600));
+        cache.sweep().unwrap();
+        assert_eq!(cache.read_all(&1).unwrap(), vec![3; 16]);
+        assert_eq!(cache.counters().memory, cache.stats().unwrap().memory);
+    }
+
+    #[test]
+    fn reads_never_return_expired_bytes() {
+        let time = Arc::new(ManualTime::new());
+        let cache = expiring_cache(&time);
+        let memory = cache.counters().memory;
+
+        // Expired blocks are dropped by the read, without a sweep.
+        time.advance(Duration::from_secs(5));
+        assert_eq!(cache.len(&0).unwrap(), 16);
+        assert_eq!(cache.read_all(&0).unwrap(), vec![2; 16]);
+        assert_eq!(cache.read(&0, 0, 64).unwrap(), vec![2; 16]);
+        assert_eq!(cache.read_all_shared(&0).unwrap().to_vec(), vec![2; 16]);
+        assert!(cache.get(&0, 16).unwrap().iter().all(|&byte| byte == 2));
+        let page = cache.scan(.., 10).unwrap();
+        assert_eq!(page.items, vec![(0, vec![2; 16])]);
+        // The series expired as a whole, before any sweep drops it.
+        let misses = cache.counters().misses;
+        assert!(matches!(
+            cache.query(&0, i64::MIN, i64::MAX),
+            Err(Error::NotFound)
+        ));
+        assert_eq!(cache.counters().misses, misses + 1);
+
+        time.advance(Duration::from_secs(10));
+        let hits = cache.counters().hits;
+        assert!(matches!(cache.read_all(&0), Err(Error::NotFound)));
+        assert!(matches!(cache.len(&0), Err(Error::NotFound)));
+        assert!(cache.scan(.., 10).unwrap().items.is_empty());
+        assert_eq!(cache.counters().hits, hits);
+        assert!(cache.counters().memory < memory);
+        assert_eq!(cache.counters().memory, cache.stats().unwrap().memory);
+    }
+
+    #[test]
+    fn sweep_alongside_lookups_and_retention_changes() {
+        let time = Arc::new(ManualTime::new());
+        let cache: Arc<Cache> = Arc::new(
+            Cache::default()
+                .with_retention(Duration::from_secs(10))
+                .with_time_source(time.clone()),
+        );
+        let threads: Vec<_> = (0..3)
+            .map(|thread| {
+                let cache = cache.clone();
+                let time = time.clone();
+                std::thread::spawn(move || {
+                    for i in 0..2000 {
+                        let id = i % 8;
+                        match thread {
+                            0 => {
+                                cache.sweep().unwrap();
+                                time.advance(Duration::from_millis(50));
+                            }
+                            1 => {
+                                let _ = cache.len(&id);
+                                let _ = cache.read_all(&id);
+                                cache.scan(.., 4).unwrap();
+                            }
+                            _ => {
+                                cache.append(id, vec![1; 100]).unwrap();
+                                let ttl = Duration::from_secs(i as u64 % 20);
+                                cache.set_retention(id, Some(ttl)).unwrap();
+                                if i % 5 == 0 {
+                                    let _ = cache.remove(&id);
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn buffered_accesses_count_before_eviction() {
+        let block = BLOCK_SIZE;
+        let memory = Block::memory_of(block);
+        let cache: Cache = Cache::with_capacity(2 * memory, EvictionMode::Item, Lru::new());
+        cache.append(0, vec![0; block]).unwrap();
+        cache.append(1, vec![1; block]).unwrap();
+        // Fewer accesses than a batch, the policy hears of them at eviction.
+        cache.read_all(&0).unwrap();
+        cache.append(2, vec![2; block]).unwrap();
+        assert!(cache.read_all(&0).is_ok());
+        assert!(matches!(cache.read_all(&1), Err(Error::NotFound)));
+
+        // Accesses buffered for a removed id don't track it again.
+        cache.read_all(&2).unwrap();
+        cache.remove(&2).unwrap();
+        let eviction = cache.eviction.as_ref().unwrap();
+        let mut policy = eviction.policy.write().unwrap();
+        eviction.drain(&mut **policy).unwrap();
+        assert_eq!(policy.victim(), Some(0));
+        policy.on_remove(&0);
+        assert_eq!(policy.victim(), None);
+    }
+
+    /// Bytes allocated for the bytes and points of `id`.
+    fn item_memory<P: BlockPool>(cache: &Cache<Id, Bytes, P>, id: Id) -> usize {
+        let stats = cache.stats().unwrap();
+        let item = stats.per_item.iter().find(|item| item.id == id);
+        item.map_or(0, |item| item.memory)
+    }
+
+    /// Append 40_000 bytes and 1000 points to ids `0..4`.
+    fn fill<P: BlockPool>(cache: &Cache<Id, Bytes, P>) {
+        for id in 0..4 {
+            cache.append(id, vec![1; 40_000]).unwrap();
+            for timestamp in 0..1000 {
+                cache.append_point(id, timestamp, timestamp as f64).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn stats_cover_bytes_and_points() {
+        let cache: Cache = Cache::default();
+        fill(&cache);
+        cache.append_point(9, 0, 1.0).unwrap();
+        let stats = cache.stats().unwrap();
+        let ids: Vec<_> = stats.per_item.iter().map(|item| item.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3, 9]);
+        let item = &stats.per_item[0];
+        assert_eq!((item.len, item.points), (40_000, 1000));
+        let points = &stats.per_item[4];
+        assert_eq!((points.len, points.blocks, points.points), (0, 0, 1));
+        assert!(points.memory > 0);
+        let memory: usize = stats.per_item.iter().map(|item| item.memory).sum();
+        assert_eq!(memory, stats.memory);
+    }
+
+    #[test]
+    fn writes_release_memory() {
+        let cache: Cache = Cache::default();
+        fill(&cache);
+        let before: Vec<_> = (0..4).map(|id| item_memory(&cache, id)).collect();
+        let memory = cache.counters().memory;
+        assert_eq!(before.iter().sum::<usize>(), memory);
+
+        cache.remove(&0).unwrap();
+        assert_eq!(cache.counters().memory, memory - before[0]);
+        cache.truncate(&1, 10).unwrap();
+        cache.replace(2, vec![1; 10]).unwrap();
+        let after: Vec<_> = (0..4).map(|id| item_memory(&cache, id)).collect();
+        assert_eq!(after[0], 0);
+        assert!(after[1] < before[1]);
+        assert!(after[2] < before[2]);
+        assert_eq!(after[3], before[3]);
+        assert_eq!(cache.counters().memory, after.iter().sum::<usize>());
+
+        for id in 1..4 {
+            cache.remove(&id).unwrap();
+        }
+        assert_eq!(cache.counters().memory, 0);
+        assert!(cache.stats().unwrap().per_item.is_empty());
+    }
+
+    #[test]
+    fn evicted_blocks_release_memory() {
+        let capacity = 8 * Block::memory_of(BLOCK_SIZE);
+        let cache: Cache = Cache::with_capacity(capacity, EvictionMode::OldestBlock, Lru::new());
+        fill(&cache);
+        let memory = cache.counters().memory;
+        assert!(memory <= capacity);
+        assert!(cache.counters().evictions > 0);
+        let items: usize = (0..4).map(|id| item_memory(&cache, id)).sum();
+        assert_eq!(items, memory);
+
+        for id in 0..4 {
+            let _ = cache.remove(&id);
+        }
+        assert_eq!(cache.counters().memory, 0);
+    }
+
+    #[test]
+    fn retention_goes_with_the_id() {
+        let time = Arc::new(ManualTime::new());
+        let cache: Cache = Cache::with_capacity(
+            2 * Block::memory_of(BLOCK_SIZE),
+            EvictionMode::Item,
+            Lru::new(),
+        )
+        .with_retention(Duration::from_secs(10))
+        .with_time_source(time.clone());
+        for id in 0..3 {
+            cache.set_retention(id, None).unwrap();
+        }
+        cache.append(0, vec![0; 16]).unwrap();
+        cache.remove(&0).unwrap();
+        // Never written.
+        cache.remove(&1).unwrap_err();
+        cache.append(2, vec![2; BLOCK_SIZE]).unwrap();
+        cache.append(3, vec![3; BLOCK_SIZE]).unwrap();
+        cache.append(4, vec![4; BLOCK_SIZE]).unwrap();
+        assert!(matches!(cache.read_all(&2), Err(Error::NotFound)));
+        assert!(cache.retention.read().unwrap().ids.is_empty());
+
+        // Written again, the id expires with the cache's retention.
+        cache.append(0, vec![0; 16]).unwrap();
+        time.advance(Duration::from_secs(11));
+        assert!(matches!(cache.read_all(&0), Err(Error::NotFound)));
+        cache.sweep().unwrap();
+        assert!(cache.retention.read().unwrap().ids.is_empty());
     }
 }
@@ -0,0 +1,364 @@
+//! Compression of full blocks. A block is never written again once the next
+//! one is started, so it's compressed then and decompressed on every read.
+
+use std::convert::TryInto;
+use std::iter::Sum;
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Compresses full blocks, see
+/// [`CacheCell::with_codec`](crate::CacheCell::with_codec).
+pub trait BlockCodec: Send + Sync {
+    /// Append `data` compressed to `out`.
+    fn compress(&self, data: &[u8], out: &mut Vec<u8>);
+
+    /// Decompress `data` written by [`BlockCodec::compress`] into `out`, as
+    /// long as the bytes compressed. Returns false if `data` is malformed or
+    /// doesn't fill `out` exactly.
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> bool;
+}
+
+/// The LZ4 block format: greedy matches of 4 bytes or more found by hash,
+/// fast on both ends at a modest ratio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+const MIN_MATCH: usize = 4;
+/// The last 5 bytes are always literals.
+const LAST_LITERALS: usize = 5;
+/// The last match starts at least 12 bytes before the end.
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_LOG: u32 = 12;
+
+impl Lz4 {
+    fn hash(sequence: u32) -> usize {
+        (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+    }
+
+    fn read_u32(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    /// Lengths of 15 and more go on in bytes of 255, ended by a smaller one.
+    fn push_len(out: &mut Vec<u8>, mut len: usize) {
+        while len >= 255 {
+            out.push(255);
+            len -= 255;
+        }
+        out.push(len as u8);
+    }
+
+    fn push_literals(out: &mut Vec<u8>, token: u8, literals: &[u8]) {
+        out.push(((literals.len().min(15) as u8) << 4) | token);
+        if literals.len() >= 15 {
+            Self::push_len(out, literals.len() - 15);
+        }
+        out.extend_from_slice(literals);
+    }
+
+    fn read_len(data: &[u8], at: &mut usize, mut len: usize) -> Option<usize> {
+        if len == 15 {
+            loop {
+                let byte = *data.get(*at)?;
+                *at += 1;
+                len += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Some(len)
+    }
+
+    fn decode(data: &[u8], out: &mut [u8]) -> Option<()> {
+        let mut at = 0;
+        let mut written = 0;
+        loop {
+            let token = *data.get(at)?;
+            at += 1;
+            let literals = Self::read_len(data, &mut at, (token >> 4) as usize)?;
+            out.get_mut(written..written + literals)?
+                .copy_from_slice(data.get(at..at + literals)?);
+            at += literals;
+            written += literals;
+            if at == data.len() {
+                return (written == out.len()).then_some(());
+            }
+
+            let offset = u16::from_le_bytes(data.get(at..at + 2)?.try_into().unwrap()) as usize;
+            at += 2;
+            let len = Self::read_len(data, &mut at, (token & 15) as usize)? + MIN_MATCH;
+            if offset == 0 || offset > written || written + len > out.len() {
+                return None;
+            }
+            let start = written - offset;
+            if offset >= len {
+                out.copy_within(start..start + len, written);
+            } else {
+                // The match overlaps what it writes, repeating the last
+                // `offset` bytes.
+                for i in 0..len {
+                    out[written + i] = out[start + i];
+                }
+            }
+            written += len;
+        }
+    }
+}
+
+impl BlockCodec for Lz4 {
+    fn compress(&self, data: &[u8], out: &mut Vec<u8>) {
+        let mut anchor = 0;
+        if data.len() > MF_LIMIT {
+            let mut table = [0u32; 1 << HASH_LOG];
+            let match_limit = data.len() - MF_LIMIT;
+            let end_limit = data.len() - LAST_LITERALS;
+            let mut at = 0;
+            while at <= match_limit {
+                let sequence = Self::read_u32(data, at);
+                let hash = Self::hash(sequence);
+                // Positions are kept off by one, 0 is none.
+                let candidate = table[hash] as usize;
+                table[hash] = at as u32 + 1;
+                let found = candidate > 0 && at - (candidate - 1) <= MAX_OFFSET;
+                if !found || Self::read_u32(data, candidate - 1) != sequence {
+                    at += 1 + ((at - anchor) >> 6);
+                    continue;
+                }
+
+                let mut start = at;
+                let mut from = candidate - 1;
+                while start > anchor && from > 0 && data[start - 1] == data[from - 1] {
+                    start -= 1;
+                    from -= 1;
+                }
+                let mut end = at + MIN_MATCH;
+                while end < end_limit && data[end] == data[end - start + from] {
+                    end += 1;
+                }
+
+                let len = end - start - MIN_MATCH;
+                Self::push_literals(out, len.min(15) as u8, &data[anchor..start]);
+                out.extend_from_slice(&((start - from) as u16).to_le_bytes());
+                if len >= 15 {
+                    Self::push_len(out, len - 15);
+                }
+                at = end;
+                anchor = end;
+            }
+        }
+        Self::push_literals(out, 0, &data[anchor..]);
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> bool {
+        Self::decode(data, out).is_some()
+    }
+}
+
+/// What a codec of a cache did since the cache was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodecStats {
+    /// Full blocks compressed.
+    pub blocks: u64,
+    /// Full blocks kept as they are, compressing them saved too little.
+    pub incompressible: u64,
+    /// Bytes of compressed blocks, before and after.
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+    /// Time spent compressing, incompressible blocks included.
+    pub compress_time: Duration,
+    /// Compressed blocks read.
+    pub decompressions: u64,
+    pub decompress_time: Duration,
+}
+
+impl CodecStats {
+    /// Compressed bytes per byte before, 1 when nothing is compressed.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
+
+impl Add for CodecStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            blocks: self.blocks + other.blocks,
+            incompressible: self.incompressible + other.incompressible,
+            raw_bytes: self.raw_bytes + other.raw_bytes,
+            compressed_bytes: self.compressed_bytes + other.compressed_bytes,
+            compress_time: self.compress_time + other.compress_time,
+            decompressions: self.decompressions + other.decompressions,
+            decompress_time: self.decompress_time + other.decompress_time,
+        }
+    }
+}
+
+impl Sum for CodecStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// The codec of a cache and its counters, shared by the blocks it
+/// compressed.
+pub(crate) struct Codec {
+    codec: Box<dyn BlockCodec>,
+    blocks: AtomicU64,
+    incompressible: AtomicU64,
+    raw_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+    compress_nanos: AtomicU64,
+    decompressions: AtomicU64,
+    decompress_nanos: AtomicU64,
+}
+
+impl Codec {
+    pub fn new(codec: Box<dyn BlockCodec>) -> Self {
+        Self {
+            codec,
+            blocks: AtomicU64::new(0),
+            incompressible: AtomicU64::new(0),
+            raw_bytes: AtomicU64::new(0),
+            compressed_bytes: AtomicU64::new(0),
+            compress_nanos: AtomicU64::new(0),
+            decompressions: AtomicU64::new(0),
+            decompress_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// `data` compressed, `None` if it saves less than an eighth.
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        let mut out = Vec::with_capacity(data.len());
+        self.codec.compress(data, &mut out);
+        add_nanos(&self.compress_nanos, start);
+
+        if out.len() > data.len() - data.len() / 8 {
+            self.incompressible.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        self.raw_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(out.len() as u64, Ordering::Relaxed);
+        Some(out)
+    }
+
+    /// `len` bytes decompressed from `data`, `None` if it's malformed.
+    pub fn decompress(&self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        let mut out = vec![0; len];
+        let decoded = self.codec.decompress(data, &mut out);
+        add_nanos(&self.decompress_nanos, start);
+        self.decompressions.fetch_add(1, Ordering::Relaxed);
+        decoded.then_some(out)
+    }
+
+    pub fn stats(&self) -> CodecStats {
+        CodecStats {
+            blocks: self.blocks.load(Ordering::Relaxed),
+            incompressible: self.incompressible.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+            compress_time: Duration::from_nanos(self.compress_nanos.load(Ordering::Relaxed)),
+            decompressions: self.decompressions.load(Ordering::Relaxed),
+            decompress_time: Duration::from_nanos(self.decompress_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn add_nanos(counter: &AtomicU64, start: Instant) {
+    counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        Lz4.compress(data, &mut compressed);
+        let mut out = vec![0; data.len()];
+        assert!(Lz4.decompress(&compressed, &mut out));
+        assert_eq!(out, data);
+        compressed
+    }
+
+    /// Deterministic xorshift bytes.
+    fn random(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        round_trip(&[]);
+        round_trip(b"short");
+        round_trip(b"exactly 13 b.");
+        let text = b"the quick brown fox jumps over the lazy dog, ".repeat(100);
+        assert!(round_trip(&text).len() < text.len() / 10);
+        // Matches overlapping what they write, and lengths past 15 and 270.
+        assert!(round_trip(&[7; 16 * 1024]).len() < 100);
+        round_trip(&b"ab".repeat(1000));
+        let mut mixed = random(300, 1);
+        mixed.extend_from_slice(&[0; 1000]);
+        mixed.extend(random(20, 2));
+        mixed.extend_from_slice(&mixed.clone()[..500]);
+        round_trip(&mixed);
+        // Matches further back than an offset reaches.
+        let mut far = random(70_000, 3);
+        far.extend_from_slice(&far.clone()[..100]);
+        round_trip(&far);
+    }
+
+    #[test]
+    fn lz4_incompressible() {
+        let data = random(16 * 1024, 4);
+        // Literals only: a token and lengths past the bytes themselves.
+        let compressed = round_trip(&data);
+        assert!(compressed.len() > data.len());
+        assert!(compressed.len() < data.len() + data.len() / 200 + 16);
+
+        let codec = Codec::new(Box::new(Lz4));
+        assert!(codec.compress(&data).is_none());
+        let compressed = codec.compress(&[9; 1024]).unwrap();
+        assert_eq!(codec.decompress(&compressed, 1024), Some(vec![9; 1024]));
+        let stats = codec.stats();
+        assert_eq!((stats.blocks, stats.incompressible), (1, 1));
+        assert_eq!(stats.raw_bytes, 1024);
+        assert_eq!(stats.compressed_bytes, compressed.len() as u64);
+        assert_eq!(stats.decompressions, 1);
+    }
+
+    #[test]
+    fn lz4_malformed() {
+        let data = b"the quick brown fox jumps over the lazy dog, ".repeat(10);
+        let mut compressed = vec![];
+        Lz4.compress(&data, &mut compressed);
+        let mut out = vec![0; data.len()];
+        assert!(!Lz4.decompress(&compressed[..compressed.len() - 1], &mut out));
+        assert!(!Lz4.decompress(&[], &mut out));
+        let mut short = vec![0; data.len() - 1];
+        assert!(!Lz4.decompress(&compressed, &mut short));
+        let mut long = vec![0; data.len() + 1];
+        assert!(!Lz4.decompress(&compressed, &mut long));
+        // An offset reaching before the start.
+        assert!(!Lz4.decompress(&[0x10, b'a', 2, 0], &mut [0; 5]));
+        assert!(!Lz4.decompress(&[0x10, b'a', 0, 0], &mut [0; 5]));
+    }
+}
@@ -0,0 +1,84 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+/// Why a cache operation failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The id has nothing cached.
+    NotFound,
+    /// A lock is poisoned by a thread that panicked holding it.
+    Poisoned,
+    /// The value needs more memory than the cache may hold.
+    CapacityExceeded {
+        needed: usize,
+        capacity: usize,
+    },
+    Corrupted(Corrupted),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::Poisoned => write!(f, "lock poisoned"),
+            Error::CapacityExceeded { needed, capacity } => {
+                write!(f, "needs {needed} bytes, over capacity {capacity}")
+            }
+            Error::Corrupted(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Corrupted(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error::Poisoned
+    }
+}
+
+impl From<Corrupted> for Error {
+    fn from(e: Corrupted) -> Self {
+        Error::Corrupted(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A cached block whose bytes don't match the checksum taken when they were
+/// written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corrupted {
+    /// Index of the block in its item, oldest first.
+    pub block: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block {} corrupted: crc32c {:#010x}, expected {:#010x}",
+            self.block, self.actual, self.expected
+        )
+    }
+}
+
+impl error::Error for Corrupted {}
@@ -0,0 +1,520 @@
+//! Eviction policies deciding which key to drop when a cache is over its
+//! capacity.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use crate::value::Key;
+
+/// Tracks keys of a cache and picks victims. Calls are serialized by the
+/// cache. Accesses are told in batches, before victims are picked, so one
+/// may come after the key is removed.
+pub trait EvictionPolicy<K>: Send {
+    /// `key` is added to the cache.
+    fn on_insert(&mut self, key: &K);
+
+    /// `key` is read or appended. Keys not tracked stay untracked.
+    fn on_access(&mut self, key: &K);
+
+    /// `key` is gone from the cache, by eviction or otherwise.
+    fn on_remove(&mut self, key: &K);
+
+    /// The key to evict next. It stays tracked until `on_remove`, since
+    /// evicting a block may leave the key in the cache.
+    fn victim(&mut self) -> Option<K>;
+}
+
+/// What is dropped from a victim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionMode {
+    /// The whole item.
+    Item,
+    /// Only the oldest block of the item.
+    OldestBlock,
+}
+
+/// Keys ordered by recency.
+struct LruList<K> {
+    ticks: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Key> LruList<K> {
+    fn new() -> Self {
+        Self {
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    /// Insert or move `key` to the most recent position.
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some(old) = self.ticks.insert(key.clone(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key.clone());
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        match self.ticks.remove(key) {
+            Some(tick) => {
+                self.order.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn least_recent(&self) -> Option<&K> {
+        self.order.values().next()
+    }
+
+    fn pop_least_recent(&mut self) -> Option<K> {
+        let key = self.least_recent()?.clone();
+        self.remove(&key);
+        Some(key)
+    }
+}
+
+/// Least recently used.
+pub struct Lru<K> {
+    list: LruList<K>,
+}
+
+impl<K: Key> Lru<K> {
+    pub fn new() -> Self {
+        Self {
+            list: LruList::new(),
+        }
+    }
+}
+
+impl<K: Key> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Key + Send> EvictionPolicy<K> for Lru<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.list.touch(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if self.list.contains(key) {
+            self.list.touch(key);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        self.list.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.list.least_recent().cloned()
+    }
+}
+
+/// Second chance: a hand sweeps over keys, clearing their referenced bit and
+/// evicting the first key found unreferenced.
+pub struct Clock<K> {
+    ring: Vec<(K, bool)>,
+    index: HashMap<K, usize>,
+    hand: usize,
+}
+
+impl<K: Key> Clock<K> {
+    pub fn new() -> Self {
+        Self {
+            ring: vec![],
+            index: HashMap::new(),
+            hand: 0,
+        }
+    }
+}
+
+impl<K: Key> Default for Clock<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Key + Send> EvictionPolicy<K> for Clock<K> {
+    fn on_insert(&mut self, key: &K) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.clone(), self.ring.len());
+            self.ring.push((key.clone(), false));
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if let Some(&i) = self.index.get(key) {
+            self.ring[i].1 = true;
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if let Some(i) = self.index.remove(key) {
+            self.ring.swap_remove(i);
+            if let Some((moved, _)) = self.ring.get(i) {
+                self.index.insert(moved.clone(), i);
+            }
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        loop {
+            self.hand %= self.ring.len();
+            let (key, referenced) = &mut self.ring[self.hand];
+            if !*referenced {
+                return Some(key.clone());
+            }
+            *referenced = false;
+            self.hand += 1;
+        }
+    }
+}
+
+/// Count-Min sketch of access frequency, with counters halved periodically
+/// so old popularity fades.
+struct FrequencySketch {
+    rows: [Vec<u8>; 4],
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    const MAX_COUNT: u8 = 15;
+
+    fn new(expected_keys: usize) -> Self {
+        let width = expected_keys.max(64).next_power_of_two();
+        Self {
+            rows: [
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+            ],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    fn index<K: Hash>(&self, key: &K, row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish() as usize & self.mask
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for row in 0..self.rows.len() {
+            let i = self.index(key, row);
+            let count = &mut self.rows[row][i];
+            *count = (*count + 1).min(Self::MAX_COUNT);
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.rows
+                .iter_mut()
+                .flat_map(|row| row.iter_mut())
+                .for_each(|count| *count /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        (0..self.rows.len())
+            .map(|row| self.rows[row][self.index(key, row)])
+            .min()
+            .unwrap()
+    }
+}
+
+/// Window TinyLFU: new keys enter a small LRU window; a key leaving the
+/// window is only admitted to the main segmented LRU if it is accessed more
+/// often than the main segment's victim.
+pub struct WTinyLfu<K> {
+    sketch: FrequencySketch,
+    window: LruList<K>,
+    probation: LruList<K>,
+    protected: LruList<K>,
+}
+
+impl<K: Key> WTinyLfu<K> {
+    /// Share of keys in the window, in percent.
+    const WINDOW_PERCENT: usize = 1;
+    /// Share of the main segment that is protected, in percent.
+    const PROTECTED_PERCENT: usize = 80;
+
+    /// `expected_keys` sizes the frequency sketch.
+    pub fn new(expected_keys: usize) -> Self {
+        Self {
+            sketch: FrequencySketch::new(expected_keys),
+            window: LruList::new(),
+            probation: LruList::new(),
+            protected: LruList::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn window_capacity(&self) -> usize {
+        (self.len() * Self::WINDOW_PERCENT / 100).max(1)
+    }
+
+    fn protected_capacity(&self) -> usize {
+        let main = self.probation.len() + self.protected.len();
+        (main * Self::PROTECTED_PERCENT / 100).max(1)
+    }
+
+    fn main_victim(&self) -> Option<&K> {
+        self.probation
+            .least_recent()
+            .or_else(|| self.protected.least_recent())
+    }
+}
+
+impl<K: Key + Send> EvictionPolicy<K> for WTinyLfu<K> {
+    fn on_insert(&mut self, key: &K) {
+        // A key may be inserted again by another kind of item.
+        if self.window.contains(key) || self.probation.contains(key) || self.protected.contains(key)
+        {
+            return self.on_access(key);
+        }
+        self.sketch.increment(key);
+        self.window.touch(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.sketch.increment(key);
+        if self.window.contains(key) {
+            self.window.touch(key);
+        } else if self.probation.remove(key) {
+            self.protected.touch(key);
+            if self.protected.len() > self.protected_capacity() {
+                let demoted = self.protected.pop_least_recent().unwrap();
+                self.probation.touch(&demoted);
+            }
+        } else if self.protected.contains(key) {
+            self.protected.touch(key);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        let _ = self.window.remove(key) || self.probation.remove(key) || self.protected.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        while self.window.len() > self.window_capacity() {
+            let candidate = self.window.least_recent().unwrap().clone();
+            match self.main_victim() {
+                Some(victim) => {
+                    let victim = victim.clone();
+                    if self.sketch.frequency(&candidate) > self.sketch.frequency(&victim) {
+                        self.window.remove(&candidate);
+                        self.probation.touch(&candidate);
+                        return Some(victim);
+                    }
+                    return Some(candidate);
+                }
+                // Keys leaving the window fill an empty main segment.
+                None => {
+                    self.window.remove(&candidate);
+                    self.probation.touch(&candidate);
+                }
+            }
+        }
+
+        self.main_victim()
+            .or_else(|| self.window.least_recent())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::{Block, BLOCK_SIZE};
+    use crate::{Cache, Error};
+
+    /// Victims of `policy` in order, removing each.
+    fn victims(policy: &mut dyn EvictionPolicy<u64>) -> Vec<u64> {
+        let mut victims = vec![];
+        while let Some(victim) = policy.victim() {
+            policy.on_remove(&victim);
+            victims.push(victim);
+        }
+        victims
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new();
+        for key in 0..4 {
+            lru.on_insert(&key);
+        }
+        lru.on_access(&1);
+        lru.on_access(&0);
+        // Accesses of keys not tracked don't track them.
+        lru.on_access(&9);
+        assert_eq!(victims(&mut lru), vec![2, 3, 1, 0]);
+
+        lru.on_insert(&0);
+        lru.on_insert(&1);
+        lru.on_remove(&0);
+        assert_eq!(victims(&mut lru), vec![1]);
+    }
+
+    #[test]
+    fn clock_gives_referenced_keys_a_second_chance() {
+        let mut clock = Clock::new();
+        for key in 0..4 {
+            clock.on_insert(&key);
+        }
+        clock.on_access(&0);
+        clock.on_access(&1);
+        clock.on_access(&9);
+        assert_eq!(clock.victim(), Some(2));
+        // The victim stays until removed.
+        assert_eq!(clock.victim(), Some(2));
+        clock.on_remove(&2);
+        // The hand cleared 0 and 1 on its way, and passes 3 now referenced.
+        clock.on_access(&3);
+        assert_eq!(victims(&mut clock), vec![0, 3, 1]);
+    }
+
+    #[test]
+    fn tiny_lfu_admits_keys_more_frequent_than_the_victim() {
+        let mut lfu = WTinyLfu::new(100);
+        lfu.on_insert(&0);
+        (0..5).for_each(|_| lfu.on_access(&0));
+        lfu.on_insert(&1);
+        (0..9).for_each(|_| lfu.on_access(&1));
+        // 0 leaves the window for the empty main segment.
+        assert_eq!(lfu.victim(), Some(0));
+
+        // 1 is more frequent than 0, it's admitted.
+        lfu.on_insert(&2);
+        assert_eq!(lfu.victim(), Some(0));
+        lfu.on_remove(&0);
+
+        // 2 is not more frequent than 1, it's evicted from the window.
+        lfu.on_insert(&3);
+        assert_eq!(lfu.victim(), Some(2));
+        lfu.on_remove(&2);
+        assert_eq!(victims(&mut lfu), vec![1, 3]);
+    }
+
+    #[test]
+    fn values_over_capacity_fail_without_writing() {
+        let memory = Block::memory_of(BLOCK_SIZE);
+        let cache: Cache = Cache::with_capacity(2 * memory, EvictionMode::Item, Lru::new());
+        cache.append(0, vec![0; 16]).unwrap();
+        let before = cache.counters().memory;
+
+        match cache.append(0, vec![1; 2 * BLOCK_SIZE + 1]) {
+            Err(Error::CapacityExceeded { needed, capacity }) => {
+                assert_eq!((needed, capacity), (3 * memory, 2 * memory));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            cache.replace(0, vec![1; 3 * BLOCK_SIZE]),
+            Err(Error::CapacityExceeded { .. })
+        ));
+        assert_eq!(cache.read_all(&0).unwrap(), vec![0; 16]);
+        assert_eq!(cache.counters().memory, before);
+
+        // A value filling the capacity fits.
+        cache.remove(&0).unwrap();
+        cache.append(1, vec![1; 2 * BLOCK_SIZE]).unwrap();
+        assert_eq!(cache.read_all(&1).unwrap(), vec![1; 2 * BLOCK_SIZE]);
+        assert_eq!(cache.counters().evictions, 0);
+    }
+
+    #[test]
+    fn counters_count_hits_misses_and_evictions() {
+        let memory = Block::memory_of(BLOCK_SIZE);
+        let cache: Cache = Cache::with_capacity(2 * memory, EvictionMode::Item, Lru::new());
+        cache.append(0, vec![0; BLOCK_SIZE]).unwrap();
+        cache.append(1, vec![1; BLOCK_SIZE]).unwrap();
+        cache.read_all(&0).unwrap();
+        cache.read_all(&0).unwrap();
+        assert!(cache.read_all(&5).is_err());
+        let counters = cache.counters();
+        assert_eq!(
+            (counters.hits, counters.misses, counters.evictions),
+            (2, 1, 0)
+        );
+
+        cache.append(2, vec![2; BLOCK_SIZE]).unwrap();
+        assert!(cache.read_all(&1).is_err());
+        let counters = cache.counters();
+        assert_eq!(
+            (counters.hits, counters.misses, counters.evictions),
+            (2, 2, 1)
+        );
+        assert_eq!(counters.memory, 2 * memory);
+    }
+
+    /// Deterministic xorshift, appends only need to look random.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    #[test]
+    fn memory_stays_within_capacity() {
+        let capacity = 16 * Block::memory_of(BLOCK_SIZE);
+        for &mode in [EvictionMode::Item, EvictionMode::OldestBlock].iter() {
+            let policies: Vec<Box<dyn Fn() -> Cache>> = vec![
+                Box::new(move || Cache::with_capacity(capacity, mode, Lru::new())),
+                Box::new(move || Cache::with_capacity(capacity, mode, Clock::new())),
+                Box::new(move || Cache::with_capacity(capacity, mode, WTinyLfu::new(64))),
+            ];
+            for cache in policies.iter().map(|new| new()) {
+                let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+                for i in 0..2000 {
+                    let id = rng.next() % 32;
+                    if i % 3 == 0 {
+                        cache.append_point(id, i as i64, i as f64).unwrap();
+                    } else {
+                        let len = rng.next() % (2 * BLOCK_SIZE);
+                        cache.append(id, vec![1; len]).unwrap();
+                    }
+                    let _ = cache.read_all(&(rng.next() % 32));
+                    assert!(cache.counters().memory <= capacity, "{:?}", mode);
+                }
+                assert!(cache.counters().evictions > 0);
+                assert_eq!(cache.stats().unwrap().memory, cache.counters().memory);
+            }
+        }
+    }
+}
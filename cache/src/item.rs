@@ -1,83 +1,685 @@
 use crate::cell::{Bytes, BytesRef};
+use crate::codec::Codec;
+use crate::crc::{crc32c, crc32c_append};
+use crate::error::{Corrupted, Error};
+use crate::pool::{BlockPool, Buf};
+use crate::value::Value;
 use rand::random;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter::Sum;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Add, Deref};
+use std::sync::Arc;
+use std::time::Instant;
 
-const BLOCK_SIZE: usize = 1024 * 16;
+/// Block size of a cache unless set with
+/// [`CacheCell::with_block_size`](crate::CacheCell::with_block_size).
+pub(crate) const BLOCK_SIZE: usize = 1024 * 16;
 
-struct Block {
-    block: [u8; BLOCK_SIZE],
-    used: usize,
+/// Smallest block compaction allocates for the tail of an item.
+const MIN_TAIL_SIZE: usize = 256;
+
+#[derive(Clone)]
+pub(crate) struct Block {
+    pub block: Buf,
+    pub used: usize,
+    /// Checksum of the written part.
+    pub crc: u32,
+    /// Last time this block is written.
+    pub written_at: Instant,
+    /// Codec `block` is compressed with. Only full blocks are compressed,
+    /// `used` and `crc` stay those of the bytes as written.
+    pub codec: Option<Arc<Codec>>,
 }
 
 impl Block {
-    pub fn new() -> Self {
+    /// Bytes a block of `size` bytes takes, its buf included.
+    pub const fn memory_of(size: usize) -> usize {
+        size_of::<Block>() + size
+    }
+
+    pub fn new(block: Buf, now: Instant) -> Self {
         Self {
-            block: [0; BLOCK_SIZE],
+            block,
             used: 0,
+            crc: 0,
+            written_at: now,
+            codec: None,
+        }
+    }
+
+    /// Bytes this block takes.
+    pub fn memory(&self) -> usize {
+        Self::memory_of(self.block.len())
+    }
+
+    /// Bytes this block holds as written. Compressed blocks are full.
+    pub fn capacity(&self) -> usize {
+        match self.codec {
+            Some(_) => self.used,
+            None => self.block.len(),
         }
     }
 
-    pub fn get(&self, size: usize) -> BytesRef {
-        &self.block[0..size]
+    /// Written part of this block. Compressed blocks are read through
+    /// [`Block::decompress`].
+    pub fn data(&self) -> BytesRef<'_> {
+        debug_assert!(self.codec.is_none(), "data of a compressed block");
+        &self.block[..self.used]
+    }
+
+    /// This full block compressed with `codec`, `None` if it doesn't shrink
+    /// enough.
+    fn compress(&self, codec: &Arc<Codec>) -> Option<Block> {
+        Some(Block {
+            block: Buf::from(codec.compress(self.data())?),
+            used: self.used,
+            crc: self.crc,
+            written_at: self.written_at,
+            codec: Some(codec.clone()),
+        })
+    }
+
+    /// Bytes of a compressed block as written, checked against the
+    /// checksum. `index` is where the block is in its item.
+    fn decompress(&self, index: usize) -> Result<Bytes, Corrupted> {
+        let codec = self.codec.as_ref().expect("decompress a raw block");
+        match codec.decompress(&self.block, self.used) {
+            Some(bytes) => self.check(index, &bytes).map(|()| bytes),
+            None => Err(Corrupted {
+                block: index,
+                expected: self.crc,
+                actual: crc32c(&self.block),
+            }),
+        }
+    }
+
+    /// Bytes of this block as written, decompressed if needed, checked
+    /// against the checksum.
+    fn raw(&self, index: usize) -> Result<Cow<'_, [u8]>, Corrupted> {
+        match self.codec {
+            Some(_) => self.decompress(index).map(Cow::Owned),
+            None => self
+                .check(index, self.data())
+                .map(|()| Cow::Borrowed(self.data())),
+        }
     }
 
     /// Return un-capacity size
     pub fn put(&mut self, bytes: BytesRef) -> usize {
-        let bytes_len = bytes.len();
-        if BLOCK_SIZE - self.used >= bytes_len {
-            self.block[self.used..self.used + bytes_len].copy_from_slice(bytes);
-            self.used += bytes_len;
-            0
+        let written = bytes.len().min(self.capacity() - self.used);
+        let end = self.used + written;
+        self.block[self.used..end].copy_from_slice(&bytes[..written]);
+        self.crc = crc32c_append(self.crc, &bytes[..written]);
+        self.used = end;
+        bytes.len() - written
+    }
+
+    /// Keep the first `len` written bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.used = len;
+        self.crc = crc32c(self.data());
+    }
+
+    /// Check the written part against its checksum, decompressing it first
+    /// if it's compressed. `index` is where the block is in its item.
+    pub fn verify(&self, index: usize) -> Result<(), Corrupted> {
+        self.raw(index).map(drop)
+    }
+
+    /// Check `data` against the checksum. `index` is where the block is in
+    /// its item.
+    pub fn check(&self, index: usize, data: BytesRef) -> Result<(), Corrupted> {
+        let actual = crc32c(data);
+        if actual == self.crc {
+            Ok(())
         } else {
-            let remaining = BLOCK_SIZE - self.used;
-            self.block[self.used..].copy_from_slice(&bytes[..remaining]);
-            self.used = BLOCK_SIZE;
-            bytes_len - remaining
+            Err(Corrupted {
+                block: index,
+                expected: self.crc,
+                actual,
+            })
         }
     }
 }
 
-pub struct Item {
-    blocks: Vec<Block>,
+/// Appended bytes of one id, kept in order across blocks.
+///
+/// Blocks are shared with [`BlockSlice`]s handed out by reads. A block still
+/// shared is copied before it is written, so bytes a slice covers never
+/// change.
+pub struct Item<V = Bytes> {
+    blocks: VecDeque<Arc<Block>>,
+    /// Bytes of all blocks, see [`Item::memory`].
+    memory: usize,
+    _value: PhantomData<V>,
 }
 
-impl Item {
-    pub fn get(&self, size: usize) -> Bytes {
-        let block_num = self.blocks.len();
-        let mut result = Vec::with_capacity(size);
+impl<V: Value> Item<V> {
+    /// Get `size` bytes gathered from random blocks. Only used to generate
+    /// read load, see [`Item::read`] for reading back what was appended.
+    pub fn get(&self, size: usize) -> Result<V, Error> {
+        Ok(V::decode(self.get_shared(size)?.to_vec())?)
+    }
+
+    /// Everything appended so far, in order.
+    pub fn read_all(&self) -> Result<V, Error> {
+        Ok(V::decode(self.read(0, self.len())?)?)
+    }
+}
+
+impl<V> Item<V> {
+    /// Append encoded bytes, into new blocks of `block_size` bytes once the
+    /// last one is full. Full blocks are compressed with `codec` when the
+    /// next one is started.
+    pub(crate) fn put_bytes<P: BlockPool>(
+        &mut self,
+        bytes: BytesRef,
+        now: Instant,
+        pool: &P,
+        block_size: usize,
+        codec: Option<&Arc<Codec>>,
+    ) {
+        // A full last block isn't touched, so it keeps the time it was
+        // last written.
+        let full = |block: &Arc<Block>| block.codec.is_some() || block.used == block.capacity();
+        if self.blocks.back().is_none_or(full) {
+            self.seal_last(codec, pool);
+            self.push_block(Block::new(pool.alloc(block_size), now));
+        }
+        let last = Arc::make_mut(self.blocks.back_mut().unwrap());
+        last.written_at = now;
+        let mut remaining = last.put(bytes);
+        while remaining != 0 {
+            self.seal_last(codec, pool);
+            let mut block = Block::new(pool.alloc(block_size), now);
+            let cursor = bytes.len() - remaining;
+            remaining = block.put(&bytes[cursor..]);
+            self.push_block(block);
+        }
+    }
+
+    fn push_block(&mut self, block: Block) {
+        self.memory += block.memory();
+        self.blocks.push_back(Arc::new(block));
+    }
+
+    /// Compress the last block with `codec` if it's full, as it's never
+    /// written again once another follows.
+    fn seal_last<P: BlockPool>(&mut self, codec: Option<&Arc<Codec>>, pool: &P) {
+        let (codec, last) = match (codec, self.blocks.back_mut()) {
+            (Some(codec), Some(last)) if last.codec.is_none() && last.used == last.capacity() => {
+                (codec, last)
+            }
+            _ => return,
+        };
+        if let Some(block) = last.compress(codec) {
+            self.memory = self.memory - last.memory() + block.memory();
+            release(std::mem::replace(last, Arc::new(block)), pool);
+        }
+    }
 
-        while result.len() < size {
+    /// Total appended bytes.
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|block| block.used).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|block| block.used == 0)
+    }
+
+    /// Read `len` bytes starting at `offset`. The result is shorter than
+    /// `len` if it reaches the end. Blocks read are checked against their
+    /// checksum.
+    pub fn read(&self, offset: usize, len: usize) -> Result<Bytes, Corrupted> {
+        Ok(self.read_shared(offset, len)?.to_vec())
+    }
+
+    /// Like [`Item::read`], but shares the blocks instead of copying them.
+    pub fn read_shared(&self, mut offset: usize, len: usize) -> Result<SharedBytes, Corrupted> {
+        let mut result = SharedBytes::default();
+        for (index, block) in self.blocks.iter().enumerate() {
+            if result.len == len {
+                break;
+            }
+            if offset >= block.used {
+                offset -= block.used;
+                continue;
+            }
+            let block = readable(block, index)?;
+            let end = block.used.min(offset.saturating_add(len - result.len));
+            result.push(BlockSlice::new(&block, offset, end));
+            offset = 0;
+        }
+
+        Ok(result)
+    }
+
+    /// Get `size` bytes from random blocks without copying them. Only used to
+    /// generate read load.
+    pub fn get_shared(&self, size: usize) -> Result<SharedBytes, Corrupted> {
+        let mut result = SharedBytes::default();
+        if self.is_empty() {
+            return Ok(result);
+        }
+
+        let block_num = self.blocks.len();
+        while result.len < size {
             let index = random::<usize>() % block_num;
-            result.extend_from_slice(self.blocks[index].get(size - result.len()));
+            let block = &self.blocks[index];
+            let end = block.used.min(size - result.len);
+            if end > 0 {
+                result.push(BlockSlice::new(&readable(block, index)?, 0, end));
+            }
         }
 
-        calculation(result)
+        Ok(result)
     }
 
-    pub fn put(&mut self, bytes: Bytes) {
-        let bytes = calculation(bytes);
+    /// Bytes allocated for blocks.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
 
-        let mut cursor = 0;
-        let mut remaining = self.blocks.last_mut().unwrap().put(&bytes[cursor..]);
-        while remaining != 0 {
-            self.blocks.push(Block::new());
-            cursor += remaining;
-            remaining = self.blocks.last_mut().unwrap().put(&bytes[cursor..]);
+    /// Drop the oldest block. Returns the bytes released.
+    pub fn pop_front_block<P: BlockPool>(&mut self, pool: &P) -> usize {
+        match self.blocks.pop_front() {
+            Some(block) => {
+                let freed = block.memory();
+                self.memory -= freed;
+                release(block, pool);
+                freed
+            }
+            None => 0,
+        }
+    }
+
+    /// Drop every block. Returns the bytes released.
+    pub fn clear<P: BlockPool>(&mut self, pool: &P) -> usize {
+        let freed = self.memory;
+        self.memory = 0;
+        self.blocks.drain(..).for_each(|block| release(block, pool));
+        freed
+    }
+
+    /// Keep the first `len` bytes and drop the rest. A compressed block
+    /// cut in the middle is decompressed to be written again, so memory may
+    /// grow.
+    pub fn truncate<P: BlockPool>(&mut self, len: usize, pool: &P) -> Result<(), Corrupted> {
+        let mut kept = 0;
+        let mut keep_blocks = 0;
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            if kept >= len {
+                break;
+            }
+            if block.used > len - kept {
+                if block.codec.is_some() {
+                    let raw = block.decompress(index)?;
+                    let mut buf = pool.alloc(raw.len());
+                    buf.copy_from_slice(&raw);
+                    let raw = Block {
+                        block: buf,
+                        used: block.used,
+                        crc: block.crc,
+                        written_at: block.written_at,
+                        codec: None,
+                    };
+                    self.memory = self.memory - block.memory() + raw.memory();
+                    release(std::mem::replace(block, Arc::new(raw)), pool);
+                }
+                Arc::make_mut(block).truncate(len - kept);
+            }
+            kept += block.used;
+            keep_blocks += 1;
+        }
+
+        let mut freed = 0;
+        self.blocks.drain(keep_blocks..).for_each(|block| {
+            freed += block.memory();
+            release(block, pool);
+        });
+        self.memory -= freed;
+        Ok(())
+    }
+
+    /// Whether the oldest block is last written before `cutoff`.
+    pub fn has_expired(&self, cutoff: Instant) -> bool {
+        self.blocks
+            .front()
+            .is_some_and(|block| block.written_at < cutoff)
+    }
+
+    /// Drop blocks last written before `cutoff`. Blocks are written in order,
+    /// so they are always a prefix. Returns the bytes released.
+    pub fn expire<P: BlockPool>(&mut self, cutoff: Instant, pool: &P) -> usize {
+        let mut freed = 0;
+        while self.has_expired(cutoff) {
+            freed += self.pop_front_block(pool);
+        }
+        freed
+    }
+
+    /// Bytes taken if the item is rewritten densely: full blocks of
+    /// `block_size` bytes, then the rest in the smallest tail block that
+    /// holds it.
+    fn dense_memory(&self, block_size: usize) -> usize {
+        let len = self.len();
+        let tail = len % block_size;
+        let mut memory = len / block_size * Block::memory_of(block_size);
+        if tail > 0 {
+            memory += Block::memory_of(tail_size(tail, block_size));
+        }
+        memory
+    }
+
+    /// Rewrite the item densely, merging under-filled blocks, if it saves
+    /// memory. Blocks are checked against their checksum first. Each new
+    /// block counts as written when the oldest block it takes bytes from
+    /// was, so no byte outlives its retention. Full ones are compressed with
+    /// `codec`. Returns the bytes released.
+    pub(crate) fn compact<P: BlockPool>(
+        &mut self,
+        block_size: usize,
+        pool: &P,
+        codec: Option<&Arc<Codec>>,
+    ) -> Result<usize, Corrupted> {
+        if self.dense_memory(block_size) >= self.memory {
+            return Ok(0);
+        }
+        let raw = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| block.raw(index))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut left = self.len();
+        let mut dense = Item::<V>::default();
+        for (block, data) in self.blocks.iter().zip(&raw) {
+            let mut data = &data[..];
+            while !data.is_empty() {
+                let full = dense
+                    .blocks
+                    .back()
+                    .is_none_or(|block| block.used == block.capacity());
+                if full {
+                    dense.seal_last(codec, pool);
+                    let size = if left >= block_size {
+                        block_size
+                    } else {
+                        tail_size(left, block_size)
+                    };
+                    dense.push_block(Block::new(pool.alloc(size), block.written_at));
+                }
+                let last = Arc::get_mut(dense.blocks.back_mut().unwrap()).unwrap();
+                last.written_at = last.written_at.min(block.written_at);
+                let written = data.len() - last.put(data);
+                left -= written;
+                data = &data[written..];
+            }
+        }
+
+        drop(raw);
+        let before = self.memory;
+        let after = dense.memory;
+        self.clear(pool);
+        *self = dense;
+        Ok(before - after)
+    }
+
+    /// How full the blocks are. Blocks holding less than `block_size` bytes
+    /// are under-filled.
+    pub(crate) fn fragmentation(&self, block_size: usize) -> Fragmentation {
+        let mut fragmentation = Fragmentation {
+            items: 1,
+            blocks: self.blocks.len(),
+            capacity: self.blocks.iter().map(|block| block.capacity()).sum(),
+            used: self.len(),
+            ..Default::default()
+        };
+        fragmentation.under_filled = self
+            .blocks
+            .iter()
+            .filter(|block| block.used < block_size)
+            .count();
+        if self.dense_memory(block_size) < self.memory {
+            fragmentation.compactable = 1;
         }
+        fragmentation
+    }
+
+    /// Written part of each block, in order, checked against its checksum.
+    /// Compressed blocks are decompressed.
+    pub fn blocks(&self) -> Result<Blocks<'_>, Corrupted> {
+        let blocks = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| block.raw(index))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Blocks {
+            inner: blocks.into_iter(),
+        })
     }
 }
 
-impl Default for Item {
-    fn default() -> Item {
+impl<V> Default for Item<V> {
+    fn default() -> Self {
         Item {
-            blocks: vec![Block::new()],
+            blocks: VecDeque::new(),
+            memory: 0,
+            _value: PhantomData,
+        }
+    }
+}
+
+/// Size of the block holding the last `len` bytes of a dense item.
+fn tail_size(len: usize, block_size: usize) -> usize {
+    len.next_power_of_two()
+        .clamp(MIN_TAIL_SIZE.min(block_size), block_size)
+}
+
+/// How much of the block memory of a cache holds bytes. Series are not
+/// counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    pub items: usize,
+    pub blocks: usize,
+    /// Blocks holding less than the cache's block size.
+    pub under_filled: usize,
+    /// Items [`CacheCell::compact`](crate::CacheCell::compact) would shrink.
+    pub compactable: usize,
+    /// Bytes of all blocks.
+    pub capacity: usize,
+    /// Bytes written in them.
+    pub used: usize,
+}
+
+impl Fragmentation {
+    /// Share of block bytes not written, from 0 when every block is full to
+    /// 1 when they are all empty.
+    pub fn ratio(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            1.0 - self.used as f64 / self.capacity as f64
+        }
+    }
+}
+
+impl Add for Fragmentation {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            items: self.items + other.items,
+            blocks: self.blocks + other.blocks,
+            under_filled: self.under_filled + other.under_filled,
+            compactable: self.compactable + other.compactable,
+            capacity: self.capacity + other.capacity,
+            used: self.used + other.used,
         }
     }
 }
 
-#[inline]
-fn calculation(mut bytes: Bytes) -> Bytes {
-    let mut sum: u8 = 0;
-    bytes.iter().for_each(|x| sum = sum.wrapping_add(*x));
-    bytes[0] += sum;
-    bytes
+impl Sum for Fragmentation {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// Iterator returned by [`Item::blocks`]. Skips empty blocks. Blocks
+/// written as they are are borrowed, compressed ones are decompressed.
+pub struct Blocks<'a> {
+    inner: std::vec::IntoIter<Cow<'a, [u8]>>,
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.by_ref().find(|data| !data.is_empty())
+    }
+}
+
+/// `block` to be sliced by a reader, checked against its checksum. A
+/// compressed block is decompressed into a new one only the reader holds.
+fn readable(block: &Arc<Block>, index: usize) -> Result<Arc<Block>, Corrupted> {
+    match block.codec {
+        Some(_) => Ok(Arc::new(Block {
+            block: Buf::from(block.decompress(index)?),
+            used: block.used,
+            crc: block.crc,
+            written_at: block.written_at,
+            codec: None,
+        })),
+        None => {
+            block.verify(index)?;
+            Ok(block.clone())
+        }
+    }
+}
+
+/// Give the buf of `block` back to `pool`, unless a reader still shares it.
+fn release<P: BlockPool>(block: Arc<Block>, pool: &P) {
+    if let Ok(block) = Arc::try_unwrap(block) {
+        pool.free(block.block);
+    }
+}
+
+/// Written bytes of a block, kept alive by reference count rather than
+/// copied out of the cache.
+#[derive(Clone)]
+pub struct BlockSlice {
+    block: Arc<Block>,
+    start: usize,
+    end: usize,
+}
+
+impl BlockSlice {
+    fn new(block: &Arc<Block>, start: usize, end: usize) -> Self {
+        Self {
+            block: block.clone(),
+            start,
+            end,
+        }
+    }
+}
+
+impl Deref for BlockSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.block.block[self.start..self.end]
+    }
+}
+
+/// Bytes read without copying, as slices of blocks in order.
+#[derive(Clone, Default)]
+pub struct SharedBytes {
+    slices: Vec<BlockSlice>,
+    len: usize,
+}
+
+impl SharedBytes {
+    fn push(&mut self, slice: BlockSlice) {
+        self.len += slice.len();
+        self.slices.push(slice);
+    }
+
+    /// Total bytes of all slices.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, BlockSlice> {
+        self.slices.iter()
+    }
+
+    /// Copy the bytes out.
+    pub fn to_vec(&self) -> Bytes {
+        let mut bytes = Vec::with_capacity(self.len);
+        self.slices
+            .iter()
+            .for_each(|slice| bytes.extend_from_slice(slice));
+        bytes
+    }
+}
+
+impl fmt::Debug for BlockSlice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockSlice")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+impl fmt::Debug for SharedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedBytes")
+            .field("len", &self.len)
+            .field("slices", &self.slices)
+            .finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a SharedBytes {
+    type Item = &'a BlockSlice;
+    type IntoIter = std::slice::Iter<'a, BlockSlice>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slices.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::HeapPool;
+    use std::time::Duration;
+
+    #[test]
+    fn compact_keeps_oldest_write_time() {
+        let old = Instant::now();
+        let new = old + Duration::from_secs(5);
+        let mut item = Item::<Bytes>::default();
+        for (bytes, now) in [(&[1u8; 10], old), (&[2u8; 10], new)] {
+            let mut block = Block::new(Buf::heap(64), now);
+            block.put(bytes);
+            item.push_block(block);
+        }
+
+        assert!(item.compact(64, &HeapPool, None).unwrap() > 0);
+        assert_eq!(item.blocks.len(), 1);
+        assert_eq!(item.read_all().unwrap(), [[1; 10], [2; 10]].concat());
+        assert!(item.has_expired(old + Duration::from_nanos(1)));
+    }
 }
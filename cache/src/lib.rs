@@ -1,7 +1,33 @@
 #![feature(test)]
+mod bits;
 mod cache;
 mod cell;
+mod codec;
+mod crc;
+mod error;
+mod evict;
 mod item;
+mod pool;
+mod scan;
+mod series;
+mod snapshot;
+mod stats;
+mod sync;
+mod time;
+mod value;
+mod wal;
 
-pub use cache::Cache;
-pub use cell::{Bytes, BytesRef, Id};
+pub use cache::{Cache, LocalCache};
+pub use cell::{Bytes, BytesRef, CacheCell, CacheCounters, Id};
+pub use codec::{BlockCodec, CodecStats, Lz4};
+pub use error::{Corrupted, Error};
+pub use evict::{Clock, EvictionMode, EvictionPolicy, Lru, WTinyLfu};
+pub use item::{BlockSlice, Blocks, Fragmentation, SharedBytes};
+pub use pool::{BlockPool, Buf, HeapPool, LocalPool, PoolStats, SharedPool};
+pub use scan::{ScanCursor, ScanPage};
+pub use series::{Point, Points, SeriesItem};
+pub use stats::{CacheStats, ItemStats};
+pub use sync::{Local, SyncMode, Threaded};
+pub use time::{ManualTime, RealTime, TimeSource};
+pub use value::{Key, Value};
+pub use wal::{Durable, FsyncPolicy, Wal, WalOptions, WalRecord};
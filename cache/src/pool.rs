@@ -0,0 +1,435 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// Blocks in one slab by default, a 2 MiB huge page with the default block
+/// size.
+const SLAB_BLOCKS: usize = 128;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// Where blocks of a cache come from, and go back to.
+pub trait BlockPool {
+    /// A zeroed or reused buf of `size` bytes.
+    fn alloc(&self, size: usize) -> Buf;
+
+    /// Give `buf` back for reuse. Bufs dropped elsewhere go back to their
+    /// slab, and are picked up by the pool when it runs out.
+    fn free(&self, buf: Buf);
+
+    fn stats(&self) -> PoolStats;
+}
+
+/// Counters of a pool since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Blocks handed out.
+    pub allocs: u64,
+    /// Allocations served by a recycled block.
+    pub reuses: u64,
+    /// Blocks given back through [`BlockPool::free`].
+    pub frees: u64,
+    /// Slabs allocated.
+    pub slabs: u64,
+    /// Slabs backed by huge pages.
+    pub huge_slabs: u64,
+    /// Blocks waiting for reuse.
+    pub idle: usize,
+}
+
+/// Bytes of one block, either on its own in the heap or carved from a slab.
+pub struct Buf(BufInner);
+
+enum BufInner {
+    Heap(Box<[u8]>),
+    Slab { slab: Arc<Slab>, index: usize },
+}
+
+impl Buf {
+    /// A zeroed buf of `size` bytes straight from the global allocator.
+    pub fn heap(size: usize) -> Self {
+        Buf(BufInner::Heap(vec![0; size].into_boxed_slice()))
+    }
+}
+
+impl From<Vec<u8>> for Buf {
+    /// A heap buf holding `bytes`, as long as they are.
+    fn from(bytes: Vec<u8>) -> Self {
+        Buf(BufInner::Heap(bytes.into_boxed_slice()))
+    }
+}
+
+impl Deref for Buf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match &self.0 {
+            BufInner::Heap(bytes) => bytes,
+            // Safety: every index of a slab is owned by one buf at a time.
+            BufInner::Slab { slab, index } => unsafe {
+                slice::from_raw_parts(slab.block(*index), slab.block_size)
+            },
+        }
+    }
+}
+
+impl DerefMut for Buf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.0 {
+            BufInner::Heap(bytes) => bytes,
+            BufInner::Slab { slab, index } => unsafe {
+                slice::from_raw_parts_mut(slab.block(*index), slab.block_size)
+            },
+        }
+    }
+}
+
+impl Clone for Buf {
+    /// Copy into a heap buf, it's only done when a shared block is written.
+    fn clone(&self) -> Self {
+        let mut buf = Buf::heap(self.len());
+        buf.copy_from_slice(self);
+        buf
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        if let BufInner::Slab { slab, index } = &self.0 {
+            slab.returned.lock().unwrap().push(*index);
+        }
+    }
+}
+
+/// Memory of many blocks allocated at once.
+struct Slab {
+    ptr: NonNull<u8>,
+    block_size: usize,
+    blocks: usize,
+    huge: bool,
+    /// Indexes of bufs dropped outside the pool.
+    returned: Mutex<Vec<usize>>,
+}
+
+// Safety: slab memory is only reached through bufs, each owning its index.
+unsafe impl Send for Slab {}
+unsafe impl Sync for Slab {}
+
+impl Slab {
+    fn new(block_size: usize, blocks: usize, huge_pages: bool) -> Self {
+        let len = block_size * blocks;
+        if huge_pages {
+            if let Some(ptr) = Self::map_huge(len) {
+                return Self::from_ptr(ptr, block_size, blocks, true);
+            }
+        }
+        let ptr = unsafe { alloc_zeroed(Self::layout(len)) };
+        let ptr = NonNull::new(ptr).expect("slab allocation failed");
+        Self::from_ptr(ptr, block_size, blocks, false)
+    }
+
+    fn from_ptr(ptr: NonNull<u8>, block_size: usize, blocks: usize, huge: bool) -> Self {
+        Self {
+            ptr,
+            block_size,
+            blocks,
+            huge,
+            returned: Mutex::new(vec![]),
+        }
+    }
+
+    /// Bytes of all blocks.
+    fn len(&self) -> usize {
+        self.block_size * self.blocks
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, PAGE_SIZE).unwrap()
+    }
+
+    fn huge_len(len: usize) -> usize {
+        len.div_ceil(HUGE_PAGE_SIZE) * HUGE_PAGE_SIZE
+    }
+
+    /// Map anonymous huge pages, `None` if the system has none reserved.
+    #[cfg(target_os = "linux")]
+    fn map_huge(len: usize) -> Option<NonNull<u8>> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                Self::huge_len(len),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        NonNull::new(ptr as *mut u8)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn map_huge(_len: usize) -> Option<NonNull<u8>> {
+        None
+    }
+
+    fn block(&self, index: usize) -> *mut u8 {
+        debug_assert!(index < self.blocks);
+        unsafe { self.ptr.as_ptr().add(index * self.block_size) }
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if self.huge {
+            unsafe { libc::munmap(self.ptr.as_ptr() as _, Self::huge_len(self.len())) };
+            return;
+        }
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len())) }
+    }
+}
+
+/// Free blocks and the slabs they are carved from. Slabs hold blocks of one
+/// size, and free blocks are kept apart by size.
+struct PoolState {
+    slab_blocks: usize,
+    huge_pages: bool,
+    free: HashMap<usize, Vec<Buf>>,
+    slabs: Vec<Arc<Slab>>,
+    stats: PoolStats,
+}
+
+impl PoolState {
+    fn new(slab_blocks: usize, huge_pages: bool) -> Self {
+        Self {
+            slab_blocks,
+            huge_pages,
+            free: HashMap::new(),
+            slabs: vec![],
+            stats: PoolStats::default(),
+        }
+    }
+
+    fn alloc(&mut self, size: usize) -> Buf {
+        self.stats.allocs += 1;
+        if self.free.get(&size).is_none_or(Vec::is_empty) {
+            self.reclaim();
+        }
+        match self.free.get_mut(&size).and_then(Vec::pop) {
+            Some(buf) => {
+                self.stats.reuses += 1;
+                buf
+            }
+            None => self.grow(size),
+        }
+    }
+
+    fn free(&mut self, buf: Buf) {
+        self.stats.frees += 1;
+        // Heap bufs of other sizes, like compressed blocks, would never be
+        // handed out again.
+        let reusable = match &buf.0 {
+            BufInner::Heap(bytes) => self.slabs.iter().any(|slab| slab.block_size == bytes.len()),
+            BufInner::Slab { .. } => true,
+        };
+        if reusable {
+            self.free.entry(buf.len()).or_default().push(buf);
+        }
+    }
+
+    /// Take back bufs dropped outside the pool.
+    fn reclaim(&mut self) {
+        for slab in &self.slabs {
+            let returned = std::mem::take(&mut *slab.returned.lock().unwrap());
+            if returned.is_empty() {
+                continue;
+            }
+            let free = self.free.entry(slab.block_size).or_default();
+            free.extend(returned.into_iter().map(|index| {
+                Buf(BufInner::Slab {
+                    slab: slab.clone(),
+                    index,
+                })
+            }));
+        }
+    }
+
+    /// Allocate a new slab of `size` byte blocks, returning its first block.
+    fn grow(&mut self, size: usize) -> Buf {
+        let slab = Arc::new(Slab::new(size, self.slab_blocks, self.huge_pages));
+        self.stats.slabs += 1;
+        if slab.huge {
+            self.stats.huge_slabs += 1;
+        }
+        let free = self.free.entry(size).or_default();
+        free.extend((1..slab.blocks).rev().map(|index| {
+            Buf(BufInner::Slab {
+                slab: slab.clone(),
+                index,
+            })
+        }));
+        self.slabs.push(slab.clone());
+        Buf(BufInner::Slab { slab, index: 0 })
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            idle: self.free.values().map(Vec::len).sum(),
+            ..self.stats
+        }
+    }
+}
+
+/// Every block from the global allocator, dropped when freed. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapPool;
+
+impl BlockPool for HeapPool {
+    fn alloc(&self, size: usize) -> Buf {
+        Buf::heap(size)
+    }
+
+    fn free(&self, _buf: Buf) {}
+
+    fn stats(&self) -> PoolStats {
+        PoolStats::default()
+    }
+}
+
+/// Pool shared by threads, for a cache used from many of them.
+pub struct SharedPool(Mutex<PoolState>);
+
+impl Default for SharedPool {
+    fn default() -> Self {
+        Self(Mutex::new(PoolState::new(SLAB_BLOCKS, false)))
+    }
+}
+
+impl SharedPool {
+    /// Allocate `blocks` blocks in each slab.
+    pub fn with_slab_blocks(self, blocks: usize) -> Self {
+        self.0.lock().unwrap().slab_blocks = blocks.max(1);
+        self
+    }
+
+    /// Back slabs with huge pages when the system has them reserved.
+    pub fn with_huge_pages(self) -> Self {
+        self.0.lock().unwrap().huge_pages = true;
+        self
+    }
+}
+
+impl BlockPool for SharedPool {
+    fn alloc(&self, size: usize) -> Buf {
+        self.0.lock().unwrap().alloc(size)
+    }
+
+    fn free(&self, buf: Buf) {
+        self.0.lock().unwrap().free(buf)
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.0.lock().unwrap().stats()
+    }
+}
+
+/// Pool of one thread, for the caches of a shard. Share it with [`Rc`].
+pub struct LocalPool(RefCell<PoolState>);
+
+impl Default for LocalPool {
+    fn default() -> Self {
+        Self(RefCell::new(PoolState::new(SLAB_BLOCKS, false)))
+    }
+}
+
+impl LocalPool {
+    /// Allocate `blocks` blocks in each slab.
+    pub fn with_slab_blocks(self, blocks: usize) -> Self {
+        self.0.borrow_mut().slab_blocks = blocks.max(1);
+        self
+    }
+
+    /// Back slabs with huge pages when the system has them reserved.
+    pub fn with_huge_pages(self) -> Self {
+        self.0.borrow_mut().huge_pages = true;
+        self
+    }
+}
+
+impl BlockPool for LocalPool {
+    fn alloc(&self, size: usize) -> Buf {
+        self.0.borrow_mut().alloc(size)
+    }
+
+    fn free(&self, buf: Buf) {
+        self.0.borrow_mut().free(buf)
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.0.borrow().stats()
+    }
+}
+
+impl<P: BlockPool> BlockPool for Rc<P> {
+    fn alloc(&self, size: usize) -> Buf {
+        (**self).alloc(size)
+    }
+
+    fn free(&self, buf: Buf) {
+        (**self).free(buf)
+    }
+
+    fn stats(&self) -> PoolStats {
+        (**self).stats()
+    }
+}
+
+impl<P: BlockPool> BlockPool for Arc<P> {
+    fn alloc(&self, size: usize) -> Buf {
+        (**self).alloc(size)
+    }
+
+    fn free(&self, buf: Buf) {
+        (**self).free(buf)
+    }
+
+    fn stats(&self) -> PoolStats {
+        (**self).stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn odd_sized_heap_bufs_are_dropped() {
+        let pool = LocalPool::default().with_slab_blocks(4);
+        let buf = pool.alloc(64);
+        pool.free(buf);
+        assert_eq!(pool.stats().idle, 4);
+
+        // A compressed block, no slab has blocks of its size.
+        pool.free(Buf::from(vec![1; 37]));
+        assert_eq!(pool.stats().idle, 4);
+        // A copy of a shared slab block.
+        pool.free(Buf::heap(64));
+        assert_eq!(pool.stats().idle, 5);
+
+        let stats = pool.stats();
+        assert_eq!((stats.frees, stats.slabs), (3, 1));
+        for _ in 0..5 {
+            assert_eq!(pool.alloc(64).len(), 64);
+        }
+        assert_eq!(pool.stats().reuses, 5);
+        assert_eq!(pool.stats().idle, 0);
+    }
+}
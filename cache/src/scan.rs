@@ -0,0 +1,139 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::{Bound, RangeBounds};
+
+/// Items of a scan in id order, and where the next page starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanPage<K, V> {
+    pub items: Vec<(K, V)>,
+    /// Range of the next page, `None` once the scan is done.
+    pub next: Option<ScanCursor<K>>,
+}
+
+/// The rest of a scanned range, after the last item of a page. Pass it to
+/// the next scan as its range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanCursor<K> {
+    after: K,
+    end: Bound<K>,
+}
+
+impl<K: Clone> ScanCursor<K> {
+    /// Range after `last`, up to the end of `range`.
+    pub(crate) fn after<R: RangeBounds<K>>(last: &K, range: &R) -> Self {
+        Self {
+            after: last.clone(),
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+impl<K> RangeBounds<K> for ScanCursor<K> {
+    fn start_bound(&self) -> Bound<&K> {
+        Bound::Excluded(&self.after)
+    }
+
+    fn end_bound(&self) -> Bound<&K> {
+        self.end.as_ref()
+    }
+}
+
+impl<K: Ord + Clone, V> ScanPage<K, V> {
+    /// Merge pages of one `range` from caches holding different ids into
+    /// one page of at most `limit` items, in id order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub fn merge<R: RangeBounds<K>>(pages: Vec<Self>, range: &R, limit: usize) -> Self {
+        assert!(limit > 0, "scan of 0 items");
+        let mut more = pages.iter().any(|page| page.next.is_some());
+        let mut sources = pages
+            .into_iter()
+            .map(|page| page.items.into_iter().peekable())
+            .collect::<Vec<_>>();
+        let mut heads = BinaryHeap::with_capacity(sources.len());
+        for (index, source) in sources.iter_mut().enumerate() {
+            if let Some((key, _)) = source.peek() {
+                heads.push(Reverse((key.clone(), index)));
+            }
+        }
+
+        let mut items = Vec::with_capacity(limit.min(heads.len()));
+        while let Some(Reverse((_, index))) = heads.pop() {
+            if items.len() == limit {
+                more = true;
+                break;
+            }
+            let source = &mut sources[index];
+            items.extend(source.next());
+            if let Some((key, _)) = source.peek() {
+                heads.push(Reverse((key.clone(), index)));
+            }
+        }
+
+        let next = match items.last() {
+            Some((last, _)) if more => Some(ScanCursor::after(last, range)),
+            _ => None,
+        };
+        Self { items, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bytes, Cache, Id};
+
+    /// A page of `range` merged from every cache.
+    fn scan<R: RangeBounds<Id> + Clone>(
+        caches: &[Cache],
+        range: R,
+        limit: usize,
+    ) -> ScanPage<Id, Bytes> {
+        let pages = caches
+            .iter()
+            .map(|cache| cache.scan(range.clone(), limit).unwrap())
+            .collect();
+        ScanPage::merge(pages, &range, limit)
+    }
+
+    /// Ids of every page of `range`, checking no page holds over `limit`.
+    fn scan_all<R: RangeBounds<Id> + Clone>(caches: &[Cache], range: R, limit: usize) -> Vec<Id> {
+        let mut ids = vec![];
+        let mut page = scan(caches, range, limit);
+        loop {
+            assert!(page.items.len() <= limit);
+            ids.extend(page.items.iter().map(|(id, value)| {
+                assert_eq!(value, &id.to_le_bytes());
+                *id
+            }));
+            match page.next {
+                Some(cursor) => page = scan(caches, cursor, limit),
+                None => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_of_several_caches_hold_every_id_once_in_order() {
+        let caches: Vec<Cache> = (0..3).map(|_| Cache::default()).collect();
+        // Runs of ids in one cache, and caches without ids of a range.
+        let ids: Vec<Id> = (0..100).filter(|id| id % 7 != 3).chain(200..210).collect();
+        for &id in ids.iter() {
+            let shard = if id < 200 { id / 5 % 3 } else { 2 };
+            caches[shard].append(id, id.to_le_bytes().to_vec()).unwrap();
+        }
+
+        for limit in [1, 2, 3, 7, 100, 1000] {
+            assert_eq!(scan_all(&caches, .., limit), ids, "{:?}", limit);
+            let range: Vec<_> = ids
+                .iter()
+                .copied()
+                .filter(|id| (10..=95).contains(id))
+                .collect();
+            assert_eq!(scan_all(&caches, 10..=95, limit), range, "{:?}", limit);
+        }
+        assert!(scan_all(&caches, 100..200, 2).is_empty());
+    }
+}
@@ -0,0 +1,598 @@
+//! Time-series item compressed as in Facebook's Gorilla: delta-of-delta
+//! timestamps and XOR'd float values, written bit by bit into blocks.
+
+use crate::bits::{BitReader, BitWriter};
+use crate::crc::crc32c_append;
+use crate::error::Corrupted;
+use crate::item::{Block, BLOCK_SIZE};
+use crate::pool::{BlockPool, HeapPool};
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::time::Instant;
+
+/// Worst case size of one encoded point: `1111` + 64 bits delta-of-delta,
+/// `11` + 5 bits leading zeros + 6 bits length + 64 bits value.
+const MAX_POINT_BITS: usize = 4 + 64 + 2 + 5 + 6 + 64;
+
+/// Smallest series block, one that holds any point.
+const MIN_BLOCK_SIZE: usize = MAX_POINT_BITS.div_ceil(8);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+impl Point {
+    pub fn new(timestamp: i64, value: f64) -> Self {
+        Self { timestamp, value }
+    }
+}
+
+/// Encoder state carried from one point to the next.
+#[derive(Clone, Copy, Default)]
+struct State {
+    timestamp: i64,
+    delta: i64,
+    value: u64,
+    leading: u32,
+    trailing: u32,
+}
+
+/// One block of encoded points. The first point is stored raw, every later
+/// one relative to its predecessor.
+///
+/// The checksum covers the bytes no later point changes: whole bytes while
+/// points are appended, and the last partial byte too once the block is
+/// sealed.
+struct SeriesBlock {
+    block: Block,
+    bits: usize,
+    sealed: bool,
+    count: usize,
+    min_timestamp: i64,
+    max_timestamp: i64,
+    state: State,
+}
+
+impl SeriesBlock {
+    fn new(block: Block) -> Self {
+        Self {
+            block,
+            bits: 0,
+            sealed: false,
+            count: 0,
+            min_timestamp: i64::MAX,
+            max_timestamp: i64::MIN,
+            state: State::default(),
+        }
+    }
+
+    /// Bytes this block takes, its buf included.
+    fn memory(&self) -> usize {
+        size_of::<SeriesBlock>() + self.block.block.len()
+    }
+
+    /// Whether another point is guaranteed to fit.
+    fn has_room(&self) -> bool {
+        self.block.block.len() * 8 - self.bits >= MAX_POINT_BITS
+    }
+
+    fn append(&mut self, point: Point) {
+        debug_assert!(self.has_room() && !self.sealed);
+        let complete = self.bits / 8;
+        let mut writer = BitWriter::new(&mut self.block.block[..], self.bits);
+        let value = point.value.to_bits();
+
+        if self.count == 0 {
+            writer.write_bits(point.timestamp as u64, 64);
+            writer.write_bits(value, 64);
+            self.state = State {
+                timestamp: point.timestamp,
+                delta: 0,
+                value,
+                // No window to reuse yet.
+                leading: u32::MAX,
+                trailing: 0,
+            };
+        } else {
+            let delta = point.timestamp.wrapping_sub(self.state.timestamp);
+            write_dod(&mut writer, delta.wrapping_sub(self.state.delta));
+            self.state.timestamp = point.timestamp;
+            self.state.delta = delta;
+            write_xor(&mut writer, &mut self.state, value);
+        }
+
+        self.bits = writer.pos();
+        self.block.used = self.bits.div_ceil(8);
+        self.block.crc = crc32c_append(self.block.crc, &self.block.block[complete..self.bits / 8]);
+        self.count += 1;
+        self.min_timestamp = self.min_timestamp.min(point.timestamp);
+        self.max_timestamp = self.max_timestamp.max(point.timestamp);
+    }
+
+    /// Take the last partial byte into the checksum, once no point is
+    /// appended anymore.
+    fn seal(&mut self) {
+        let complete = self.bits / 8;
+        self.block.crc =
+            crc32c_append(self.block.crc, &self.block.block[complete..self.block.used]);
+        self.sealed = true;
+    }
+
+    /// Check the bytes covered by the checksum. `index` is where the block
+    /// is in its item.
+    fn verify(&self, index: usize) -> Result<(), Corrupted> {
+        let checked = match self.sealed {
+            true => self.block.used,
+            false => self.bits / 8,
+        };
+        self.block.check(index, &self.block.block[..checked])
+    }
+
+    fn points(&self) -> BlockPoints<'_> {
+        BlockPoints {
+            reader: BitReader::new(self.block.data()),
+            remaining: self.count,
+            first: true,
+            state: State::default(),
+        }
+    }
+}
+
+/// Encode delta-of-delta with the smallest bucket that holds it.
+fn write_dod(writer: &mut BitWriter, dod: i64) {
+    match dod {
+        0 => writer.write_bit(false),
+        -64..=63 => {
+            writer.write_bits(0b10, 2);
+            writer.write_bits(dod as u64, 7);
+        }
+        -256..=255 => {
+            writer.write_bits(0b110, 3);
+            writer.write_bits(dod as u64, 9);
+        }
+        -2048..=2047 => {
+            writer.write_bits(0b1110, 4);
+            writer.write_bits(dod as u64, 12);
+        }
+        _ => {
+            writer.write_bits(0b1111, 4);
+            writer.write_bits(dod as u64, 64);
+        }
+    }
+}
+
+fn read_dod(reader: &mut BitReader) -> Option<i64> {
+    let mut prefix = 0;
+    while prefix < 4 && reader.read_bit()? {
+        prefix += 1;
+    }
+    let bits = match prefix {
+        0 => return Some(0),
+        1 => 7,
+        2 => 9,
+        3 => 12,
+        _ => 64,
+    };
+    let raw = reader.read_bits(bits)?;
+    // Sign extend from `bits` wide.
+    let shift = 64 - bits;
+    Some(((raw << shift) as i64) >> shift)
+}
+
+/// XOR `value` with the previous one and write the meaningful bits, reusing
+/// the previous leading/trailing zeros window when the new bits fit in it.
+fn write_xor(writer: &mut BitWriter, state: &mut State, value: u64) {
+    let xor = value ^ state.value;
+    state.value = value;
+    if xor == 0 {
+        writer.write_bit(false);
+        return;
+    }
+
+    // Leading zeros are stored in 5 bits.
+    let leading = xor.leading_zeros().min(31);
+    let trailing = xor.trailing_zeros();
+    if state.leading != u32::MAX && leading >= state.leading && trailing >= state.trailing {
+        writer.write_bits(0b10, 2);
+        let len = 64 - state.leading - state.trailing;
+        writer.write_bits(xor >> state.trailing, len);
+    } else {
+        let len = 64 - leading - trailing;
+        writer.write_bits(0b11, 2);
+        writer.write_bits(leading as u64, 5);
+        // Length is in 1..=64, stored minus one in 6 bits.
+        writer.write_bits((len - 1) as u64, 6);
+        writer.write_bits(xor >> trailing, len);
+        state.leading = leading;
+        state.trailing = trailing;
+    }
+}
+
+fn read_xor(reader: &mut BitReader, state: &mut State) -> Option<u64> {
+    if !reader.read_bit()? {
+        return Some(state.value);
+    }
+    if reader.read_bit()? {
+        state.leading = reader.read_bits(5)? as u32;
+        let len = reader.read_bits(6)? as u32 + 1;
+        state.trailing = 64 - state.leading - len;
+    }
+    let len = 64 - state.leading - state.trailing;
+    let xor = reader.read_bits(len)? << state.trailing;
+    state.value ^= xor;
+    Some(state.value)
+}
+
+/// Decoding iterator over one block.
+struct BlockPoints<'a> {
+    reader: BitReader<'a>,
+    remaining: usize,
+    first: bool,
+    state: State,
+}
+
+impl Iterator for BlockPoints<'_> {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        if self.first {
+            self.first = false;
+            self.state.timestamp = self.reader.read_bits(64)? as i64;
+            self.state.value = self.reader.read_bits(64)?;
+        } else {
+            let dod = read_dod(&mut self.reader)?;
+            self.state.delta = self.state.delta.wrapping_add(dod);
+            self.state.timestamp = self.state.timestamp.wrapping_add(self.state.delta);
+            read_xor(&mut self.reader, &mut self.state)?;
+        }
+
+        Some(Point::new(
+            self.state.timestamp,
+            f64::from_bits(self.state.value),
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// Points of one id, compressed into blocks in append order.
+#[derive(Default)]
+pub struct SeriesItem {
+    blocks: VecDeque<SeriesBlock>,
+}
+
+impl SeriesItem {
+    /// Append a point into blocks of the default size from the heap.
+    pub fn append(&mut self, timestamp: i64, value: f64) {
+        self.append_at(timestamp, value, Instant::now(), &HeapPool, BLOCK_SIZE)
+    }
+
+    /// Append a point, marking its block as written at `now`. New blocks
+    /// of `block_size` bytes come from `pool`, or of the smallest size that
+    /// holds any point if `block_size` is less.
+    pub(crate) fn append_at<P: BlockPool>(
+        &mut self,
+        timestamp: i64,
+        value: f64,
+        now: Instant,
+        pool: &P,
+        block_size: usize,
+    ) {
+        if !self.blocks.back().is_some_and(SeriesBlock::has_room) {
+            if let Some(last) = self.blocks.back_mut() {
+                last.seal();
+            }
+            let size = block_size.max(MIN_BLOCK_SIZE);
+            let block = Block::new(pool.alloc(size), now);
+            self.blocks.push_back(SeriesBlock::new(block));
+        }
+        let block = self.blocks.back_mut().unwrap();
+        block.block.written_at = now;
+        block.append(Point::new(timestamp, value));
+    }
+
+    /// Number of points.
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|block| block.count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes taken by encoded points.
+    pub fn encoded_bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.block.used).sum()
+    }
+
+    /// Bytes allocated for blocks.
+    pub fn memory(&self) -> usize {
+        self.blocks.iter().map(SeriesBlock::memory).sum()
+    }
+
+    /// Drop the oldest block, giving its buf back to `pool`. Returns the
+    /// bytes released.
+    pub fn pop_front_block<P: BlockPool>(&mut self, pool: &P) -> usize {
+        match self.blocks.pop_front() {
+            Some(block) => {
+                let freed = block.memory();
+                pool.free(block.block.block);
+                freed
+            }
+            None => 0,
+        }
+    }
+
+    /// Drop every block, giving their bufs back to `pool`. Returns the bytes
+    /// released.
+    pub fn clear<P: BlockPool>(&mut self, pool: &P) -> usize {
+        let mut freed = 0;
+        while !self.blocks.is_empty() {
+            freed += self.pop_front_block(pool);
+        }
+        freed
+    }
+
+    /// Whether the oldest block is last written before `cutoff`.
+    pub fn has_expired(&self, cutoff: Instant) -> bool {
+        self.blocks
+            .front()
+            .is_some_and(|block| block.block.written_at < cutoff)
+    }
+
+    /// Drop blocks last written before `cutoff`, giving their bufs back to
+    /// `pool`. Returns the bytes released.
+    pub fn expire<P: BlockPool>(&mut self, cutoff: Instant, pool: &P) -> usize {
+        let mut freed = 0;
+        while self.has_expired(cutoff) {
+            freed += self.pop_front_block(pool);
+        }
+        freed
+    }
+
+    /// Encoded size over the size of raw `(i64, f64)` pairs.
+    pub fn compression_ratio(&self) -> f64 {
+        self.encoded_bytes() as f64 / (self.len() * 16) as f64
+    }
+
+    /// Points with timestamp in `[start, end)`, in append order. Blocks whose
+    /// timestamps are all out of the range are not decoded, the others are
+    /// checked against their checksum first.
+    pub fn query(&self, start: i64, end: i64) -> Result<Vec<Point>, Corrupted> {
+        let mut points = vec![];
+        for (index, block) in self.blocks.iter().enumerate() {
+            if block.max_timestamp < start || block.min_timestamp >= end {
+                continue;
+            }
+            block.verify(index)?;
+            points.extend(
+                block
+                    .points()
+                    .filter(|point| point.timestamp >= start && point.timestamp < end),
+            );
+        }
+        Ok(points)
+    }
+
+    /// Decode all points in append order, after checking every block
+    /// against its checksum.
+    pub fn points(&self) -> Result<Points<'_>, Corrupted> {
+        for (index, block) in self.blocks.iter().enumerate() {
+            block.verify(index)?;
+        }
+        Ok(Points {
+            blocks: self.blocks.iter(),
+            current: None,
+        })
+    }
+}
+
+/// Decoding iterator returned by [`SeriesItem::points`].
+pub struct Points<'a> {
+    blocks: std::collections::vec_deque::Iter<'a, SeriesBlock>,
+    current: Option<BlockPoints<'a>>,
+}
+
+impl Iterator for Points<'_> {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        loop {
+            if let Some(point) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(point);
+            }
+            self.current = Some(self.blocks.next()?.points());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift, the series only need to look random.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// In `-bound..bound`.
+        fn jitter(&mut self, bound: i64) -> i64 {
+            (self.next() % (2 * bound as u64)) as i64 - bound
+        }
+    }
+
+    /// Append `points`, check they decode bit for bit, and return the
+    /// compression ratio.
+    fn round_trip(points: &[Point]) -> f64 {
+        let mut item = SeriesItem::default();
+        for point in points {
+            item.append(point.timestamp, point.value);
+        }
+        assert_eq!(item.len(), points.len());
+        let bits = |points: &mut dyn Iterator<Item = Point>| -> Vec<(i64, u64)> {
+            points
+                .map(|point| (point.timestamp, point.value.to_bits()))
+                .collect()
+        };
+        assert_eq!(
+            bits(&mut item.points().unwrap()),
+            bits(&mut points.iter().copied())
+        );
+        item.compression_ratio()
+    }
+
+    fn series(len: i64, point: impl FnMut(i64) -> Point) -> Vec<Point> {
+        (0..len).map(point).collect()
+    }
+
+    #[test]
+    fn constant() {
+        // 2 bits a point: a delta-of-delta and a XOR of 0.
+        let ratio = round_trip(&series(10_000, |i| Point::new(i * 1000, 42.5)));
+        assert!(ratio < 0.02, "{}", ratio);
+    }
+
+    #[test]
+    fn counter() {
+        let ratio = round_trip(&series(10_000, |i| Point::new(i * 1000, i as f64)));
+        assert!(ratio < 0.15, "{}", ratio);
+    }
+
+    #[test]
+    fn gauge() {
+        let ratio = round_trip(&series(10_000, |i| {
+            let value = ((i as f64 / 50.0).sin() * 1000.0).round() / 10.0;
+            Point::new(1_600_000_000_000 + i * 15_000, value)
+        }));
+        assert!(ratio < 0.6, "{}", ratio);
+    }
+
+    #[test]
+    fn jittered() {
+        let mut rng = Rng(7);
+        let ratio = round_trip(&series(10_000, |i| {
+            Point::new(i * 1000 + rng.jitter(100), (i % 100) as f64)
+        }));
+        assert!(ratio < 0.3, "{}", ratio);
+    }
+
+    #[test]
+    fn random() {
+        let mut rng = Rng(11);
+        let points = series(10_000, |_| {
+            Point::new(rng.next() as i64, f64::from_bits(rng.next()))
+        });
+        let ratio = round_trip(&points);
+        // Random bits don't compress, but stay within the worst case.
+        assert!(ratio > 1.0, "{}", ratio);
+        assert!(ratio <= MAX_POINT_BITS as f64 / 128.0, "{}", ratio);
+
+        let mut item = SeriesItem::default();
+        for point in &points {
+            item.append(point.timestamp, point.value);
+        }
+        assert!(item.blocks.len() > 8);
+        let (start, end) = (-1 << 61, 1 << 60);
+        let expected: Vec<_> = points
+            .iter()
+            .filter(|point| point.timestamp >= start && point.timestamp < end)
+            .map(|point| (point.timestamp, point.value.to_bits()))
+            .collect();
+        let found: Vec<_> = item
+            .query(start, end)
+            .unwrap()
+            .iter()
+            .map(|point| (point.timestamp, point.value.to_bits()))
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn edge_timestamps_and_values() {
+        let timestamps = [
+            i64::MIN,
+            i64::MAX,
+            0,
+            i64::MIN,
+            i64::MIN + 1,
+            -1,
+            i64::MAX - 1,
+            i64::MAX,
+            i64::MAX,
+            1,
+            i64::MIN,
+        ];
+        let values = [
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE / 2.0,
+            f64::MAX,
+            f64::MIN,
+            f64::EPSILON,
+            1.0,
+            f64::from_bits(1),
+        ];
+        let points: Vec<_> = timestamps
+            .iter()
+            .zip(&values)
+            .map(|(&timestamp, &value)| Point::new(timestamp, value))
+            .collect();
+        let ratio = round_trip(&points);
+        assert!(ratio <= MAX_POINT_BITS as f64 / 128.0, "{}", ratio);
+
+        let mut item = SeriesItem::default();
+        for point in &points {
+            item.append(point.timestamp, point.value);
+        }
+        assert_eq!(item.query(i64::MIN, i64::MIN + 1).unwrap().len(), 3);
+        assert_eq!(item.query(i64::MAX, i64::MAX).unwrap().len(), 0);
+        assert_eq!(item.query(i64::MIN, i64::MAX).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn corrupted_blocks_fail_reads() {
+        let mut rng = Rng(13);
+        let mut item = SeriesItem::default();
+        let mut timestamp = 0;
+        while item.blocks.len() < 2 {
+            item.append(timestamp, f64::from_bits(rng.next()));
+            timestamp += 1000;
+        }
+        // The checksum of the open block follows its appends.
+        for i in 0..100 {
+            item.append(timestamp + i, i as f64);
+            item.points().unwrap();
+        }
+
+        let sealed = &mut item.blocks[0].block;
+        let last = sealed.used - 1;
+        sealed.block[last] ^= 1;
+        let error = item.points().err().unwrap();
+        assert_eq!(error.block, 0);
+        // Blocks out of the range aren't read.
+        assert_eq!(item.query(timestamp, timestamp + 1).unwrap().len(), 1);
+        assert!(item.query(i64::MIN, i64::MAX).is_err());
+        item.blocks[0].block.block[last] ^= 1;
+
+        item.blocks[1].block.block[0] ^= 0x80;
+        assert_eq!(item.points().err().unwrap().block, 1);
+    }
+}
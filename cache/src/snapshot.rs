@@ -0,0 +1,347 @@
+//! Snapshot file format.
+//!
+//! All integers are little endian.
+//!
+//! ```text
+//! header:   magic "CCSNAPSH" | version u32 | log seq u64
+//! record:   kind u8 | payload length u32 | payload | crc32c(kind, payload) u32
+//!
+//! bytes:    kind 1, key length u32 | key | block count u32 | (length u32 | block)*
+//! points:   kind 2, key length u32 | key | point count u32 | (timestamp i64 | value f64)*
+//! end:      kind 0, record count u64
+//! ```
+//!
+//! Each record is checked on its own, and a snapshot without its end record
+//! is truncated. The log seq is the sequence number of the first
+//! write-ahead log record the snapshot doesn't hold, 0 if it's taken apart
+//! from a log. Version 1 has no log seq.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use crate::cell::{Bytes, BytesRef};
+use crate::crc::{crc32c, crc32c_append};
+use crate::series::Point;
+
+const MAGIC: &[u8; 8] = b"CCSNAPSH";
+const VERSION: u32 = 2;
+
+const KIND_END: u8 = 0;
+const KIND_BYTES: u8 = 1;
+const KIND_POINTS: u8 = 2;
+
+/// Records of a snapshot, and the log seq it was taken at.
+pub(crate) struct Snapshot {
+    pub log_seq: u64,
+    pub records: Vec<Record>,
+}
+
+/// One id read back from a snapshot.
+pub(crate) enum Record {
+    Bytes { key: Bytes, blocks: Vec<Bytes> },
+    Points { key: Bytes, points: Vec<Point> },
+}
+
+pub(crate) struct SnapshotWriter<W> {
+    writer: W,
+    records: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(mut writer: W, log_seq: u64) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&log_seq.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            records: 0,
+            buf: vec![],
+        })
+    }
+
+    pub fn write_bytes(
+        &mut self,
+        key: BytesRef,
+        blocks: impl Iterator<Item = impl AsRef<[u8]>>,
+    ) -> io::Result<()> {
+        self.buf.clear();
+        put_bytes(&mut self.buf, key);
+        let count_at = self.buf.len();
+        self.buf.extend_from_slice(&0u32.to_le_bytes());
+        let mut count = 0u32;
+        for block in blocks {
+            put_bytes(&mut self.buf, block.as_ref());
+            count += 1;
+        }
+        self.buf[count_at..count_at + 4].copy_from_slice(&count.to_le_bytes());
+        self.write_record(KIND_BYTES)
+    }
+
+    pub fn write_points(
+        &mut self,
+        key: BytesRef,
+        points: impl Iterator<Item = Point>,
+    ) -> io::Result<()> {
+        self.buf.clear();
+        put_bytes(&mut self.buf, key);
+        let count_at = self.buf.len();
+        self.buf.extend_from_slice(&0u32.to_le_bytes());
+        let mut count = 0u32;
+        for point in points {
+            self.buf.extend_from_slice(&point.timestamp.to_le_bytes());
+            self.buf.extend_from_slice(&point.value.to_le_bytes());
+            count += 1;
+        }
+        self.buf[count_at..count_at + 4].copy_from_slice(&count.to_le_bytes());
+        self.write_record(KIND_POINTS)
+    }
+
+    /// Write the end record and flush.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.buf.clear();
+        let records = self.records;
+        self.buf.extend_from_slice(&records.to_le_bytes());
+        self.write_record(KIND_END)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_record(&mut self, kind: u8) -> io::Result<()> {
+        let len: u32 = self
+            .buf
+            .len()
+            .try_into()
+            .map_err(|_| invalid_input("snapshot record over 4 GiB"))?;
+        let crc = crc32c_append(crc32c(&[kind]), &self.buf);
+        self.writer.write_all(&[kind])?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&self.buf)?;
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+/// Read and check every record of a snapshot.
+pub(crate) fn read_snapshot<R: Read>(mut reader: R) -> io::Result<Snapshot> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a snapshot"));
+    }
+    let log_seq = match u32::from_le_bytes(read_array(&mut reader)?) {
+        1 => 0,
+        VERSION => u64::from_le_bytes(read_array(&mut reader)?),
+        version => {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {version}"
+            )))
+        }
+    };
+
+    let mut records = vec![];
+    loop {
+        let [kind] = read_array(&mut reader)?;
+        let len = u32::from_le_bytes(read_array(&mut reader)?);
+        // The length is not checked yet, the payload grows as it's read.
+        let mut payload = vec![];
+        reader.by_ref().take(len.into()).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let crc = u32::from_le_bytes(read_array(&mut reader)?);
+        if crc != crc32c_append(crc32c(&[kind]), &payload) {
+            return Err(invalid_data("snapshot record checksum mismatch"));
+        }
+
+        let mut payload = Payload(&payload);
+        match kind {
+            KIND_END => {
+                let count = u64::from_le_bytes(payload.array()?);
+                if count != records.len() as u64 {
+                    return Err(invalid_data("snapshot record count mismatch"));
+                }
+                return Ok(Snapshot { log_seq, records });
+            }
+            KIND_BYTES => {
+                let key = payload.bytes()?.to_vec();
+                let count = payload.count(4)?;
+                let blocks = (0..count)
+                    .map(|_| payload.bytes().map(<[u8]>::to_vec))
+                    .collect::<io::Result<_>>()?;
+                records.push(Record::Bytes { key, blocks });
+            }
+            KIND_POINTS => {
+                let key = payload.bytes()?.to_vec();
+                let count = payload.count(16)?;
+                let points = (0..count)
+                    .map(|_| {
+                        let timestamp = i64::from_le_bytes(payload.array()?);
+                        let value = f64::from_le_bytes(payload.array()?);
+                        Ok(Point::new(timestamp, value))
+                    })
+                    .collect::<io::Result<_>>()?;
+                records.push(Record::Points { key, points });
+            }
+            kind => return Err(invalid_data(format!("unknown snapshot record {kind}"))),
+        }
+    }
+}
+
+/// Cursor over a checked record payload.
+struct Payload<'a>(&'a [u8]);
+
+impl<'a> Payload<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("snapshot record too short"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Count of entries taking at least `size` bytes each, checked against
+    /// the rest of the payload before anything is allocated for them.
+    fn count(&mut self, size: usize) -> io::Result<usize> {
+        let count = u32::from_le_bytes(self.array()?) as usize;
+        if count > self.0.len() / size {
+            return Err(invalid_data("snapshot record too short"));
+        }
+        Ok(count)
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        self.take(len)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: BytesRef) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut array = [0; N];
+    reader.read_exact(&mut array)?;
+    Ok(array)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn invalid_input(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot at log seq 7 of two ids, `count` its end record's count.
+    fn snapshot(count: u64) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(vec![], 7).unwrap();
+        writer
+            .write_bytes(b"a", [vec![1; 3], vec![2; 5]].iter())
+            .unwrap();
+        let points = (0..3).map(|i| Point::new(i, i as f64 / 2.0));
+        writer.write_points(b"b", points).unwrap();
+        writer.records = count;
+        writer.finish().unwrap()
+    }
+
+    fn error_kind(bytes: &[u8]) -> io::ErrorKind {
+        match read_snapshot(bytes) {
+            Ok(_) => panic!("read a broken snapshot"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = read_snapshot(&snapshot(2)[..]).unwrap();
+        assert_eq!(snapshot.log_seq, 7);
+        assert_eq!(snapshot.records.len(), 2);
+        match &snapshot.records[0] {
+            Record::Bytes { key, blocks } => {
+                assert_eq!(key, b"a");
+                assert_eq!(blocks, &[vec![1; 3], vec![2; 5]]);
+            }
+            Record::Points { .. } => panic!("points read as bytes"),
+        }
+        match &snapshot.records[1] {
+            Record::Points { key, points } => {
+                assert_eq!(key, b"b");
+                assert_eq!(points, &[0, 1, 2].map(|i| Point::new(i, i as f64 / 2.0)));
+            }
+            Record::Bytes { .. } => panic!("bytes read as points"),
+        }
+    }
+
+    #[test]
+    fn version_1_has_no_log_seq() {
+        let bytes = snapshot(2);
+        let mut v1 = MAGIC.to_vec();
+        v1.extend_from_slice(&1u32.to_le_bytes());
+        v1.extend_from_slice(&bytes[20..]);
+        let snapshot = read_snapshot(&v1[..]).unwrap();
+        assert_eq!((snapshot.log_seq, snapshot.records.len()), (0, 2));
+
+        let mut v3 = bytes.clone();
+        v3[8..12].copy_from_slice(&3u32.to_le_bytes());
+        assert_eq!(error_kind(&v3), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(b"CCSNAPSX"), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bad_checksum_fails() {
+        let mut bytes = snapshot(2);
+        // Header, kind and length, key, block count and length, then the
+        // last byte of the first block.
+        bytes[20 + 5 + 5 + 4 + 4 + 2] ^= 1;
+        assert_eq!(error_kind(&bytes), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn missing_end_record_fails() {
+        let bytes = snapshot(2);
+        let end = bytes.len() - (1 + 4 + 8 + 4);
+        for len in [end, end + 5, bytes.len() - 1] {
+            assert_eq!(error_kind(&bytes[..len]), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn record_count_mismatch_fails() {
+        assert_eq!(error_kind(&snapshot(1)), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind(&snapshot(3)), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lengths_are_checked_before_allocating() {
+        let mut bytes = snapshot(2)[..20].to_vec();
+        bytes.push(KIND_BYTES);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        assert_eq!(error_kind(&bytes), io::ErrorKind::UnexpectedEof);
+
+        // A checked record claiming more blocks than it holds.
+        let mut payload = vec![];
+        put_bytes(&mut payload, b"a");
+        payload.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut bytes = snapshot(2)[..20].to_vec();
+        bytes.push(KIND_BYTES);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        let crc = crc32c_append(crc32c(&[KIND_BYTES]), &payload);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(error_kind(&bytes), io::ErrorKind::InvalidData);
+    }
+}
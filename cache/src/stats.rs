@@ -0,0 +1,97 @@
+use std::iter::Sum;
+use std::ops::Add;
+
+use crate::cell::Id;
+use crate::codec::CodecStats;
+use crate::item::Fragmentation;
+
+/// What a cache holds, taken by [`CacheCell::stats`](crate::CacheCell::stats).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStats<K = Id> {
+    /// Ids with points.
+    pub series: usize,
+    /// Bytes allocated for blocks, points included.
+    pub memory: usize,
+    /// Lookups that found the id.
+    pub hits: u64,
+    /// Lookups that didn't find the id.
+    pub misses: u64,
+    /// Items or blocks dropped to stay in capacity.
+    pub evictions: u64,
+    /// Items, blocks, and bytes allocated and used in them.
+    pub fragmentation: Fragmentation,
+    /// Blocks compressed and read back, all zero without a codec.
+    pub codec: CodecStats,
+    /// Summary of each id with bytes or points, in id order. Their memory
+    /// adds up to `memory`.
+    pub per_item: Vec<ItemStats<K>>,
+}
+
+impl<K> CacheStats<K> {
+    /// Share of block bytes not written, see [`Fragmentation::ratio`].
+    pub fn fragmentation_ratio(&self) -> f64 {
+        self.fragmentation.ratio()
+    }
+}
+
+/// Summary of the bytes and points of one id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemStats<K = Id> {
+    pub id: K,
+    /// Bytes appended and not dropped.
+    pub len: usize,
+    /// Blocks of the bytes.
+    pub blocks: usize,
+    /// Points appended and not dropped.
+    pub points: usize,
+    /// Bytes allocated for blocks of bytes and points.
+    pub memory: usize,
+}
+
+impl<K> Default for CacheStats<K> {
+    fn default() -> Self {
+        Self {
+            series: 0,
+            memory: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            fragmentation: Fragmentation::default(),
+            codec: CodecStats::default(),
+            per_item: vec![],
+        }
+    }
+}
+
+impl<K: Ord> Add for CacheStats<K> {
+    type Output = Self;
+
+    fn add(mut self, other: Self) -> Self {
+        self.merge(other);
+        self.per_item.sort_by(|a, b| a.id.cmp(&b.id));
+        self
+    }
+}
+
+impl<K: Ord> Sum for CacheStats<K> {
+    /// Sums of all, with items of all in id order.
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        let mut sum = Self::default();
+        iter.for_each(|stats| sum.merge(stats));
+        sum.per_item.sort_by(|a, b| a.id.cmp(&b.id));
+        sum
+    }
+}
+
+impl<K> CacheStats<K> {
+    fn merge(&mut self, other: Self) {
+        self.series += other.series;
+        self.memory += other.memory;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.fragmentation = self.fragmentation + other.fragmentation;
+        self.codec = self.codec + other.codec;
+        self.per_item.extend(other.per_item);
+    }
+}
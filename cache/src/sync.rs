@@ -0,0 +1,202 @@
+//! Synchronization of a cache, either shared between threads or owned by
+//! one.
+
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::Error;
+
+/// The locks and counters a cache is built with.
+pub trait SyncMode: 'static {
+    /// Guards the item maps and every item.
+    type RwLock<T>: Lock<T>;
+    /// Guards the eviction policy.
+    type Mutex<T>: Lock<T>;
+    type Counter: Counter;
+}
+
+/// Locks from `std::sync` and atomic counters, for caches shared between
+/// threads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Threaded;
+
+impl SyncMode for Threaded {
+    type RwLock<T> = RwLock<T>;
+    type Mutex<T> = Mutex<T>;
+    type Counter = AtomicUsize;
+}
+
+/// `RefCell`s and `Cell`s, for caches owned by one thread. Such a cache is
+/// not `Sync`, and a borrow conflict panics: it happens only when a callback
+/// given to the cache calls back into it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Local;
+
+impl SyncMode for Local {
+    type RwLock<T> = RefCell<T>;
+    type Mutex<T> = RefCell<T>;
+    type Counter = Cell<usize>;
+}
+
+pub trait Lock<T> {
+    type Read<'a>: Deref<Target = T>
+    where
+        Self: 'a;
+    type Write<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    fn new(value: T) -> Self;
+
+    fn read(&self) -> Result<Self::Read<'_>, Error>;
+
+    fn write(&self) -> Result<Self::Write<'_>, Error>;
+
+    fn get_mut(&mut self) -> Result<&mut T, Error>;
+
+    fn into_inner(self) -> Result<T, Error>;
+}
+
+impl<T> Lock<T> for RwLock<T> {
+    type Read<'a>
+        = RwLockReadGuard<'a, T>
+    where
+        T: 'a;
+    type Write<'a>
+        = RwLockWriteGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(value: T) -> Self {
+        RwLock::new(value)
+    }
+
+    #[inline]
+    fn read(&self) -> Result<Self::Read<'_>, Error> {
+        Ok(RwLock::read(self)?)
+    }
+
+    #[inline]
+    fn write(&self) -> Result<Self::Write<'_>, Error> {
+        Ok(RwLock::write(self)?)
+    }
+
+    fn get_mut(&mut self) -> Result<&mut T, Error> {
+        Ok(RwLock::get_mut(self)?)
+    }
+
+    fn into_inner(self) -> Result<T, Error> {
+        Ok(RwLock::into_inner(self)?)
+    }
+}
+
+impl<T> Lock<T> for Mutex<T> {
+    type Read<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+    type Write<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(value: T) -> Self {
+        Mutex::new(value)
+    }
+
+    #[inline]
+    fn read(&self) -> Result<Self::Read<'_>, Error> {
+        Ok(self.lock()?)
+    }
+
+    #[inline]
+    fn write(&self) -> Result<Self::Write<'_>, Error> {
+        Ok(self.lock()?)
+    }
+
+    fn get_mut(&mut self) -> Result<&mut T, Error> {
+        Ok(Mutex::get_mut(self)?)
+    }
+
+    fn into_inner(self) -> Result<T, Error> {
+        Ok(Mutex::into_inner(self)?)
+    }
+}
+
+impl<T> Lock<T> for RefCell<T> {
+    type Read<'a>
+        = Ref<'a, T>
+    where
+        T: 'a;
+    type Write<'a>
+        = RefMut<'a, T>
+    where
+        T: 'a;
+
+    fn new(value: T) -> Self {
+        RefCell::new(value)
+    }
+
+    #[inline]
+    fn read(&self) -> Result<Self::Read<'_>, Error> {
+        Ok(self.borrow())
+    }
+
+    #[inline]
+    fn write(&self) -> Result<Self::Write<'_>, Error> {
+        Ok(self.borrow_mut())
+    }
+
+    fn get_mut(&mut self) -> Result<&mut T, Error> {
+        Ok(RefCell::get_mut(self))
+    }
+
+    fn into_inner(self) -> Result<T, Error> {
+        Ok(RefCell::into_inner(self))
+    }
+}
+
+pub trait Counter: Default {
+    fn add(&self, n: usize);
+
+    fn sub(&self, n: usize);
+
+    fn get(&self) -> usize;
+}
+
+impl Counter for AtomicUsize {
+    #[inline]
+    fn add(&self, n: usize) {
+        self.fetch_add(n, Relaxed);
+    }
+
+    #[inline]
+    fn sub(&self, n: usize) {
+        self.fetch_sub(n, Relaxed);
+    }
+
+    #[inline]
+    fn get(&self) -> usize {
+        self.load(Relaxed)
+    }
+}
+
+impl Counter for Cell<usize> {
+    #[inline]
+    fn add(&self, n: usize) {
+        self.set(self.get() + n);
+    }
+
+    #[inline]
+    fn sub(&self, n: usize) {
+        self.set(self.get() - n);
+    }
+
+    #[inline]
+    fn get(&self) -> usize {
+        Cell::get(self)
+    }
+}
@@ -0,0 +1,89 @@
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::io;
+
+use crate::cell::Bytes;
+
+/// Key of a cache. `Hash` is used by loads to route a key to its shard, and
+/// by eviction policies together with `Clone`.
+pub trait Key: Ord + Hash + Clone {}
+
+impl<T: Ord + Hash + Clone> Key for T {}
+
+/// A value that is stored in an item as bytes.
+///
+/// Appending values to an item concatenates their encodings, so `decode` is
+/// given a run of one or more encoded values.
+pub trait Value: Sized {
+    fn encode(self) -> Bytes;
+
+    /// Fails with [`io::ErrorKind::InvalidData`] if the type can't hold what
+    /// `bytes` encode.
+    fn decode(bytes: Bytes) -> io::Result<Self>;
+}
+
+impl Value for Bytes {
+    #[inline]
+    fn encode(self) -> Bytes {
+        self
+    }
+
+    #[inline]
+    fn decode(bytes: Bytes) -> io::Result<Self> {
+        Ok(bytes)
+    }
+}
+
+impl Value for String {
+    #[inline]
+    fn encode(self) -> Bytes {
+        self.into_bytes()
+    }
+
+    /// Invalid UTF-8 sequences are replaced.
+    #[inline]
+    fn decode(bytes: Bytes) -> io::Result<Self> {
+        Ok(match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        })
+    }
+}
+
+/// Ids are written as 8 bytes little endian, so snapshots can be read on any
+/// platform.
+impl Value for usize {
+    #[inline]
+    fn encode(self) -> Bytes {
+        (self as u64).to_le_bytes().to_vec()
+    }
+
+    /// Only exactly one value decodes: a run of several, or a part of one,
+    /// fails rather than losing bytes. So does a value over `usize::MAX`.
+    #[inline]
+    fn decode(bytes: Bytes) -> io::Result<Self> {
+        let array = <[u8; 8]>::try_from(bytes.as_slice())
+            .map_err(|_| invalid_data(format!("{} bytes, not one usize", bytes.len())))?;
+        let value = u64::from_le_bytes(array);
+        usize::try_from(value).map_err(|_| invalid_data(format!("{value} over usize::MAX")))
+    }
+}
+
+fn invalid_data(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usize_decodes_exactly_one_value() {
+        assert_eq!(usize::decode(usize::MAX.encode()).unwrap(), usize::MAX);
+        let run = [1usize.encode(), 2usize.encode()].concat();
+        for bytes in [run, vec![1; 4], vec![]] {
+            let error = usize::decode(bytes).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,870 @@
+//! Write-ahead log of cache writes.
+//!
+//! A log is a directory of segments, each named after the sequence number of
+//! its first record. All integers are little endian.
+//!
+//! ```text
+//! record:   length u32 | crc32c(seq .. body) u32 | seq u64 | kind u8 | body
+//!
+//! append:        kind 1, id u64 | bytes
+//! remove:        kind 2, id u64
+//! truncate:      kind 3, id u64 | length u64
+//! replace:       kind 4, id u64 | bytes
+//! append point:  kind 5, id u64 | timestamp i64 | value f64
+//! ```
+//!
+//! Records are buffered and written in groups. A crash loses at most the
+//! group not yet committed, and leaves at most one torn record at the end of
+//! the last segment, which replay drops.
+//!
+//! Segments are synced by a thread of each log, so the thread writing never
+//! waits for the disk. Syncs asked for while one runs are done together. A
+//! write is acknowledged once [`Wal::durable`] of its record resolves.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cell::{Bytes, CacheCell, Id};
+use crate::crc::crc32c;
+use crate::error::Error;
+use crate::pool::BlockPool;
+use crate::sync::SyncMode;
+
+/// Bytes of length and crc before each record.
+const FRAME_SIZE: usize = 8;
+/// Bytes of sequence number and kind.
+const RECORD_HEADER_SIZE: usize = 9;
+
+const KIND_APPEND: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_TRUNCATE: u8 = 3;
+const KIND_REPLACE: u8 = 4;
+const KIND_APPEND_POINT: u8 = 5;
+
+/// When committed records are synced to disk, and acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync every commit, records are acknowledged once synced.
+    Always,
+    /// Sync a commit if the last sync is older than the interval. Records
+    /// are acknowledged once written.
+    Interval(Duration),
+    /// Leave it to the OS. Records are acknowledged once written.
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    fsync: FsyncPolicy,
+    segment_size: u64,
+    group_size: usize,
+    group_delay: Duration,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::Always,
+            segment_size: 64 * 1024 * 1024,
+            group_size: 0,
+            group_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+impl WalOptions {
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Start a new segment once the current one reaches `size` bytes.
+    pub fn with_segment_size(mut self, size: u64) -> Self {
+        self.segment_size = size;
+        self
+    }
+
+    /// Commit records together once `size` bytes of them are buffered, or
+    /// the oldest is buffered for `delay`. A size of 0 commits every record,
+    /// which still shares a sync with the records committed while the
+    /// previous one ran.
+    pub fn with_group_commit(mut self, size: usize, delay: Duration) -> Self {
+        self.group_size = size;
+        self.group_delay = delay;
+        self
+    }
+
+    /// How long a record may wait for its group.
+    pub fn group_delay(&self) -> Duration {
+        self.group_delay
+    }
+}
+
+/// One logged write. Logging borrows the bytes, replay owns them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord<B = Bytes> {
+    Append(Id, B),
+    Remove(Id),
+    Truncate(Id, usize),
+    Replace(Id, B),
+    AppendPoint(Id, i64, f64),
+}
+
+impl WalRecord {
+    /// Redo this write on `cache`. Removing or truncating an id that is
+    /// already gone is not an error.
+    pub fn apply<P: BlockPool, S: SyncMode>(
+        self,
+        cache: &CacheCell<Id, Bytes, P, S>,
+    ) -> Result<(), Error> {
+        let result = match self {
+            WalRecord::Append(id, bytes) => cache.append(id, bytes),
+            WalRecord::Remove(id) => cache.remove(&id),
+            WalRecord::Truncate(id, len) => cache.truncate(&id, len),
+            WalRecord::Replace(id, bytes) => cache.replace(id, bytes),
+            WalRecord::AppendPoint(id, timestamp, value) => {
+                cache.append_point(id, timestamp, value)
+            }
+        };
+        match result {
+            Err(Error::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl<B> WalRecord<B> {
+    /// Id written.
+    pub fn id(&self) -> Id {
+        match self {
+            WalRecord::Append(id, _)
+            | WalRecord::Remove(id)
+            | WalRecord::Truncate(id, _)
+            | WalRecord::Replace(id, _)
+            | WalRecord::AppendPoint(id, ..) => *id,
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> WalRecord<B> {
+    fn encode(&self, seq: u64, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; FRAME_SIZE]);
+        buf.extend_from_slice(&seq.to_le_bytes());
+        match self {
+            WalRecord::Append(id, bytes) | WalRecord::Replace(id, bytes) => {
+                let kind = match self {
+                    WalRecord::Append(..) => KIND_APPEND,
+                    _ => KIND_REPLACE,
+                };
+                buf.push(kind);
+                buf.extend_from_slice(&(*id as u64).to_le_bytes());
+                buf.extend_from_slice(bytes.as_ref());
+            }
+            WalRecord::Remove(id) => {
+                buf.push(KIND_REMOVE);
+                buf.extend_from_slice(&(*id as u64).to_le_bytes());
+            }
+            WalRecord::Truncate(id, len) => {
+                buf.push(KIND_TRUNCATE);
+                buf.extend_from_slice(&(*id as u64).to_le_bytes());
+                buf.extend_from_slice(&(*len as u64).to_le_bytes());
+            }
+            WalRecord::AppendPoint(id, timestamp, value) => {
+                buf.push(KIND_APPEND_POINT);
+                buf.extend_from_slice(&(*id as u64).to_le_bytes());
+                buf.extend_from_slice(&timestamp.to_le_bytes());
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let body = &buf[start + FRAME_SIZE..];
+        let len = body.len() as u32;
+        let crc = crc32c(body);
+        buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        buf[start + 4..start + 8].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// Decode the record at the start of `bytes`, with its sequence number and
+/// size. `None` if it's incomplete or fails its checksum.
+fn decode(bytes: &[u8]) -> Option<(u64, WalRecord, usize)> {
+    if bytes.len() < FRAME_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let body = bytes.get(FRAME_SIZE..FRAME_SIZE + len)?;
+    if len < RECORD_HEADER_SIZE || crc32c(body) != crc {
+        return None;
+    }
+
+    let seq = u64::from_le_bytes(body[0..8].try_into().unwrap());
+    let kind = body[8];
+    let body = &body[RECORD_HEADER_SIZE..];
+    let u64_at = |at: usize| -> Option<u64> {
+        Some(u64::from_le_bytes(
+            body.get(at..at + 8)?.try_into().unwrap(),
+        ))
+    };
+    let id = u64_at(0)? as Id;
+    let record = match kind {
+        KIND_APPEND => WalRecord::Append(id, body[8..].to_vec()),
+        KIND_REMOVE => WalRecord::Remove(id),
+        KIND_TRUNCATE => WalRecord::Truncate(id, u64_at(8)? as usize),
+        KIND_REPLACE => WalRecord::Replace(id, body[8..].to_vec()),
+        KIND_APPEND_POINT => {
+            WalRecord::AppendPoint(id, u64_at(8)? as i64, f64::from_bits(u64_at(16)?))
+        }
+        _ => return None,
+    };
+    Some((seq, record, FRAME_SIZE + len))
+}
+
+/// Whether the damaged record at the start of `bytes`, expected to be `seq`,
+/// is followed by the record after it. A crash only tears the end of a
+/// segment, so that's corruption instead.
+fn is_followed(bytes: &[u8], seq: u64) -> bool {
+    let len = match bytes.get(..4) {
+        Some(len) => u32::from_le_bytes(len.try_into().unwrap()) as usize,
+        None => return false,
+    };
+    match bytes.get(FRAME_SIZE + len..).map(decode) {
+        Some(Some((next, ..))) => next == seq + 1,
+        _ => false,
+    }
+}
+
+/// Write-ahead log of one shard.
+pub struct Wal {
+    dir: PathBuf,
+    options: WalOptions,
+    segment: Arc<File>,
+    segment_len: u64,
+    next_seq: u64,
+    buf: Vec<u8>,
+    /// When the oldest buffered record was logged.
+    buffered_since: Option<Instant>,
+    /// When the last sync was asked for.
+    last_sync: Instant,
+    acks: Arc<Acks>,
+    syncer: Option<JoinHandle<()>>,
+}
+
+impl Wal {
+    /// Open the log in `dir`, creating it if needed. Returns the records to
+    /// replay with their sequence numbers, in order. A torn record at the end
+    /// of the last segment is dropped, damage anywhere else, or followed by
+    /// an intact record, is an error.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        options: WalOptions,
+    ) -> io::Result<(Self, Vec<(u64, WalRecord)>)> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let segments = segments(&dir)?;
+        let mut records = vec![];
+        let mut next_seq = 0;
+        for (i, (first_seq, path)) in segments.iter().enumerate() {
+            if i == 0 {
+                next_seq = *first_seq;
+            } else if *first_seq != next_seq {
+                return Err(invalid_data(format!(
+                    "write-ahead log segment {} starts at {first_seq}, expected {next_seq}",
+                    path.display()
+                )));
+            }
+
+            let bytes = fs::read(path)?;
+            let mut offset = 0;
+            while offset < bytes.len() {
+                match decode(&bytes[offset..]) {
+                    Some((seq, record, size)) if seq == next_seq => {
+                        records.push((seq, record));
+                        next_seq += 1;
+                        offset += size;
+                    }
+                    _ => break,
+                }
+            }
+            if offset < bytes.len() {
+                if i + 1 != segments.len() || is_followed(&bytes[offset..], next_seq) {
+                    return Err(invalid_data(format!(
+                        "write-ahead log segment {} is corrupted at {offset}",
+                        path.display()
+                    )));
+                }
+                // Torn by a crash while it was written.
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(offset as u64)?;
+            }
+        }
+
+        let (segment, segment_len) = match segments.last() {
+            Some((_, path)) => {
+                let segment = OpenOptions::new().append(true).open(path)?;
+                let len = segment.metadata()?.len();
+                (segment, len)
+            }
+            None => {
+                let segment = create_segment(&dir, next_seq)?;
+                File::open(&dir)?.sync_all()?;
+                (segment, 0)
+            }
+        };
+
+        let acks = Arc::new(Acks::new(next_seq));
+        let syncer = {
+            let acks = acks.clone();
+            thread::Builder::new()
+                .name("wal-sync".into())
+                .spawn(move || acks.sync_pending())?
+        };
+        let wal = Self {
+            dir,
+            options,
+            segment: Arc::new(segment),
+            segment_len,
+            next_seq,
+            buf: vec![],
+            buffered_since: None,
+            last_sync: Instant::now(),
+            acks,
+            syncer: Some(syncer),
+        };
+        Ok((wal, records))
+    }
+
+    /// Log `record`, committing its group if it's full or old enough.
+    /// Returns its sequence number, wait for [`Wal::durable`] of it before
+    /// acknowledging the write.
+    pub fn log<B: AsRef<[u8]>>(&mut self, record: &WalRecord<B>) -> io::Result<u64> {
+        let seq = self.next_seq;
+        record.encode(seq, &mut self.buf);
+        self.next_seq += 1;
+
+        let since = *self.buffered_since.get_or_insert_with(Instant::now);
+        if self.buf.len() >= self.options.group_size || since.elapsed() >= self.options.group_delay
+        {
+            self.commit()?;
+        }
+        Ok(seq)
+    }
+
+    /// Write buffered records, have them synced as the policy says, and
+    /// rotate the segment if it's full.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            (&*self.segment).write_all(&self.buf)?;
+            self.segment_len += self.buf.len() as u64;
+            self.buf.clear();
+            self.buffered_since = None;
+
+            let sync = match self.options.fsync {
+                FsyncPolicy::Always => true,
+                FsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+                FsyncPolicy::Never => false,
+            };
+            if sync {
+                self.sync(SyncTarget::Segment(self.segment.clone()));
+            }
+            if self.options.fsync != FsyncPolicy::Always {
+                self.acks.ack(self.next_seq);
+            }
+        }
+
+        if self.segment_len >= self.options.segment_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Commit, then run `snapshot` which saves everything logged so far,
+    /// with the sequence number of the next record to record in it, see
+    /// [`CacheCell::snapshot_at`]. Segments before it are deleted once it
+    /// succeeds. Replay after a crash in between skips records the snapshot
+    /// holds by their sequence number.
+    pub fn checkpoint<F, E>(&mut self, snapshot: F) -> Result<(), E>
+    where
+        F: FnOnce(u64) -> Result<(), E>,
+        E: From<io::Error>,
+    {
+        self.commit()?;
+        self.rotate()?;
+        snapshot(self.next_seq)?;
+
+        for (first_seq, path) in segments(&self.dir)? {
+            if first_seq < self.next_seq {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sequence number of the next record.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Resolves once the record `seq` is acknowledged as the policy says:
+    /// synced with [`FsyncPolicy::Always`], written otherwise. Buffered
+    /// records wait for their group to be committed.
+    pub fn durable(&self, seq: u64) -> Durable {
+        Durable {
+            acks: self.acks.clone(),
+            seq,
+        }
+    }
+
+    /// Have `target` synced by the syncer, acknowledging everything written
+    /// so far with it.
+    fn sync(&mut self, target: SyncTarget) {
+        self.acks.request(target, self.next_seq);
+        self.last_sync = Instant::now();
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.segment_len == 0 {
+            return Ok(());
+        }
+        if self.options.fsync != FsyncPolicy::Never {
+            self.sync(SyncTarget::Segment(self.segment.clone()));
+        }
+        self.segment = Arc::new(create_segment(&self.dir, self.next_seq)?);
+        self.segment_len = 0;
+        // Make the new name durable too.
+        self.sync(SyncTarget::Dir(File::open(&self.dir)?));
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Wal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wal")
+            .field("dir", &self.dir)
+            .field("next_seq", &self.next_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        let _ = self.commit();
+        self.acks.close();
+        if let Some(syncer) = self.syncer.take() {
+            let _ = syncer.join();
+        }
+    }
+}
+
+/// A file the syncer syncs.
+enum SyncTarget {
+    Segment(Arc<File>),
+    Dir(File),
+}
+
+impl SyncTarget {
+    fn sync(&self) -> io::Result<()> {
+        match self {
+            SyncTarget::Segment(segment) => segment.sync_data(),
+            SyncTarget::Dir(dir) => dir.sync_all(),
+        }
+    }
+}
+
+struct AckState {
+    /// Records before it are acknowledged.
+    acked: u64,
+    /// Files to sync in order, each with the records it acknowledges.
+    pending: Vec<(SyncTarget, u64)>,
+    wakers: Vec<(u64, Waker)>,
+    /// The first sync that failed fails every record not acknowledged yet.
+    error: Option<(io::ErrorKind, String)>,
+    closed: bool,
+}
+
+impl AckState {
+    /// Whether record `seq` is done waiting, and how.
+    fn result(&self, seq: u64) -> Option<io::Result<()>> {
+        if seq < self.acked {
+            Some(Ok(()))
+        } else if let Some((kind, error)) = &self.error {
+            Some(Err(io::Error::new(*kind, error.clone())))
+        } else if self.closed {
+            Some(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "write-ahead log closed before the record was acknowledged",
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+/// What is acknowledged, shared by a log, its syncer and the writes waiting.
+struct Acks {
+    state: Mutex<AckState>,
+    changed: Condvar,
+}
+
+impl Acks {
+    fn new(acked: u64) -> Self {
+        Self {
+            state: Mutex::new(AckState {
+                acked,
+                pending: vec![],
+                wakers: vec![],
+                error: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AckState> {
+        // The state is consistent after every update, a panic elsewhere
+        // doesn't matter to it.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Acknowledge the records before `seq`.
+    fn ack(&self, seq: u64) {
+        let mut state = self.lock();
+        state.acked = state.acked.max(seq);
+        self.notify(&mut state);
+    }
+
+    /// Sync `target`, then acknowledge the records before `seq`. Syncs of a
+    /// segment asked for in a row are done once.
+    fn request(&self, target: SyncTarget, seq: u64) {
+        let mut state = self.lock();
+        match (state.pending.last_mut(), &target) {
+            (Some((SyncTarget::Segment(last), upto)), SyncTarget::Segment(segment))
+                if Arc::ptr_eq(last, segment) =>
+            {
+                *upto = seq;
+            }
+            _ => state.pending.push((target, seq)),
+        }
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Run by the syncer until the log is closed and nothing is pending.
+    fn sync_pending(&self) {
+        let mut state = self.lock();
+        loop {
+            if state.pending.is_empty() {
+                if state.closed {
+                    break;
+                }
+                state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+
+            let pending = mem::take(&mut state.pending);
+            drop(state);
+            let result = pending.iter().try_for_each(|(target, _)| target.sync());
+            state = self.lock();
+            match result {
+                Ok(()) => {
+                    let (_, seq) = pending.last().unwrap();
+                    state.acked = state.acked.max(*seq);
+                }
+                Err(e) => {
+                    state.error.get_or_insert((e.kind(), e.to_string()));
+                }
+            }
+            self.notify(&mut state);
+        }
+        self.notify(&mut state);
+    }
+
+    /// Wake the writes that are done waiting.
+    fn notify(&self, state: &mut AckState) {
+        let wakers = mem::take(&mut state.wakers);
+        for (seq, waker) in wakers {
+            if state.result(seq).is_some() {
+                waker.wake();
+            } else {
+                state.wakers.push((seq, waker));
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Acknowledgement of one record, see [`Wal::durable`]. Await it, or
+/// [`Durable::wait`] for it off an async runtime.
+#[must_use = "a write isn't acknowledged until its record is durable"]
+pub struct Durable {
+    acks: Arc<Acks>,
+    seq: u64,
+}
+
+impl Durable {
+    /// Block until the record is acknowledged.
+    pub fn wait(self) -> io::Result<()> {
+        let mut state = self.acks.lock();
+        loop {
+            if let Some(result) = state.result(self.seq) {
+                return result;
+            }
+            state = self
+                .acks
+                .changed
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Future for Durable {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.acks.lock();
+        match state.result(self.seq) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                let waker = cx.waker();
+                match state.wakers.iter_mut().find(|(seq, _)| *seq == self.seq) {
+                    Some((_, known)) if known.will_wake(waker) => {}
+                    _ => state.wakers.push((self.seq, waker.clone())),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Segments in `dir` with their first sequence number, in order.
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "wal") {
+            if let Some(seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                segments.push((seq, path));
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Create the segment starting at `first_seq`. Its name is durable once
+/// `dir` is synced.
+fn create_segment(dir: &Path, first_seq: u64) -> io::Result<File> {
+    let path = dir.join(format!("{first_seq:020}.wal"));
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+}
+
+fn invalid_data(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cache;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-wal-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn acknowledged_once_committed_and_synced() {
+        let dir = temp_dir("durable");
+        let options = WalOptions::default().with_group_commit(1 << 20, Duration::from_secs(3600));
+        let (mut wal, _) = Wal::open(&dir, options).unwrap();
+        let seq = wal.log(&WalRecord::<Bytes>::Remove(1)).unwrap();
+
+        let waiter = thread::spawn({
+            let durable = wal.durable(seq);
+            move || durable.wait()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished(), "acknowledged while buffered");
+        wal.commit().unwrap();
+        waiter.join().unwrap().unwrap();
+
+        // Acknowledged before the wait with nothing buffered.
+        wal.durable(seq).wait().unwrap();
+        let seq = wal.log(&WalRecord::<Bytes>::Remove(2)).unwrap();
+        drop(wal);
+        let (_wal, records) = Wal::open(&dir, WalOptions::default()).unwrap();
+        assert_eq!(records.last(), Some(&(seq, WalRecord::Remove(2))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_between_snapshot_and_truncation() {
+        let dir = temp_dir("checkpoint");
+        let snapshot = dir.join("snapshot");
+        {
+            let (mut wal, records) = Wal::open(&dir, WalOptions::default()).unwrap();
+            assert!(records.is_empty());
+            let cache = Cache::default();
+            for i in 0..10u8 {
+                let record = WalRecord::Append(0, vec![i; 3]);
+                wal.log(&record).unwrap();
+                record.apply(&cache).unwrap();
+            }
+            // The snapshot is in place, the process dies before the log is
+            // truncated.
+            let result: Result<(), Error> = wal.checkpoint(|log_seq| {
+                cache.snapshot_at(File::create(&snapshot)?, log_seq)?;
+                Err(io::Error::other("crash").into())
+            });
+            assert!(result.is_err());
+            let record = WalRecord::Append(0, vec![10; 3]);
+            wal.log(&record).unwrap();
+        }
+
+        let (_wal, records) = Wal::open(&dir, WalOptions::default()).unwrap();
+        assert_eq!(records.len(), 11);
+        let cache = Cache::default();
+        let log_seq = cache.restore(File::open(&snapshot).unwrap()).unwrap();
+        assert_eq!(log_seq, 10);
+        for (seq, record) in records {
+            if seq >= log_seq {
+                record.apply(&cache).unwrap();
+            }
+        }
+        let expected: Vec<u8> = (0..11u8).flat_map(|i| vec![i; 3]).collect();
+        assert_eq!(cache.read_all(&0).unwrap(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Log `n` appends of id 0, each committed on its own.
+    fn write_records(dir: &Path, options: WalOptions, n: u8) {
+        let (mut wal, _) = Wal::open(dir, options).unwrap();
+        for i in 0..n {
+            let seq = wal.log(&WalRecord::Append(0, vec![i; 8])).unwrap();
+            wal.durable(seq).wait().unwrap();
+        }
+    }
+
+    fn last_segment(dir: &Path) -> PathBuf {
+        segments(dir).unwrap().pop().unwrap().1
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let record_size = (FRAME_SIZE + RECORD_HEADER_SIZE + 16) as u64;
+        let tears: [fn(&mut Vec<u8>); 2] = [
+            |bytes| bytes.truncate(bytes.len() - 3),
+            |bytes| *bytes.last_mut().unwrap() ^= 0xff,
+        ];
+        for (i, tear) in tears.iter().enumerate() {
+            let dir = temp_dir(&format!("torn-{i}"));
+            write_records(&dir, WalOptions::default(), 5);
+            let segment = last_segment(&dir);
+            let mut bytes = fs::read(&segment).unwrap();
+            tear(&mut bytes);
+            fs::write(&segment, bytes).unwrap();
+
+            let (mut wal, records) = Wal::open(&dir, WalOptions::default()).unwrap();
+            let expected: Vec<_> = (0..4u8)
+                .map(|i| (i as u64, WalRecord::Append(0, vec![i; 8])))
+                .collect();
+            assert_eq!(records, expected);
+            assert_eq!(fs::metadata(&segment).unwrap().len(), 4 * record_size);
+
+            // Logging goes on where the intact records end.
+            assert_eq!(wal.log(&WalRecord::<Bytes>::Remove(0)).unwrap(), 4);
+            drop(wal);
+            let (_wal, records) = Wal::open(&dir, WalOptions::default()).unwrap();
+            assert_eq!(records.last(), Some(&(4, WalRecord::Remove(0))));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupted_middle_record_fails() {
+        let record_size = FRAME_SIZE + RECORD_HEADER_SIZE + 16;
+        let dir = temp_dir("corrupted");
+        write_records(&dir, WalOptions::default(), 5);
+        let segment = last_segment(&dir);
+        let mut bytes = fs::read(&segment).unwrap();
+        bytes[2 * record_size + FRAME_SIZE + RECORD_HEADER_SIZE] ^= 0xff;
+        fs::write(&segment, bytes).unwrap();
+
+        let error = Wal::open(&dir, WalOptions::default()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Nothing is truncated away.
+        assert_eq!(
+            fs::metadata(&segment).unwrap().len(),
+            5 * record_size as u64
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_across_segments_with_every_policy() {
+        let policies = [
+            FsyncPolicy::Always,
+            FsyncPolicy::Interval(Duration::from_millis(1)),
+            FsyncPolicy::Never,
+        ];
+        for (i, &fsync) in policies.iter().enumerate() {
+            let dir = temp_dir(&format!("segments-{i}"));
+            let options = WalOptions::default()
+                .with_fsync(fsync)
+                .with_segment_size(100);
+            write_records(&dir, options.clone(), 20);
+            assert!(segments(&dir).unwrap().len() > 1, "{:?}", fsync);
+
+            let (_wal, records) = Wal::open(&dir, options).unwrap();
+            let expected: Vec<_> = (0..20u8)
+                .map(|i| (i as u64, WalRecord::Append(0, vec![i; 8])))
+                .collect();
+            assert_eq!(records, expected, "{:?}", fsync);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupted_earlier_segment_fails() {
+        let dir = temp_dir("corrupted-segment");
+        let options = WalOptions::default().with_segment_size(100);
+        write_records(&dir, options.clone(), 20);
+        let first = segments(&dir).unwrap().remove(0).1;
+        let mut bytes = fs::read(&first).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&first, bytes).unwrap();
+
+        let error = Wal::open(&dir, options).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
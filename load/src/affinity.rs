@@ -1,35 +1,240 @@
-use cache::{Bytes, Cache, Id};
+use cache::{
+    Bytes, CacheStats, Durable, Fragmentation, Id, LocalCache, LocalPool, Point, ScanPage,
+    SharedBytes, Wal, WalOptions, WalRecord,
+};
 use core_affinity::CoreId;
-use runtime::Runtime;
+use futures::future::join_all;
+use runtime::{Runtime, RuntimeMetrics, Service, Sharded};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::thread_local;
-use tokio::sync::oneshot;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Builder;
+
+use crate::batch::by_shard;
+use crate::{
+    acknowledged, bounds, is_data_dir, restore_cache, snapshot_cache, snapshot_path, wal_path,
+    within, Error, LoadStats, COMPACT_ITEMS, SWEEP_INTERVAL,
+};
+
+/// Caches of a shard are only touched by the shard's thread, so they take no
+/// locks, and share one block pool of the thread.
+type ShardCache = LocalCache<Id, Bytes, Rc<LocalPool>>;
 
 const CORE_NUM: usize = 15;
 const CACHE_PER_SHARD: usize = 10;
 
 struct AffinityShard {
-    caches: Rc<Vec<Cache>>,
+    caches: Rc<Vec<ShardCache>>,
+    wal: Option<RefCell<Wal>>,
+    data_dir: Option<PathBuf>,
 }
 
 impl AffinityShard {
     pub fn new() -> Self {
+        let pool = Rc::new(LocalPool::default());
         let mut caches = Vec::with_capacity(CACHE_PER_SHARD);
-        caches.resize_with(CACHE_PER_SHARD, Default::default);
+        caches.resize_with(CACHE_PER_SHARD, || {
+            LocalCache::default().with_block_pool(pool.clone())
+        });
         let caches = Rc::new(caches);
 
-        Self { caches }
+        Self {
+            caches,
+            wal: None,
+            data_dir: None,
+        }
+    }
+
+    /// Restore the snapshot of shard `shard` from `data_dir`, replay
+    /// `records` of its write-ahead log on top, and log writes to `wal`.
+    pub fn recover(
+        &mut self,
+        data_dir: PathBuf,
+        shard: usize,
+        wal: Wal,
+        records: Vec<(u64, WalRecord)>,
+    ) -> Result<(), Error> {
+        let log_seqs = self.restore(&data_dir, shard)?;
+        for (seq, record) in records {
+            // Records before a crash between a checkpoint's snapshot and
+            // deleting the log are in the snapshot already.
+            let i = Self::shard_id(record.id());
+            if seq >= log_seqs[i] {
+                record.apply(&self.caches[i])?;
+            }
+        }
+
+        self.wal = Some(RefCell::new(wal));
+        self.data_dir = Some(data_dir);
+        Ok(())
+    }
+
+    pub fn commit_wal(&self) -> Result<(), Error> {
+        if let Some(wal) = &self.wal {
+            wal.borrow_mut().commit()?;
+        }
+        Ok(())
+    }
+
+    /// Log `record` if the shard has a write-ahead log. Writes return what
+    /// to wait for, so the wait is off the shard's core.
+    fn log(&self, record: WalRecord<&[u8]>) -> Result<Option<Durable>, Error> {
+        match &self.wal {
+            Some(wal) => {
+                let mut wal = wal.borrow_mut();
+                let seq = wal.log(&record)?;
+                Ok(Some(wal.durable(seq)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn append(&self, id: Id, bytes: Bytes) -> Result<Option<Durable>, Error> {
+        let durable = self.log(WalRecord::Append(id, &bytes))?;
+        self.caches[Self::shard_id(id)].append(id, bytes)?;
+        Ok(durable)
+    }
+
+    pub fn get(&self, id: Id, size: usize) -> Result<Bytes, Error> {
+        Ok(self.caches[Self::shard_id(id)].get(&id, size)?)
+    }
+
+    pub fn multi_get(&self, ids: Vec<Id>, size: usize) -> Vec<Result<Bytes, Error>> {
+        ids.into_iter().map(|id| self.get(id, size)).collect()
+    }
+
+    pub fn multi_append(&self, pairs: Vec<(Id, Bytes)>) -> Vec<Result<Option<Durable>, Error>> {
+        pairs
+            .into_iter()
+            .map(|(id, bytes)| self.append(id, bytes))
+            .collect()
+    }
+
+    pub fn get_shared(&self, id: Id, size: usize) -> Result<SharedBytes, Error> {
+        Ok(self.caches[Self::shard_id(id)].get_shared(&id, size)?)
+    }
+
+    pub fn remove(&self, id: Id) -> Result<Option<Durable>, Error> {
+        let durable = self.log(WalRecord::Remove(id))?;
+        self.caches[Self::shard_id(id)].remove(&id)?;
+        Ok(durable)
+    }
+
+    pub fn truncate(&self, id: Id, len: usize) -> Result<Option<Durable>, Error> {
+        let durable = self.log(WalRecord::Truncate(id, len))?;
+        self.caches[Self::shard_id(id)].truncate(&id, len)?;
+        Ok(durable)
+    }
+
+    pub fn replace(&self, id: Id, bytes: Bytes) -> Result<Option<Durable>, Error> {
+        let durable = self.log(WalRecord::Replace(id, &bytes))?;
+        self.caches[Self::shard_id(id)].replace(id, bytes)?;
+        Ok(durable)
+    }
+
+    pub fn append_point(
+        &self,
+        id: Id,
+        timestamp: i64,
+        value: f64,
+    ) -> Result<Option<Durable>, Error> {
+        let durable = self.log(WalRecord::AppendPoint(id, timestamp, value))?;
+        self.caches[Self::shard_id(id)].append_point(id, timestamp, value)?;
+        Ok(durable)
+    }
+
+    pub fn query(&self, id: Id, start: i64, end: i64) -> Result<Vec<Point>, Error> {
+        Ok(self.caches[Self::shard_id(id)].query(&id, start, end)?)
+    }
+
+    /// Retention of `id`, or with `None` of every id without its own.
+    pub fn set_retention(&self, id: Option<Id>, ttl: Option<Duration>) -> Result<(), Error> {
+        match id {
+            Some(id) => self.caches[Self::shard_id(id)].set_retention(id, ttl)?,
+            None => {
+                for cache in self.caches.iter() {
+                    cache.set_default_retention(ttl)?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn append(&self, id: Id, bytes: Bytes) {
-        let bytes = calculation(bytes);
-        let id = Self::shard_id(id);
-        self.caches[id].append(id, bytes);
+    pub fn sweep(&self) -> Result<(), Error> {
+        for cache in self.caches.iter() {
+            cache.sweep()?;
+        }
+        Ok(())
     }
 
-    pub fn get(&self, id: Id, size: usize) -> Option<Bytes> {
-        let id = Self::shard_id(id);
-        self.caches[id].get(id, size).map(calculation)
+    pub fn scan(
+        &self,
+        range: (Bound<Id>, Bound<Id>),
+        limit: usize,
+    ) -> Result<ScanPage<Id, Bytes>, Error> {
+        let pages = self
+            .caches
+            .iter()
+            .map(|cache| cache.scan(range, limit))
+            .collect::<Result<_, _>>()?;
+        Ok(ScanPage::merge(pages, &range, limit))
+    }
+
+    pub fn compact(&self, max_items: usize) -> Result<usize, Error> {
+        let mut freed = 0;
+        for cache in self.caches.iter() {
+            freed += cache.compact(max_items)?;
+        }
+        Ok(freed)
+    }
+
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        let mut fragmentation = Fragmentation::default();
+        for cache in self.caches.iter() {
+            fragmentation = fragmentation + cache.fragmentation()?;
+        }
+        Ok(fragmentation)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, Error> {
+        let stats = self
+            .caches
+            .iter()
+            .map(|cache| cache.stats())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(stats.into_iter().sum())
+    }
+
+    /// Snapshot caches of shard `shard` into `dir`, checkpointing the
+    /// write-ahead log if it's the data directory.
+    pub fn snapshot(&self, dir: &Path, shard: usize) -> Result<(), Error> {
+        let snapshot = |log_seq| {
+            self.caches.iter().enumerate().try_for_each(|(i, cache)| {
+                snapshot_cache(cache, &snapshot_path(dir, shard, i), log_seq)
+            })
+        };
+        match &self.wal {
+            Some(wal) if is_data_dir(self.data_dir.as_deref(), dir) => {
+                wal.borrow_mut().checkpoint(snapshot)
+            }
+            _ => snapshot(0),
+        }
+    }
+
+    /// Restore caches of shard `shard` from `dir`. Returns the log seq of
+    /// each cache's snapshot.
+    pub fn restore(&self, dir: &Path, shard: usize) -> Result<Vec<u64>, Error> {
+        self.caches
+            .iter()
+            .enumerate()
+            .map(|(i, cache)| restore_cache(cache, &snapshot_path(dir, shard, i)))
+            .collect()
     }
 
     fn shard_id(id: Id) -> Id {
@@ -37,52 +242,332 @@ impl AffinityShard {
     }
 }
 
+impl Service for AffinityShard {}
+
 pub struct AffinityLoad {
-    runtime: Runtime,
+    shards: Arc<Sharded<AffinityShard>>,
+    timeout: Option<Duration>,
 }
 
 impl AffinityLoad {
+    /// Each shard sweeps expired blocks on its own core and compacts a few
+    /// items after each sweep, until the load is dropped.
     #[allow(clippy::new_without_default)]
     pub fn new(core_ids: &[CoreId]) -> Self {
         assert_eq!(core_ids.len(), CORE_NUM);
-        let runtime = Runtime::new(core_ids);
+        let runtime = Arc::new(Runtime::new(core_ids));
+        let shards = Arc::new(Sharded::new(runtime, |_| AffinityShard::new()));
 
-        Self { runtime }
-    }
+        let weak = Arc::downgrade(&shards);
+        thread::spawn(move || {
+            let rt = Builder::new_current_thread().build().unwrap();
+            loop {
+                thread::sleep(SWEEP_INTERVAL);
+                match weak.upgrade() {
+                    Some(shards) => rt.block_on(shards.try_invoke_on_all(|_, shard| {
+                        shard.sweep()?;
+                        shard.compact(COMPACT_ITEMS)
+                    })),
+                    None => break,
+                };
+            }
+        });
 
-    pub async fn append(&self, id: Id, bytes: Bytes) {
-        let (tx, rx) = oneshot::channel();
+        Self {
+            shards,
+            timeout: None,
+        }
+    }
 
-        // let id = shard_id(id);
-        self.runtime.spawn(shard_id(id), async move {
-            thread_local! (static SHARD:AffinityShard = AffinityShard::new() );
+    /// Drop blocks not written for `ttl`, unless the id has its own
+    /// retention, see [`AffinityLoad::set_retention`].
+    pub fn with_retention(self, ttl: Duration) -> Result<Self, Error> {
+        self.configure(move |_, shard| shard.set_retention(None, Some(ttl)))?;
+        Ok(self)
+    }
 
-            SHARD.with(|shard| {
-                shard.append(id, bytes);
-            });
+    /// Keep data in `dir` across restarts. Each shard restores its snapshot
+    /// from `dir` and replays its write-ahead log on top, then logs every
+    /// write. Writes are answered once the log acknowledges them, as its
+    /// [`FsyncPolicy`](cache::FsyncPolicy) says, waiting off the shard's
+    /// core. Snapshots into `dir` truncate the logs. Call it before writing,
+    /// writes before aren't logged.
+    pub fn with_wal(self, dir: impl AsRef<Path>, options: WalOptions) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let data_dir = fs::canonicalize(dir)?;
+        let wals = (0..CORE_NUM)
+            .map(|index| Wal::open(wal_path(dir, index), options.clone()).map(Some))
+            .collect::<io::Result<Vec<_>>>()?;
+        let wals = Mutex::new(wals);
+        self.configure(move |index, shard| {
+            let (wal, records) = wals.lock()?[index].take().ok_or(Error::ShardUnavailable)?;
+            shard.recover(data_dir.clone(), index, wal, records)
+        })?;
 
-            tx.send(()).unwrap();
+        let weak = Arc::downgrade(&self.shards);
+        let delay = options.group_delay();
+        thread::spawn(move || {
+            let rt = Builder::new_current_thread().build().unwrap();
+            loop {
+                thread::sleep(delay);
+                match weak.upgrade() {
+                    // A failed commit keeps its records buffered, the next
+                    // write that commits them reports the error.
+                    Some(shards) => {
+                        rt.block_on(shards.try_invoke_on_all(|_, shard| shard.commit_wal()))
+                    }
+                    None => break,
+                };
+            }
         });
 
-        rx.await.unwrap();
+        Ok(self)
+    }
+
+    /// Fail requests with [`Error::Timeout`] when their shard doesn't answer
+    /// within `timeout`. Needs a Tokio runtime with time enabled.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run `f` on every shard and wait for all, for the builders. Not on the
+    /// caller's thread, which may be in a runtime already.
+    fn configure<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(usize, &mut AffinityShard) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        let shards = self.shards.clone();
+        thread::spawn(move || {
+            let rt = Builder::new_current_thread().build().unwrap();
+            rt.block_on(shards.try_invoke_on_all(f))
+                .into_iter()
+                .try_for_each(|result| result.map_err(Error::from))
+        })
+        .join()
+        .unwrap_or(Err(Error::ShardUnavailable))
     }
 
-    #[allow(clippy::unit_arg)]
-    pub async fn get(&self, id: Id, size: usize) {
-        let (tx, rx) = oneshot::channel();
+    /// Set retention of `id`, overriding the load's. `None` keeps it
+    /// forever. The setting goes with the id, and isn't kept across
+    /// restarts.
+    pub async fn set_retention(&self, id: Id, ttl: Option<Duration>) -> Result<(), Error> {
+        self.invoke_on(shard_id(id), move |shard| {
+            shard.set_retention(Some(id), ttl)
+        })
+        .await
+    }
+
+    pub async fn append(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
+        let durable = self
+            .invoke_on(shard_id(id), move |shard| shard.append(id, bytes))
+            .await?;
+        acknowledged(durable).await
+    }
 
-        // let id = shard_id(id);
-        self.runtime.spawn(shard_id(id), async move {
-            thread_local! (static SHARD:AffinityShard = AffinityShard::new() );
+    pub fn metrics(&self) -> RuntimeMetrics {
+        self.shards.runtime().metrics()
+    }
+
+    /// Blocks read are checked against their checksum.
+    pub async fn get(&self, id: Id, size: usize) -> Result<(), Error> {
+        self.invoke_on(shard_id(id), move |shard| shard.get(id, size).map(|_| ()))
+            .await
+    }
 
-            let result = SHARD.with(|shard| {
-                shard.get(id, size);
+    /// Up to `size` bytes of each of `ids`, in the order of `ids`. Ids are
+    /// read on their cores in one call per shard. A failed id fails only its
+    /// own result, a shard that doesn't answer fails the whole call.
+    pub async fn multi_get(
+        &self,
+        ids: &[Id],
+        size: usize,
+    ) -> Result<Vec<Result<Bytes, Error>>, Error> {
+        let (batches, order) = by_shard(ids.iter().copied(), CORE_NUM, |&id| shard_id(id));
+        let answers = self
+            .invoke_on_batches(batches, move |shard, ids| Ok(shard.multi_get(ids, size)))
+            .await?;
+        Ok(order.restore(answers))
+    }
+
+    /// Append each pair, with the results in the order of `pairs`. Pairs
+    /// are appended on their cores in one call per shard, and pairs of one
+    /// id are appended in order. A failed append fails only its own result,
+    /// a shard that doesn't answer fails the whole call.
+    pub async fn multi_append(
+        &self,
+        pairs: Vec<(Id, Bytes)>,
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let (batches, order) = by_shard(pairs, CORE_NUM, |(id, _)| shard_id(*id));
+        let answers = self
+            .invoke_on_batches(batches, |shard, pairs| Ok(shard.multi_append(pairs)))
+            .await?;
+        let answers = order.restore(answers);
+        let mut results = Vec::with_capacity(answers.len());
+        for durable in answers {
+            results.push(match durable {
+                Ok(durable) => acknowledged(durable).await,
+                Err(e) => Err(e),
             });
+        }
+        Ok(results)
+    }
 
-            tx.send(result).unwrap();
-        });
+    /// Like [`AffinityLoad::get`], without copying the cached bytes.
+    pub async fn get_shared(&self, id: Id, size: usize) -> Result<SharedBytes, Error> {
+        self.invoke_on(shard_id(id), move |shard| shard.get_shared(id, size))
+            .await
+    }
+
+    /// Drop everything of `id`.
+    pub async fn remove(&self, id: Id) -> Result<(), Error> {
+        let durable = self
+            .invoke_on(shard_id(id), move |shard| shard.remove(id))
+            .await?;
+        acknowledged(durable).await
+    }
+
+    /// Keep the first `len` bytes of `id`.
+    pub async fn truncate(&self, id: Id, len: usize) -> Result<(), Error> {
+        let durable = self
+            .invoke_on(shard_id(id), move |shard| shard.truncate(id, len))
+            .await?;
+        acknowledged(durable).await
+    }
+
+    pub async fn replace(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
+        let durable = self
+            .invoke_on(shard_id(id), move |shard| shard.replace(id, bytes))
+            .await?;
+        acknowledged(durable).await
+    }
 
-        let _ = rx.await.unwrap();
+    /// Snapshot every shard into `dir`, each on its own core. A snapshot into
+    /// the data directory also checkpoints the write-ahead logs.
+    pub async fn snapshot(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir: PathBuf = dir.as_ref().to_owned();
+        std::fs::create_dir_all(&dir)?;
+        self.invoke_on_all(move |index, shard| shard.snapshot(&dir, index))
+            .await?;
+        Ok(())
+    }
+
+    /// Restore shards from a snapshot written by [`AffinityLoad::snapshot`]
+    /// into `dir`, each on its own core. Caches without a file are left as
+    /// they are.
+    pub async fn restore(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir: PathBuf = dir.as_ref().to_owned();
+        self.invoke_on_all(move |index, shard| shard.restore(&dir, index))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn append_point(&self, id: Id, timestamp: i64, value: f64) -> Result<(), Error> {
+        let durable = self
+            .invoke_on(shard_id(id), move |shard| {
+                shard.append_point(id, timestamp, value)
+            })
+            .await?;
+        acknowledged(durable).await
+    }
+
+    /// Points of `id` with timestamp in `[start, end)`.
+    pub async fn query(&self, id: Id, start: i64, end: i64) -> Result<Vec<Point>, Error> {
+        self.invoke_on(shard_id(id), move |shard| shard.query(id, start, end))
+            .await
+    }
+
+    /// Ids in `range` and everything appended to them, at most `limit` in id
+    /// order across all shards, each scanned on its own core. Pass the
+    /// returned cursor as range to get the next page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub async fn scan(
+        &self,
+        range: impl RangeBounds<Id>,
+        limit: usize,
+    ) -> Result<ScanPage<Id, Bytes>, Error> {
+        assert!(limit > 0, "scan of 0 items");
+        let bounds = bounds(&range);
+        let pages = self
+            .invoke_on_all(move |_, shard| shard.scan(bounds, limit))
+            .await?;
+        Ok(ScanPage::merge(pages, &range, limit))
+    }
+
+    /// Compact up to `max_items` items of every cache, each shard on its
+    /// own core, going on where the previous call stopped. Returns the bytes
+    /// released.
+    pub async fn compact(&self, max_items: usize) -> Result<usize, Error> {
+        let freed = self
+            .invoke_on_all(move |_, shard| shard.compact(max_items))
+            .await?;
+        Ok(freed.into_iter().sum())
+    }
+
+    /// How full the blocks of all shards are.
+    pub async fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        let fragmentation = self.invoke_on_all(|_, shard| shard.fragmentation()).await?;
+        Ok(fragmentation.into_iter().sum())
+    }
+
+    /// Counters, fragmentation and items of every shard, each taken on its
+    /// own core.
+    pub async fn stats(&self) -> Result<LoadStats, Error> {
+        let shards = self.invoke_on_all(|_, shard| shard.stats()).await?;
+        Ok(LoadStats { shards })
+    }
+
+    /// Run `f` on shard `index`. A panic or a dead worker fails with
+    /// [`Error::ShardUnavailable`].
+    async fn invoke_on<F, T>(&self, index: usize, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut AffinityShard) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        within(self.timeout, async {
+            Ok(self.shards.try_invoke_on(index, f).await?)
+        })
+        .await
+    }
+
+    /// Run `f` with each batch on its shard, all at once, failing with the
+    /// first error. Answers are in the order of `batches`.
+    async fn invoke_on_batches<F, T, R>(
+        &self,
+        batches: Vec<(usize, Vec<T>)>,
+        f: F,
+    ) -> Result<Vec<Vec<R>>, Error>
+    where
+        F: Fn(&mut AffinityShard, Vec<T>) -> Result<Vec<R>, Error> + Copy + Send + 'static,
+        T: Send + 'static,
+        R: Send + 'static,
+    {
+        let calls = batches
+            .into_iter()
+            .map(|(index, batch)| self.invoke_on(index, move |shard| f(shard, batch)));
+        join_all(calls).await.into_iter().collect()
+    }
+
+    /// Run `f` on every shard, failing with the first error. Results are in
+    /// shard order.
+    async fn invoke_on_all<F, T>(&self, f: F) -> Result<Vec<T>, Error>
+    where
+        F: Fn(usize, &mut AffinityShard) -> Result<T, Error> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        within(self.timeout, async {
+            self.shards
+                .try_invoke_on_all(f)
+                .await
+                .into_iter()
+                .map(|result| result.map_err(Error::from))
+                .collect()
+        })
+        .await
     }
 }
 
@@ -91,10 +576,17 @@ fn shard_id(id: Id) -> usize {
     id % CORE_NUM
 }
 
-#[inline]
-fn calculation(mut bytes: Bytes) -> Bytes {
-    let mut sum: u8 = 0;
-    bytes.iter().for_each(|x| sum = sum.wrapping_add(*x));
-    bytes[0] += sum;
-    bytes
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_release_memory() {
+        let load = AffinityLoad::new(&[core_affinity::get_core_ids().unwrap()[0]; CORE_NUM]);
+        load.append(0, vec![1; 40_000]).await.unwrap();
+        load.append_point(0, 0, 1.0).await.unwrap();
+        assert!(load.stats().await.unwrap().total().memory > 0);
+        load.remove(0).await.unwrap();
+        assert_eq!(load.stats().await.unwrap().total().memory, 0);
+    }
 }
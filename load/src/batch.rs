@@ -0,0 +1,105 @@
+/// Where the items of a request went, to put the answers of their shards
+/// back in request order.
+pub(crate) struct RequestOrder {
+    len: usize,
+    /// Positions in the request of the items of each batch.
+    positions: Vec<Vec<usize>>,
+}
+
+/// Group `items` by their shard, so each shard gets one message for all of
+/// its items. Returns each shard with items, in shard order, and how to put
+/// their answers back.
+pub(crate) fn by_shard<T>(
+    items: impl IntoIterator<Item = T>,
+    shards: usize,
+    shard_of: impl Fn(&T) -> usize,
+) -> (Vec<(usize, Vec<T>)>, RequestOrder) {
+    let mut groups = Vec::with_capacity(shards);
+    groups.resize_with(shards, || (vec![], vec![]));
+    let mut len = 0;
+    for (position, item) in items.into_iter().enumerate() {
+        let (positions, items) = &mut groups[shard_of(&item)];
+        positions.push(position);
+        items.push(item);
+        len += 1;
+    }
+
+    let mut batches = vec![];
+    let mut order = RequestOrder {
+        len,
+        positions: vec![],
+    };
+    for (shard, (positions, items)) in groups.into_iter().enumerate() {
+        if !items.is_empty() {
+            batches.push((shard, items));
+            order.positions.push(positions);
+        }
+    }
+    (batches, order)
+}
+
+impl RequestOrder {
+    /// Answers of every batch, each in the order of its items, in the order
+    /// of the request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a batch doesn't have one answer per item.
+    pub(crate) fn restore<R>(self, answers: Vec<Vec<R>>) -> Vec<R> {
+        assert_eq!(answers.len(), self.positions.len(), "answer of every batch");
+        let mut slots = Vec::with_capacity(self.len);
+        slots.resize_with(self.len, || None);
+        for (positions, answers) in self.positions.into_iter().zip(answers) {
+            assert_eq!(positions.len(), answers.len(), "answer of every item");
+            for (position, answer) in positions.into_iter().zip(answers) {
+                slots[position] = Some(answer);
+            }
+        }
+        slots.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_come_back_in_request_order() {
+        // Duplicates, and ids of one shard apart in the request.
+        let ids = [7, 2, 5, 7, 0, 3, 2, 9, 7];
+        let (batches, order) = by_shard(ids.iter().copied(), 4, |&id| id % 4);
+        let shards: Vec<_> = batches.iter().map(|(shard, _)| *shard).collect();
+        assert_eq!(shards, [0, 1, 2, 3]);
+        assert_eq!(batches[3].1, [7, 7, 3, 7]);
+        assert_eq!(batches[1].1, [5, 9]);
+
+        // Each answer tells its shard and place in the batch.
+        let answers = batches
+            .iter()
+            .map(|(shard, ids)| (0..ids.len()).map(|i| (*shard, ids[i], i)).collect())
+            .collect();
+        let restored = order.restore(answers);
+        let ids_back: Vec<_> = restored.iter().map(|&(_, id, _)| id).collect();
+        assert_eq!(ids_back, ids);
+        let places: Vec<_> = restored.iter().filter(|a| a.1 == 7).map(|a| a.2).collect();
+        assert_eq!(places, [0, 1, 3]);
+    }
+
+    #[test]
+    fn empty_requests_and_shards_are_skipped() {
+        let (batches, order) = by_shard(Vec::<usize>::new(), 4, |&id| id);
+        assert!(batches.is_empty());
+        assert!(order.restore(Vec::<Vec<()>>::new()).is_empty());
+
+        let (batches, order) = by_shard(vec![3, 3], 8, |&id| id);
+        assert_eq!(batches, [(3, vec![3, 3])]);
+        assert_eq!(order.restore(vec![vec!['a', 'b']]), ['a', 'b']);
+    }
+
+    #[test]
+    #[should_panic(expected = "answer of every item")]
+    fn missing_answers_panic() {
+        let (_, order) = by_shard(vec![1, 2, 1], 2, |&id| id % 2);
+        order.restore(vec![vec![()], vec![()]]);
+    }
+}
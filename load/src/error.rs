@@ -0,0 +1,87 @@
+use cache::Corrupted;
+use runtime::CoreError;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+/// Why a load operation failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The id has nothing cached.
+    NotFound,
+    /// A lock is poisoned by a thread that panicked holding it.
+    Poisoned,
+    /// The shard's thread or worker is gone, or dropped the request.
+    ShardUnavailable,
+    /// The value needs more memory than the cache may hold.
+    CapacityExceeded {
+        needed: usize,
+        capacity: usize,
+    },
+    Corrupted(Corrupted),
+    /// The shard didn't answer in time.
+    Timeout,
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::Poisoned => write!(f, "lock poisoned"),
+            Error::ShardUnavailable => write!(f, "shard unavailable"),
+            Error::CapacityExceeded { needed, capacity } => {
+                write!(f, "needs {needed} bytes, over capacity {capacity}")
+            }
+            Error::Corrupted(e) => e.fmt(f),
+            Error::Timeout => write!(f, "timed out"),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Corrupted(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<cache::Error> for Error {
+    fn from(e: cache::Error) -> Self {
+        match e {
+            cache::Error::NotFound => Error::NotFound,
+            cache::Error::Poisoned => Error::Poisoned,
+            cache::Error::CapacityExceeded { needed, capacity } => {
+                Error::CapacityExceeded { needed, capacity }
+            }
+            cache::Error::Corrupted(e) => Error::Corrupted(e),
+            cache::Error::Io(e) => Error::Io(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error::Poisoned
+    }
+}
+
+impl From<CoreError<Error>> for Error {
+    fn from(e: CoreError<Error>) -> Self {
+        match e {
+            CoreError::Failed(e) => e,
+            CoreError::Panicked | CoreError::Aborted => Error::ShardUnavailable,
+        }
+    }
+}
@@ -1,8 +1,106 @@
 #![feature(test)]
 mod affinity;
+mod batch;
+mod error;
 mod local_set;
+mod stats;
 mod threading;
 
+use cache::{BlockPool, Bytes, CacheCell, Durable, Id, SyncMode};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often loads sweep expired blocks.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Items each cache compacts after a sweep, going on where the previous
+/// sweep stopped.
+const COMPACT_ITEMS: usize = 64;
+
+/// File of cache `cache` in shard `shard`, in a snapshot directory.
+fn snapshot_path(dir: &Path, shard: usize, cache: usize) -> PathBuf {
+    dir.join(format!("shard-{shard}-{cache}.snap"))
+}
+
+/// Write-ahead log directory of shard `shard`, in a data directory.
+fn wal_path(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(format!("wal-{shard}"))
+}
+
+/// Whether `dir` is `data_dir`, whose logs are checkpointed by snapshots
+/// into it.
+fn is_data_dir(data_dir: Option<&Path>, dir: &Path) -> bool {
+    match (data_dir, fs::canonicalize(dir)) {
+        (Some(data_dir), Ok(dir)) => data_dir == dir,
+        _ => false,
+    }
+}
+
+/// Snapshot `cache` holding the log before `log_seq` to `path`. It's written
+/// to a temporary file first, so the previous snapshot is kept until the new
+/// one is complete.
+fn snapshot_cache<P: BlockPool, S: SyncMode>(
+    cache: &CacheCell<Id, Bytes, P, S>,
+    path: &Path,
+    log_seq: u64,
+) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    cache.snapshot_at(&mut writer, log_seq)?;
+    writer.into_inner().map_err(io::Error::from)?.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Restore `cache` from `path`, if there is a snapshot. Returns the sequence
+/// number of the first log record it doesn't hold.
+fn restore_cache<P: BlockPool, S: SyncMode>(
+    cache: &CacheCell<Id, Bytes, P, S>,
+    path: &Path,
+) -> Result<u64, Error> {
+    match File::open(path) {
+        Ok(file) => Ok(cache.restore(BufReader::new(file))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Owned bounds of `range`, to send it to shards.
+fn bounds(range: &impl RangeBounds<Id>) -> (Bound<Id>, Bound<Id>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Wait until the log acknowledges a write, if it was logged.
+async fn acknowledged(durable: Option<Durable>) -> Result<(), Error> {
+    if let Some(durable) = durable {
+        durable.await?;
+    }
+    Ok(())
+}
+
+/// Wait for `response` of a shard, failing with [`Error::Timeout`] after
+/// `timeout` if there is one.
+async fn within<T>(
+    timeout: Option<Duration>,
+    response: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, response)
+            .await
+            .map_err(|_| Error::Timeout)?,
+        None => response.await,
+    }
+}
+
 pub use affinity::AffinityLoad;
+pub use error::Error;
 pub use local_set::LocalSetLoad;
+pub use stats::LoadStats;
 pub use threading::ThreadingLoad;
+
+pub use cache::Point;
+pub use runtime::{RuntimeMetrics, WorkerMetrics};
@@ -1,34 +1,146 @@
-use cache::{Bytes, Cache, Id};
+use cache::{
+    Bytes, CacheStats, Durable, Fragmentation, Id, LocalCache, LocalPool, Point, ScanPage,
+    SharedBytes, Wal, WalOptions, WalRecord,
+};
 
+use futures::future::join_all;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Duration;
 use tokio::runtime::Builder;
 use tokio::sync::mpsc::{
     unbounded_channel, UnboundedReceiver as Receiver, UnboundedSender as Sender,
 };
 use tokio::sync::oneshot;
 use tokio::task::LocalSet;
+use tokio::time::interval;
+
+use crate::batch::by_shard;
+use crate::{
+    acknowledged, bounds, is_data_dir, restore_cache, snapshot_cache, snapshot_path, wal_path,
+    within, Error, LoadStats, COMPACT_ITEMS, SWEEP_INTERVAL,
+};
+
+/// Caches of a shard are only touched by the shard's thread, so they take no
+/// locks, and share one block pool of the thread.
+type ShardCache = LocalCache<Id, Bytes, Rc<LocalPool>>;
+
+/// Each task answers with its result, or the error that failed it.
+type Response<T> = oneshot::Sender<Result<T, Error>>;
 
 #[derive(Debug)]
 enum Task {
-    Append(Id, Bytes, oneshot::Sender<()>),
-    Get(Id, usize, oneshot::Sender<Option<Bytes>>),
+    Append(Id, Bytes, Response<()>),
+    Get(Id, usize, Response<Bytes>),
+    GetShared(Id, usize, Response<SharedBytes>),
+    Remove(Id, Response<()>),
+    Truncate(Id, usize, Response<()>),
+    Replace(Id, Bytes, Response<()>),
+    AppendPoint(Id, i64, f64, Response<()>),
+    Query(Id, i64, i64, Response<Vec<Point>>),
+    /// Ids of the shard, answered each in their order.
+    MultiGet(Vec<Id>, usize, Response<Vec<Result<Bytes, Error>>>),
+    MultiAppend(Vec<(Id, Bytes)>, Response<Vec<Result<(), Error>>>),
+    /// Snapshot directory and index of the shard.
+    Snapshot(PathBuf, usize, Response<()>),
+    Restore(PathBuf, usize, Response<()>),
+    /// Range and most items of the page.
+    Scan((Bound<Id>, Bound<Id>), usize, Response<ScanPage<Id, Bytes>>),
+    /// Items to compact in each cache.
+    Compact(usize, Response<usize>),
+    /// Retention of an id, or with `None` of every id without its own.
+    Retention(Option<Id>, Option<Duration>, Response<()>),
+    /// Data directory, index of the shard, its log and the records to
+    /// replay. Handled by the shard's loop, so later tasks see the log.
+    OpenWal(
+        PathBuf,
+        usize,
+        Box<Wal>,
+        Vec<(u64, WalRecord)>,
+        Duration,
+        mpsc::Sender<Result<(), Error>>,
+    ),
+    Fragmentation(Response<Fragmentation>),
+    Stats(Response<CacheStats>),
+}
+
+/// Write-ahead log of a shard, and the data directory it belongs to.
+struct ShardWal {
+    wal: RefCell<Wal>,
+    data_dir: PathBuf,
+    group_delay: Duration,
+}
+
+impl ShardWal {
+    /// Log `record` if the shard has a write-ahead log. Writes are applied
+    /// right after, in log order, and answered once acknowledged.
+    fn log(wal: &Option<Rc<ShardWal>>, record: WalRecord<&[u8]>) -> Result<Option<Durable>, Error> {
+        match wal {
+            Some(wal) => {
+                let mut wal = wal.wal.borrow_mut();
+                let seq = wal.log(&record)?;
+                Ok(Some(wal.durable(seq)))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 struct LocalShard<const SHARD_NUM: usize> {
     rx: Receiver<Task>,
-    caches: Rc<Vec<Cache>>,
+    caches: Rc<Vec<ShardCache>>,
 }
 
 impl<const SHARD_NUM: usize> LocalShard<SHARD_NUM> {
     pub fn new(rx: Receiver<Task>) -> Self {
+        let pool = Rc::new(LocalPool::default());
         let mut caches = Vec::with_capacity(SHARD_NUM);
-        caches.resize_with(SHARD_NUM, Default::default);
+        caches.resize_with(SHARD_NUM, || {
+            LocalCache::default().with_block_pool(pool.clone())
+        });
         let caches = Rc::new(caches);
 
         Self { rx, caches }
     }
 
-    /// Block current thread to process tasks.
+    /// Restore the snapshot of shard `index` from `data_dir`, replay
+    /// `records` of its write-ahead log on top, and log writes to `wal`.
+    fn open_wal(
+        caches: &[ShardCache],
+        data_dir: PathBuf,
+        index: usize,
+        wal: Wal,
+        records: Vec<(u64, WalRecord)>,
+        group_delay: Duration,
+    ) -> Result<Rc<ShardWal>, Error> {
+        let log_seqs = caches
+            .iter()
+            .enumerate()
+            .map(|(i, cache)| restore_cache(cache, &snapshot_path(&data_dir, index, i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (seq, record) in records {
+            // Records before a crash between a checkpoint's snapshot and
+            // deleting the log are in the snapshot already.
+            let i = Self::shard_id(record.id());
+            if seq >= log_seqs[i] {
+                record.apply(&caches[i])?;
+            }
+        }
+
+        Ok(Rc::new(ShardWal {
+            wal: RefCell::new(wal),
+            data_dir,
+            group_delay: group_delay.max(Duration::from_millis(1)),
+        }))
+    }
+
+    /// Block current thread to process tasks, sweep expired blocks, and
+    /// commit the write-ahead log if there is one.
     pub fn run(self) {
         let local = LocalSet::new();
         let rt = Builder::new_current_thread().enable_all().build().unwrap();
@@ -36,28 +148,208 @@ impl<const SHARD_NUM: usize> LocalShard<SHARD_NUM> {
         let Self { mut rx, caches } = self;
 
         local.spawn_local(async move {
-            while let Some(task) = rx.recv().await {
-                tokio::task::spawn_local(Self::run_task(caches.clone(), task));
+            let mut wal: Option<Rc<ShardWal>> = None;
+            let mut sweep_interval = interval(SWEEP_INTERVAL);
+            let mut commit_interval = interval(SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    task = rx.recv() => match task {
+                        Some(Task::OpenWal(data_dir, index, log, records, group_delay, tx)) => {
+                            let opened = Self::open_wal(&caches, data_dir, index, *log, records, group_delay);
+                            let _ = tx.send(opened.map(|opened| {
+                                commit_interval = interval(opened.group_delay);
+                                wal = Some(opened);
+                            }));
+                        }
+                        Some(task) => {
+                            tokio::task::spawn_local(Self::run_task(caches.clone(), wal.clone(), task));
+                        }
+                        None => break,
+                    },
+                    _ = sweep_interval.tick() => {
+                        caches.iter().for_each(|cache| {
+                            let _ = cache.sweep();
+                            let _ = cache.compact(COMPACT_ITEMS);
+                        });
+                    }
+                    _ = commit_interval.tick(), if wal.is_some() => {
+                        // A failed commit keeps its records buffered, the
+                        // next write that commits them reports the error.
+                        let wal = wal.as_ref().unwrap();
+                        let _ = wal.wal.borrow_mut().commit();
+                    }
+                }
             }
         });
 
         rt.block_on(local);
     }
 
-    async fn run_task(caches: Rc<Vec<Cache>>, task: Task) {
+    async fn run_task(caches: Rc<Vec<ShardCache>>, wal: Option<Rc<ShardWal>>, task: Task) {
+        // Callers that timed out dropped their receiver, so sending may fail.
         match task {
             Task::Append(id, bytes, tx) => {
-                let bytes = calculation(bytes);
-                let id = Self::shard_id(id);
-                caches[id].append(id, bytes);
+                let result = async {
+                    let durable = ShardWal::log(&wal, WalRecord::Append(id, &bytes))?;
+                    caches[Self::shard_id(id)].append(id, bytes)?;
+                    acknowledged(durable).await
+                };
+                let result = result.await;
 
-                tx.send(()).unwrap();
+                let _ = tx.send(result);
             }
             Task::Get(id, size, tx) => {
-                let id = Self::shard_id(id);
-                let result = caches[id].get(id, size).map(calculation);
+                let result = caches[Self::shard_id(id)].get(&id, size);
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::MultiGet(ids, size, tx) => {
+                let results = ids
+                    .into_iter()
+                    .map(|id| Ok(caches[Self::shard_id(id)].get(&id, size)?))
+                    .collect();
+
+                let _ = tx.send(Ok(results));
+            }
+            Task::MultiAppend(pairs, tx) => {
+                let logged: Vec<_> = pairs
+                    .into_iter()
+                    .map(|(id, bytes)| {
+                        let durable = ShardWal::log(&wal, WalRecord::Append(id, &bytes))?;
+                        caches[Self::shard_id(id)].append(id, bytes)?;
+                        Ok(durable)
+                    })
+                    .collect();
+                let mut results = Vec::with_capacity(logged.len());
+                for durable in logged {
+                    results.push(match durable {
+                        Ok(durable) => acknowledged(durable).await,
+                        Err(e) => Err(e),
+                    });
+                }
+
+                let _ = tx.send(Ok(results));
+            }
+            Task::GetShared(id, size, tx) => {
+                let result = caches[Self::shard_id(id)].get_shared(&id, size);
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::Snapshot(dir, shard, tx) => {
+                let snapshot = |log_seq| {
+                    caches.iter().enumerate().try_for_each(|(i, cache)| {
+                        snapshot_cache(cache, &snapshot_path(&dir, shard, i), log_seq)
+                    })
+                };
+                let result = match &wal {
+                    Some(wal) if is_data_dir(Some(&wal.data_dir), &dir) => {
+                        wal.wal.borrow_mut().checkpoint(snapshot)
+                    }
+                    _ => snapshot(0),
+                };
+
+                let _ = tx.send(result);
+            }
+            Task::Restore(dir, shard, tx) => {
+                let result = caches.iter().enumerate().try_for_each(|(i, cache)| {
+                    restore_cache(cache, &snapshot_path(&dir, shard, i)).map(drop)
+                });
+
+                let _ = tx.send(result);
+            }
+            Task::Scan(range, limit, tx) => {
+                let result = caches
+                    .iter()
+                    .map(|cache| cache.scan(range, limit))
+                    .collect::<Result<_, _>>()
+                    .map(|pages| ScanPage::merge(pages, &range, limit));
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::Retention(id, ttl, tx) => {
+                let result = match id {
+                    Some(id) => caches[Self::shard_id(id)].set_retention(id, ttl),
+                    None => caches
+                        .iter()
+                        .try_for_each(|cache| cache.set_default_retention(ttl)),
+                };
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::OpenWal(..) => unreachable!("handled by the shard's loop"),
+            Task::Compact(max_items, tx) => {
+                let result = caches
+                    .iter()
+                    .map(|cache| cache.compact(max_items))
+                    .sum::<Result<usize, _>>();
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::Fragmentation(tx) => {
+                let result = caches
+                    .iter()
+                    .map(|cache| cache.fragmentation())
+                    .sum::<Result<Fragmentation, _>>();
+
+                let _ = tx.send(result.map_err(Error::from));
+            }
+            Task::Stats(tx) => {
+                let result = caches
+                    .iter()
+                    .map(|cache| cache.stats())
+                    .collect::<Result<Vec<_>, _>>();
+
+                let _ = tx.send(
+                    result
+                        .map(|stats| stats.into_iter().sum())
+                        .map_err(Error::from),
+                );
+            }
+            Task::Remove(id, tx) => {
+                let result = async {
+                    let durable = ShardWal::log(&wal, WalRecord::Remove(id))?;
+                    caches[Self::shard_id(id)].remove(&id)?;
+                    acknowledged(durable).await
+                };
+                let result = result.await;
+
+                let _ = tx.send(result);
+            }
+            Task::Truncate(id, len, tx) => {
+                let result = async {
+                    let durable = ShardWal::log(&wal, WalRecord::Truncate(id, len))?;
+                    caches[Self::shard_id(id)].truncate(&id, len)?;
+                    acknowledged(durable).await
+                };
+                let result = result.await;
+
+                let _ = tx.send(result);
+            }
+            Task::Replace(id, bytes, tx) => {
+                let result = async {
+                    let durable = ShardWal::log(&wal, WalRecord::Replace(id, &bytes))?;
+                    caches[Self::shard_id(id)].replace(id, bytes)?;
+                    acknowledged(durable).await
+                };
+                let result = result.await;
 
-                tx.send(result).unwrap()
+                let _ = tx.send(result);
+            }
+            Task::AppendPoint(id, timestamp, value, tx) => {
+                let result = async {
+                    let durable =
+                        ShardWal::log(&wal, WalRecord::AppendPoint(id, timestamp, value))?;
+                    caches[Self::shard_id(id)].append_point(id, timestamp, value)?;
+                    acknowledged(durable).await
+                };
+                let result = result.await;
+
+                let _ = tx.send(result);
+            }
+            Task::Query(id, start, end, tx) => {
+                let result = caches[Self::shard_id(id)].query(&id, start, end);
+
+                let _ = tx.send(result.map_err(Error::from));
             }
         }
     }
@@ -73,9 +365,12 @@ const WORKING_THREAD: usize = 15;
 
 pub struct LocalSetLoad {
     txs: Vec<Sender<Task>>,
+    timeout: Option<Duration>,
 }
 
 impl LocalSetLoad {
+    /// Each shard sweeps expired blocks on its own thread, and compacts a few
+    /// items after each sweep.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let mut txs = Vec::with_capacity(WORKING_THREAD);
@@ -90,27 +385,316 @@ impl LocalSetLoad {
             });
         }
 
-        Self { txs }
+        Self { txs, timeout: None }
     }
 
-    pub async fn append(&self, id: Id, bytes: Bytes) {
+    /// Drop blocks not written for `ttl`, unless the id has its own
+    /// retention, see [`LocalSetLoad::set_retention`]. Shards take it before
+    /// any later request.
+    pub fn with_retention(self, ttl: Duration) -> Result<Self, Error> {
+        for shard in 0..self.txs.len() {
+            // Tasks are run in order, no need to wait for the answer.
+            let (tx, _) = oneshot::channel();
+            self.send(shard, Task::Retention(None, Some(ttl), tx))?;
+        }
+        Ok(self)
+    }
+
+    /// Keep data in `dir` across restarts. Each shard restores its snapshot
+    /// from `dir` and replays its write-ahead log on top, then logs every
+    /// write. Writes are answered once the log acknowledges them, as its
+    /// [`FsyncPolicy`](cache::FsyncPolicy) says, waiting off the shard's
+    /// other tasks. Snapshots into `dir` truncate the logs. Call it before
+    /// writing, writes before aren't logged.
+    pub fn with_wal(self, dir: impl AsRef<Path>, options: WalOptions) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let data_dir = fs::canonicalize(dir)?;
+        let wals = (0..self.txs.len())
+            .map(|index| Wal::open(wal_path(dir, index), options.clone()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (tx, rx) = mpsc::channel();
+        for (index, (wal, records)) in wals.into_iter().enumerate() {
+            let task = Task::OpenWal(
+                data_dir.clone(),
+                index,
+                Box::new(wal),
+                records,
+                options.group_delay(),
+                tx.clone(),
+            );
+            self.send(index, task)?;
+        }
+        drop(tx);
+
+        for _ in 0..self.txs.len() {
+            rx.recv().map_err(|_| Error::ShardUnavailable)??;
+        }
+        Ok(self)
+    }
+
+    /// Fail requests with [`Error::Timeout`] when their shard doesn't answer
+    /// within `timeout`. Needs a Tokio runtime with time enabled.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set retention of `id`, overriding the load's. `None` keeps it
+    /// forever. The setting goes with the id, and isn't kept across
+    /// restarts.
+    pub async fn set_retention(&self, id: Id, ttl: Option<Duration>) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::Retention(Some(id), ttl, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    pub async fn append(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
         let (tx, rx) = oneshot::channel();
         let task = Task::Append(id, bytes, tx);
         let shard_id = self.shard_id(id);
 
-        self.txs[shard_id].send(task).unwrap();
+        self.send(shard_id, task)?;
 
-        rx.await.unwrap()
+        self.response(rx).await
     }
 
-    pub async fn get(&self, id: Id, size: usize) {
+    /// Blocks read are checked against their checksum.
+    pub async fn get(&self, id: Id, size: usize) -> Result<(), Error> {
         let (tx, rx) = oneshot::channel();
         let task = Task::Get(id, size, tx);
         let shard_id = self.shard_id(id);
 
-        self.txs[shard_id].send(task).unwrap();
+        self.send(shard_id, task)?;
+
+        self.response(rx).await.map(|_| ())
+    }
+
+    /// Up to `size` bytes of each of `ids`, in the order of `ids`. Ids are
+    /// sent to their shards in one task per shard. A failed id fails only its
+    /// own result, a shard that doesn't answer fails the whole call.
+    pub async fn multi_get(
+        &self,
+        ids: &[Id],
+        size: usize,
+    ) -> Result<Vec<Result<Bytes, Error>>, Error> {
+        let (batches, order) =
+            by_shard(ids.iter().copied(), self.txs.len(), |&id| self.shard_id(id));
+        let answers = self
+            .on_shards(batches, |ids, tx| Task::MultiGet(ids, size, tx))
+            .await?;
+        Ok(order.restore(answers))
+    }
 
-        let _ = rx.await.unwrap();
+    /// Append each pair, with the results in the order of `pairs`. Pairs
+    /// are sent to their shards in one task per shard, and pairs of one id
+    /// are appended in order. A failed append fails only its own result, a
+    /// shard that doesn't answer fails the whole call.
+    pub async fn multi_append(
+        &self,
+        pairs: Vec<(Id, Bytes)>,
+    ) -> Result<Vec<Result<(), Error>>, Error> {
+        let (batches, order) = by_shard(pairs, self.txs.len(), |(id, _)| self.shard_id(*id));
+        let answers = self.on_shards(batches, Task::MultiAppend).await?;
+        Ok(order.restore(answers))
+    }
+
+    /// Like [`LocalSetLoad::get`], without copying the cached bytes.
+    pub async fn get_shared(&self, id: Id, size: usize) -> Result<SharedBytes, Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::GetShared(id, size, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    /// Drop everything of `id`.
+    pub async fn remove(&self, id: Id) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::Remove(id, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    /// Keep the first `len` bytes of `id`.
+    pub async fn truncate(&self, id: Id, len: usize) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::Truncate(id, len, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    pub async fn replace(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::Replace(id, bytes, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    /// Snapshot every shard into `dir`, each on its own thread. A snapshot
+    /// into the data directory also checkpoints the write-ahead logs.
+    pub async fn snapshot(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        self.on_all_shards(|shard, tx| Task::Snapshot(dir.to_owned(), shard, tx))
+            .await?;
+        Ok(())
+    }
+
+    /// Restore shards from a snapshot written by [`LocalSetLoad::snapshot`]
+    /// into `dir`. Caches without a file are left as they are.
+    pub async fn restore(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        self.on_all_shards(|shard, tx| Task::Restore(dir.to_owned(), shard, tx))
+            .await?;
+        Ok(())
+    }
+
+    /// Ids in `range` and everything appended to them, at most `limit` in id
+    /// order across all shards, each scanned on its own thread. Pass the
+    /// returned cursor as range to get the next page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub async fn scan(
+        &self,
+        range: impl RangeBounds<Id>,
+        limit: usize,
+    ) -> Result<ScanPage<Id, Bytes>, Error> {
+        assert!(limit > 0, "scan of 0 items");
+        let pages = self
+            .on_all_shards(|_, tx| Task::Scan(bounds(&range), limit, tx))
+            .await?;
+        Ok(ScanPage::merge(pages, &range, limit))
+    }
+
+    /// Compact up to `max_items` items of every cache, each shard on its
+    /// own thread, going on where the previous call stopped. Returns the
+    /// bytes released.
+    pub async fn compact(&self, max_items: usize) -> Result<usize, Error> {
+        let freed = self
+            .on_all_shards(|_, tx| Task::Compact(max_items, tx))
+            .await?;
+        Ok(freed.into_iter().sum())
+    }
+
+    /// How full the blocks of all shards are.
+    pub async fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        let fragmentation = self.on_all_shards(|_, tx| Task::Fragmentation(tx)).await?;
+        Ok(fragmentation.into_iter().sum())
+    }
+
+    /// Counters, fragmentation and items of every shard, each taken on its
+    /// own thread.
+    pub async fn stats(&self) -> Result<LoadStats, Error> {
+        let shards = self.on_all_shards(|_, tx| Task::Stats(tx)).await?;
+        Ok(LoadStats { shards })
+    }
+
+    /// Send the task built by `task` to every shard, and wait for all of
+    /// them. Results are in shard order.
+    async fn on_all_shards<F, T>(&self, task: F) -> Result<Vec<T>, Error>
+    where
+        F: Fn(usize, Response<T>) -> Task,
+    {
+        let mut rxs = Vec::with_capacity(self.txs.len());
+        for shard in 0..self.txs.len() {
+            let (tx, rx) = oneshot::channel();
+            self.send(shard, task(shard, tx))?;
+            rxs.push(rx);
+        }
+
+        self.responses(rxs).await
+    }
+
+    /// Send each shard its batch in the task built by `task`, and wait for
+    /// all of them. Answers are in the order of `batches`.
+    async fn on_shards<F, T, R>(
+        &self,
+        batches: Vec<(usize, Vec<T>)>,
+        task: F,
+    ) -> Result<Vec<Vec<R>>, Error>
+    where
+        F: Fn(Vec<T>, Response<Vec<R>>) -> Task,
+    {
+        let mut rxs = Vec::with_capacity(batches.len());
+        for (shard, batch) in batches {
+            let (tx, rx) = oneshot::channel();
+            self.send(shard, task(batch, tx))?;
+            rxs.push(rx);
+        }
+
+        self.responses(rxs).await
+    }
+
+    pub async fn append_point(&self, id: Id, timestamp: i64, value: f64) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::AppendPoint(id, timestamp, value, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    /// Points of `id` with timestamp in `[start, end)`.
+    pub async fn query(&self, id: Id, start: i64, end: i64) -> Result<Vec<Point>, Error> {
+        let (tx, rx) = oneshot::channel();
+        let task = Task::Query(id, start, end, tx);
+        let shard_id = self.shard_id(id);
+
+        self.send(shard_id, task)?;
+
+        self.response(rx).await
+    }
+
+    /// Send `task` to shard `shard`, which fails if its thread is gone.
+    fn send(&self, shard: usize, task: Task) -> Result<(), Error> {
+        self.txs[shard]
+            .send(task)
+            .map_err(|_| Error::ShardUnavailable)
+    }
+
+    /// Wait for the answer of a task. A task dropped without answer means
+    /// its shard is gone, or it panicked.
+    async fn response<T>(&self, rx: oneshot::Receiver<Result<T, Error>>) -> Result<T, Error> {
+        within(self.timeout, async {
+            rx.await.map_err(|_| Error::ShardUnavailable)?
+        })
+        .await
+    }
+
+    /// Wait for the answers of tasks sent to several shards, all within one
+    /// timeout. Fails with the first error in the order of `rxs`.
+    async fn responses<T>(
+        &self,
+        rxs: Vec<oneshot::Receiver<Result<T, Error>>>,
+    ) -> Result<Vec<T>, Error> {
+        within(self.timeout, async {
+            join_all(rxs)
+                .await
+                .into_iter()
+                .map(|answer| answer.map_err(|_| Error::ShardUnavailable)?)
+                .collect()
+        })
+        .await
     }
 
     #[inline]
@@ -119,10 +703,64 @@ impl LocalSetLoad {
     }
 }
 
-#[inline]
-fn calculation(mut bytes: Bytes) -> Bytes {
-    let mut sum: u8 = 0;
-    bytes.iter().for_each(|x| sum = sum.wrapping_add(*x));
-    bytes[0] += sum;
-    bytes
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_release_memory() {
+        let load = LocalSetLoad::new();
+        load.append(0, vec![1; 40_000]).await.unwrap();
+        load.append_point(0, 0, 1.0).await.unwrap();
+        assert!(load.stats().await.unwrap().total().memory > 0);
+        load.remove(0).await.unwrap();
+        assert_eq!(load.stats().await.unwrap().total().memory, 0);
+    }
+
+    #[tokio::test]
+    async fn multi_append_keeps_the_order_of_one_id() {
+        let load = LocalSetLoad::new();
+        let pairs = (0..20u8)
+            .map(|i| match i % 3 {
+                0 => (1, vec![i]),
+                _ => (2 + i as Id, vec![i]),
+            })
+            .collect();
+        let answers = load.multi_append(pairs).await.unwrap();
+        assert_eq!(answers.len(), 20);
+        assert!(answers.iter().all(Result::is_ok));
+
+        let page = load.scan(1..=1, 1).await.unwrap();
+        let expected: Vec<u8> = (0..20).step_by(3).collect();
+        assert_eq!(page.items, [(1, expected)]);
+        let got = load.multi_get(&[4, 1, 4, 99], 1).await.unwrap();
+        assert_eq!(got[0].as_ref().unwrap(), &vec![2]);
+        assert_eq!(got[1].as_ref().unwrap().len(), 1);
+        assert_eq!(got[2].as_ref().unwrap(), &vec![2]);
+        assert!(matches!(got[3], Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn retention_and_wal_together() {
+        let dir = std::env::temp_dir().join(format!("local-set-load-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let load = LocalSetLoad::new()
+            .with_retention(Duration::from_secs(3600))
+            .unwrap()
+            .with_wal(&dir, WalOptions::default())
+            .unwrap();
+        load.append(0, vec![0; 16]).await.unwrap();
+        load.set_retention(1, Some(Duration::ZERO)).await.unwrap();
+        load.append(1, vec![1; 16]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(matches!(load.get(1, 16).await, Err(Error::NotFound)));
+        drop(load);
+
+        // Retention isn't kept across restarts, so id 1 is back.
+        let load = LocalSetLoad::new()
+            .with_wal(&dir, WalOptions::default())
+            .unwrap();
+        assert_eq!(load.scan(.., 10).await.unwrap().items.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,15 @@
+use cache::CacheStats;
+
+/// What a load holds, taken shard by shard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadStats {
+    /// Caches of each shard summed, in shard order.
+    pub shards: Vec<CacheStats>,
+}
+
+impl LoadStats {
+    /// Sums of all shards, with items of all in id order.
+    pub fn total(&self) -> CacheStats {
+        self.shards.iter().cloned().sum()
+    }
+}
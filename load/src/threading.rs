@@ -1,40 +1,404 @@
-use cache::{Bytes, Cache, Id};
+use cache::{
+    Bytes, Cache, Fragmentation, Id, Point, ScanPage, SharedBytes, SharedPool, Wal, WalOptions,
+    WalRecord,
+};
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::{
+    bounds, is_data_dir, restore_cache, snapshot_cache, snapshot_path, wal_path, Error, LoadStats,
+    COMPACT_ITEMS, SWEEP_INTERVAL,
+};
 
 const SHARD_NUM: usize = 128;
 
+type ShardCache = Cache<Id, Bytes, SharedPool>;
+
+/// A record logged, with its log still locked.
+struct Logged<'a> {
+    wal: MutexGuard<'a, Wal>,
+    seq: u64,
+}
+
+/// Unlock the log of a write applied, then wait until the log acknowledges
+/// it, so other writes of the shard go on while it's synced.
+fn acknowledged(logged: Option<Logged<'_>>) -> Result<(), Error> {
+    if let Some(Logged { wal, seq }) = logged {
+        let durable = wal.durable(seq);
+        drop(wal);
+        durable.wait()?;
+    }
+    Ok(())
+}
+
 pub struct ThreadingLoad {
-    shards: Vec<Cache>,
+    shards: Arc<Vec<ShardCache>>,
+    /// Write-ahead log of each shard, empty without one.
+    wals: Arc<Vec<Mutex<Wal>>>,
+    data_dir: Option<PathBuf>,
 }
 
 impl ThreadingLoad {
+    /// Expired blocks are swept from all shards by a background thread, which
+    /// compacts a few items after each sweep, until the load is dropped.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let mut shards = Vec::with_capacity(SHARD_NUM);
-        shards.resize_with(SHARD_NUM, Default::default);
-        Self { shards }
+        shards.resize_with(SHARD_NUM, || {
+            Cache::default().with_block_pool(SharedPool::default())
+        });
+        let shards = Arc::new(shards);
+
+        let weak = Arc::downgrade(&shards);
+        thread::spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            match weak.upgrade() {
+                Some(shards) => shards.iter().for_each(|shard| {
+                    let _ = shard.sweep();
+                    let _ = shard.compact(COMPACT_ITEMS);
+                }),
+                None => break,
+            }
+        });
+
+        Self {
+            shards,
+            wals: Default::default(),
+            data_dir: None,
+        }
+    }
+
+    /// Drop blocks not written for `ttl`, unless the id has its own
+    /// retention, see [`ThreadingLoad::set_retention`].
+    pub fn with_retention(self, ttl: Duration) -> Result<Self, Error> {
+        for shard in self.shards.iter() {
+            shard.set_default_retention(Some(ttl))?;
+        }
+        Ok(self)
+    }
+
+    /// Keep data in `dir` across restarts. Each shard restores its snapshot
+    /// from `dir` and replays its write-ahead log on top, then logs every
+    /// write. Writes return once the log acknowledges them, as its
+    /// [`FsyncPolicy`](cache::FsyncPolicy) says. Snapshots into `dir` truncate
+    /// the logs. Call it before writing, writes before aren't logged.
+    pub fn with_wal(self, dir: impl AsRef<Path>, options: WalOptions) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let wals = self
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(index, shard)| {
+                let log_seq = restore_cache(shard, &snapshot_path(dir, index, 0))?;
+                let (wal, records) = Wal::open(wal_path(dir, index), options.clone())?;
+                for (seq, record) in records {
+                    // Records before a crash between a checkpoint's snapshot
+                    // and deleting the log are in the snapshot already.
+                    if seq >= log_seq {
+                        record.apply(shard)?;
+                    }
+                }
+                Ok(Mutex::new(wal))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let wals = Arc::new(wals);
+
+        let weak = Arc::downgrade(&wals);
+        let delay = options.group_delay();
+        // A failed commit keeps its records buffered, the next write that
+        // commits them reports the error.
+        thread::spawn(move || loop {
+            thread::sleep(delay);
+            match weak.upgrade() {
+                Some(wals) => wals.iter().for_each(|wal| {
+                    if let Ok(mut wal) = wal.lock() {
+                        let _ = wal.commit();
+                    }
+                }),
+                None => break,
+            }
+        });
+
+        Ok(Self {
+            wals,
+            data_dir: Some(fs::canonicalize(dir)?),
+            ..self
+        })
+    }
+
+    /// Set retention of `id`, overriding the load's. `None` keeps it
+    /// forever. The setting goes with the id, and isn't kept across
+    /// restarts.
+    pub fn set_retention(&self, id: Id, ttl: Option<Duration>) -> Result<(), Error> {
+        Ok(self.shards[self.shard_id(id)].set_retention(id, ttl)?)
+    }
+
+    pub fn append(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
+        let shard = self.shard_id(id);
+        let logged = self.log(shard, WalRecord::Append(id, &bytes))?;
+        self.shards[shard].append(id, bytes)?;
+        acknowledged(logged)
+    }
+
+    /// Blocks read are checked against their checksum.
+    pub fn get(&self, id: Id, size: usize) -> Result<Bytes, Error> {
+        Ok(self.shards[self.shard_id(id)].get(&id, size)?)
+    }
+
+    /// Like [`ThreadingLoad::get`], without copying the cached bytes.
+    pub fn get_shared(&self, id: Id, size: usize) -> Result<SharedBytes, Error> {
+        Ok(self.shards[self.shard_id(id)].get_shared(&id, size)?)
+    }
+
+    /// Drop everything of `id`.
+    pub fn remove(&self, id: Id) -> Result<(), Error> {
+        let shard = self.shard_id(id);
+        let logged = self.log(shard, WalRecord::Remove(id))?;
+        self.shards[shard].remove(&id)?;
+        acknowledged(logged)
+    }
+
+    /// Keep the first `len` bytes of `id`.
+    pub fn truncate(&self, id: Id, len: usize) -> Result<(), Error> {
+        let shard = self.shard_id(id);
+        let logged = self.log(shard, WalRecord::Truncate(id, len))?;
+        self.shards[shard].truncate(&id, len)?;
+        acknowledged(logged)
+    }
+
+    pub fn replace(&self, id: Id, bytes: Bytes) -> Result<(), Error> {
+        let shard = self.shard_id(id);
+        let logged = self.log(shard, WalRecord::Replace(id, &bytes))?;
+        self.shards[shard].replace(id, bytes)?;
+        acknowledged(logged)
     }
 
-    pub fn append(&self, id: Id, bytes: Bytes) {
-        let bytes = Self::calculation(bytes);
-        self.shards[self.shard_id(id)].append(id, bytes);
+    pub fn append_point(&self, id: Id, timestamp: i64, value: f64) -> Result<(), Error> {
+        let shard = self.shard_id(id);
+        let logged = self.log(shard, WalRecord::AppendPoint(id, timestamp, value))?;
+        self.shards[shard].append_point(id, timestamp, value)?;
+        acknowledged(logged)
     }
 
-    pub fn get(&self, id: Id, size: usize) -> Option<Bytes> {
-        self.shards[self.shard_id(id)]
-            .get(id, size)
-            .map(Self::calculation)
+    /// Points of `id` with timestamp in `[start, end)`.
+    pub fn query(&self, id: Id, start: i64, end: i64) -> Result<Vec<Point>, Error> {
+        Ok(self.shards[self.shard_id(id)].query(&id, start, end)?)
+    }
+
+    /// Ids in `range` and everything appended to them, at most `limit` in id
+    /// order across all shards. Pass the returned cursor as range to get the
+    /// next page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<Id>,
+        limit: usize,
+    ) -> Result<ScanPage<Id, Bytes>, Error> {
+        let pages = self
+            .shards
+            .iter()
+            .map(|shard| shard.scan(bounds(&range), limit))
+            .collect::<Result<_, _>>()?;
+        Ok(ScanPage::merge(pages, &range, limit))
+    }
+
+    /// Compact up to `max_items` items of every shard, going on where the
+    /// previous call stopped. Returns the bytes released.
+    pub fn compact(&self, max_items: usize) -> Result<usize, Error> {
+        let mut freed = 0;
+        for shard in self.shards.iter() {
+            freed += shard.compact(max_items)?;
+        }
+        Ok(freed)
+    }
+
+    /// How full the blocks of all shards are.
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        let mut fragmentation = Fragmentation::default();
+        for shard in self.shards.iter() {
+            fragmentation = fragmentation + shard.fragmentation()?;
+        }
+        Ok(fragmentation)
+    }
+
+    /// Counters, fragmentation and items of every shard.
+    pub fn stats(&self) -> Result<LoadStats, Error> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.stats())
+            .collect::<Result<_, _>>()?;
+        Ok(LoadStats { shards })
+    }
+
+    /// Snapshot every shard into `dir`, shards split over one thread pinned
+    /// to each core. A snapshot into the data directory also checkpoints the
+    /// write-ahead logs.
+    pub fn snapshot(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let checkpoint = is_data_dir(self.data_dir.as_deref(), dir);
+        self.for_each_shard_per_core(|index, shard| {
+            let path = snapshot_path(dir, index, 0);
+            match self.wals.get(index) {
+                Some(wal) if checkpoint => wal
+                    .lock()?
+                    .checkpoint(|log_seq| snapshot_cache(shard, &path, log_seq)),
+                _ => snapshot_cache(shard, &path, 0),
+            }
+        })
+    }
+
+    /// Restore shards from a snapshot written by [`ThreadingLoad::snapshot`]
+    /// into `dir`. Shards without a file are left as they are.
+    pub fn restore(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        self.for_each_shard_per_core(|index, shard| {
+            restore_cache(shard, &snapshot_path(dir, index, 0)).map(drop)
+        })
+    }
+
+    /// Log `record` if the shard has a write-ahead log. Writes of the shard
+    /// wait for the returned log to be unlocked, so they are applied in log
+    /// order.
+    fn log(&self, shard: usize, record: WalRecord<&[u8]>) -> Result<Option<Logged<'_>>, Error> {
+        let mut wal = match self.wals.get(shard) {
+            Some(wal) => wal.lock()?,
+            None => return Ok(None),
+        };
+        let seq = wal.log(&record)?;
+        Ok(Some(Logged { wal, seq }))
+    }
+
+    fn for_each_shard_per_core<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(usize, &ShardCache) -> Result<(), Error> + Sync,
+    {
+        let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+        let chunk = SHARD_NUM.div_ceil(core_ids.len().max(1));
+        let f = &f;
+        thread::scope(|scope| {
+            let handles = self
+                .shards
+                .chunks(chunk)
+                .enumerate()
+                .map(|(i, shards)| {
+                    let core_id = core_ids.get(i).cloned();
+                    scope.spawn(move || {
+                        if let Some(core_id) = core_id {
+                            core_affinity::set_for_current(core_id);
+                        }
+                        shards
+                            .iter()
+                            .enumerate()
+                            .try_for_each(|(j, shard)| f(i * chunk + j, shard))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap_or(Err(Error::ShardUnavailable)))
+        })
     }
 
     #[inline]
     fn shard_id(&self, id: Id) -> usize {
         id % SHARD_NUM
     }
+}
 
-    #[inline]
-    fn calculation(mut bytes: Bytes) -> Bytes {
-        let mut sum: u8 = 0;
-        bytes.iter().for_each(|x| sum = sum.wrapping_add(*x));
-        bytes[0] += sum;
-        bytes
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn writes_release_memory() {
+        let load = ThreadingLoad::new();
+        load.append(0, vec![1; 40_000]).unwrap();
+        load.append_point(0, 0, 1.0).unwrap();
+        assert!(load.stats().unwrap().total().memory > 0);
+        load.remove(0).unwrap();
+        assert_eq!(load.stats().unwrap().total().memory, 0);
+    }
+
+    #[test]
+    fn scan_pages_through_every_shard() {
+        let load = ThreadingLoad::new();
+        let ids: Vec<Id> = (0..3 * SHARD_NUM).map(|i| i * 5).collect();
+        for &id in ids.iter() {
+            load.append(id, vec![id as u8; 4]).unwrap();
+        }
+
+        let mut scanned = vec![];
+        let mut page = load.scan(.., 7).unwrap();
+        loop {
+            assert!(page.items.len() <= 7);
+            scanned.extend(page.items.iter().map(|(id, bytes)| {
+                assert_eq!(bytes, &vec![*id as u8; 4]);
+                *id
+            }));
+            match page.next {
+                Some(cursor) => page = load.scan(cursor, 7).unwrap(),
+                None => break,
+            }
+        }
+        assert_eq!(scanned, ids);
+    }
+
+    #[test]
+    fn sweeps_compact_items() {
+        let load = ThreadingLoad::new();
+        for id in 0..4 {
+            for i in 0..100 {
+                load.append(id, vec![i; 1000]).unwrap();
+            }
+            load.truncate(id, 50_000).unwrap();
+        }
+        let before = load.stats().unwrap().total();
+        assert_eq!(before.fragmentation.compactable, 4);
+
+        let deadline = Instant::now() + 3 * SWEEP_INTERVAL;
+        while load.fragmentation().unwrap().compactable > 0 {
+            assert!(Instant::now() < deadline, "items are not compacted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let after = load.stats().unwrap().total();
+        assert!(after.memory < before.memory);
+        assert_eq!(after.fragmentation.used, before.fragmentation.used);
+        let bytes: Vec<u8> = (0..50).flat_map(|i| vec![i; 1000]).collect();
+        assert_eq!(load.scan(3..=3, 1).unwrap().items, [(3, bytes)]);
+    }
+
+    #[test]
+    fn retention_and_wal_together() {
+        let dir = std::env::temp_dir().join(format!("threading-load-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let load = ThreadingLoad::new()
+            .with_retention(Duration::from_secs(3600))
+            .unwrap()
+            .with_wal(&dir, WalOptions::default())
+            .unwrap();
+        load.append(0, vec![0; 16]).unwrap();
+        load.set_retention(1, Some(Duration::ZERO)).unwrap();
+        load.append(1, vec![1; 16]).unwrap();
+        thread::sleep(Duration::from_millis(1));
+        assert!(matches!(load.get(1, 16), Err(Error::NotFound)));
+        assert_eq!(load.get(0, 16).unwrap(), vec![0; 16]);
+        drop(load);
+
+        let load = ThreadingLoad::new()
+            .with_wal(&dir, WalOptions::default())
+            .unwrap();
+        assert_eq!(load.get(0, 16).unwrap(), vec![0; 16]);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
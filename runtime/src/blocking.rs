@@ -0,0 +1,43 @@
+use crossbeam::channel::{unbounded, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Number of threads serving blocking jobs, shared by all runtimes.
+const BLOCKING_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static POOL: OnceLock<Sender<Job>> = OnceLock::new();
+
+fn pool() -> &'static Sender<Job> {
+    POOL.get_or_init(|| {
+        let (tx, rx) = unbounded::<Job>();
+        for _ in 0..BLOCKING_THREADS {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                while let Ok(job) = rx.recv() {
+                    job();
+                }
+            });
+        }
+        tx
+    })
+}
+
+/// Run `f` on a thread outside the pinned workers, so it can block without
+/// stalling other tasks.
+pub async fn spawn_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool()
+        .send(Box::new(move || {
+            let _ = tx.send(f());
+        }))
+        .unwrap();
+
+    rx.await.expect("blocking job panicked")
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use crate::runtime::Runtime;
+
+/// Why a broadcast closure didn't produce a value on one core.
+#[derive(Debug)]
+pub enum CoreError<E> {
+    /// The closure returned an error.
+    Failed(E),
+    /// The closure panicked.
+    Panicked,
+    /// The worker dropped the task without running it.
+    Aborted,
+}
+
+impl<E: fmt::Display> fmt::Display for CoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreError::Failed(e) => write!(f, "failed: {}", e),
+            CoreError::Panicked => write!(f, "panicked"),
+            CoreError::Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+/// Run `f`, catching its panic.
+pub(crate) fn call<T, E>(f: impl FnOnce() -> Result<T, E>) -> Result<T, CoreError<E>> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(CoreError::Failed(e)),
+        Err(_) => Err(CoreError::Panicked),
+    }
+}
+
+impl Runtime {
+    /// Run `map` on every worker concurrently, with the worker's index as
+    /// argument. Results are returned in worker order.
+    pub async fn broadcast<M, T, E>(&self, map: M) -> Vec<Result<T, CoreError<E>>>
+    where
+        M: Fn(usize) -> Result<T, E> + Send + Sync + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let map = Arc::new(map);
+        let mut rxs = Vec::with_capacity(self.len());
+        for index in 0..self.len() {
+            let (tx, rx) = oneshot::channel();
+            let map = map.clone();
+            self.spawn(index, async move {
+                let _ = tx.send(call(|| map(index)));
+            });
+            rxs.push(rx);
+        }
+
+        let mut results = Vec::with_capacity(rxs.len());
+        for rx in rxs {
+            results.push(rx.await.unwrap_or(Err(CoreError::Aborted)));
+        }
+        results
+    }
+
+    /// Run `map` on every worker concurrently and fold the results on the
+    /// caller with `reduce`, in worker order.
+    ///
+    /// If any worker fails, all failures are returned together with their
+    /// worker index instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the runtime has no worker.
+    pub async fn map_reduce<M, R, T, E>(
+        &self,
+        map: M,
+        mut reduce: R,
+    ) -> Result<T, Vec<(usize, CoreError<E>)>>
+    where
+        M: Fn(usize) -> Result<T, E> + Send + Sync + 'static,
+        R: FnMut(T, T) -> T,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        assert!(!self.is_empty(), "map_reduce on a runtime without worker");
+
+        let mut acc = None;
+        let mut errors = vec![];
+        for (index, result) in self.broadcast(map).await.into_iter().enumerate() {
+            match result {
+                Ok(value) => {
+                    acc = Some(match acc {
+                        Some(acc) => reduce(acc, value),
+                        None => value,
+                    })
+                }
+                Err(e) => errors.push((index, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(acc.unwrap())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime(workers: usize) -> Runtime {
+        Runtime::new(&vec![core_affinity::get_core_ids().unwrap()[0]; workers])
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn a_panicking_core_is_reported_beside_the_others() {
+        let runtime = runtime(3);
+        let results = block_on(runtime.broadcast(|index| match index {
+            1 => panic!("core {} panics", index),
+            _ => Ok::<_, String>(index * 10),
+        }));
+        assert!(matches!(results[0], Ok(0)));
+        assert!(matches!(results[1], Err(CoreError::Panicked)));
+        assert!(matches!(results[2], Ok(20)));
+
+        let reduced = block_on(runtime.map_reduce(
+            |index| match index {
+                0 => Err(format!("core {} fails", index)),
+                1 => panic!("core {} panics", index),
+                _ => Ok(index),
+            },
+            |a, b| a + b,
+        ));
+        match reduced.unwrap_err().as_slice() {
+            [(0, CoreError::Failed(e)), (1, CoreError::Panicked)] => {
+                assert_eq!(e, "core 0 fails")
+            }
+            errors => panic!("{:?}", errors),
+        }
+
+        // Workers keep running after a panic.
+        let sum = block_on(runtime.map_reduce(Ok::<_, String>, |a, b| a + b));
+        assert_eq!(sum.unwrap(), 3);
+    }
+}
@@ -0,0 +1,241 @@
+//! Async file I/O for tasks on runtime workers. Uses the worker's io_uring
+//! driver when the `uring` feature is enabled and io_uring is available,
+//! otherwise runs the blocking calls on the blocking pool.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::blocking::spawn_blocking;
+
+/// An open file. Operations take and give back owned buffers, since the
+/// kernel may still be using a buffer when the future is dropped.
+pub struct File {
+    std: Arc<std::fs::File>,
+}
+
+impl File {
+    /// Open a file in read-only mode.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<File> {
+        Self::open_with(path.as_ref().to_owned(), false).await
+    }
+
+    /// Open a file in write-only mode, creating it if it doesn't exist and
+    /// truncating it if it does.
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<File> {
+        Self::open_with(path.as_ref().to_owned(), true).await
+    }
+
+    async fn open_with(path: PathBuf, write: bool) -> io::Result<File> {
+        #[cfg(feature = "uring")]
+        if crate::uring::is_available() {
+            return uring::open(path, write).await.map(File::from_std);
+        }
+
+        spawn_blocking(move || {
+            OpenOptions::new()
+                .read(!write)
+                .write(write)
+                .create(write)
+                .truncate(write)
+                .open(path)
+        })
+        .await
+        .map(File::from_std)
+    }
+
+    fn from_std(std: std::fs::File) -> Self {
+        Self { std: Arc::new(std) }
+    }
+
+    /// Read at most `buf.len()` bytes at `pos` into `buf`. Returns the number
+    /// of bytes read along with the buffer.
+    pub async fn read_at(&self, buf: Vec<u8>, pos: u64) -> (io::Result<usize>, Vec<u8>) {
+        #[cfg(feature = "uring")]
+        if crate::uring::is_available() {
+            return uring::read_at(&self.std, buf, pos).await;
+        }
+
+        let std = self.std.clone();
+        spawn_blocking(move || {
+            let mut buf = buf;
+            (std.read_at(&mut buf, pos), buf)
+        })
+        .await
+    }
+
+    /// Write `buf` at `pos`. Returns the number of bytes written along with
+    /// the buffer.
+    pub async fn write_at(&self, buf: Vec<u8>, pos: u64) -> (io::Result<usize>, Vec<u8>) {
+        #[cfg(feature = "uring")]
+        if crate::uring::is_available() {
+            return uring::write_at(&self.std, buf, pos).await;
+        }
+
+        let std = self.std.clone();
+        spawn_blocking(move || (std.write_at(&buf, pos), buf)).await
+    }
+
+    /// Flush data and metadata to disk.
+    pub async fn sync_all(&self) -> io::Result<()> {
+        #[cfg(feature = "uring")]
+        if crate::uring::is_available() {
+            return uring::fsync(&self.std).await;
+        }
+
+        let std = self.std.clone();
+        spawn_blocking(move || std.sync_all()).await
+    }
+}
+
+#[cfg(feature = "uring")]
+mod uring {
+    use io_uring::{opcode, types};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::PathBuf;
+
+    use crate::uring::Op;
+
+    fn result(raw: i32) -> io::Result<usize> {
+        if raw < 0 {
+            Err(io::Error::from_raw_os_error(-raw))
+        } else {
+            Ok(raw as usize)
+        }
+    }
+
+    pub async fn open(path: PathBuf, write: bool) -> io::Result<std::fs::File> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let flags = if write {
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC
+        } else {
+            libc::O_RDONLY
+        };
+        let entry = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+            .flags(flags | libc::O_CLOEXEC)
+            .mode(0o644)
+            .build();
+
+        let (raw, _) = unsafe { Op::submit(entry, path) }
+            .map_err(|(e, _)| e)?
+            .await?;
+        let fd = result(raw)?;
+        Ok(unsafe { std::fs::File::from_raw_fd(fd as i32) })
+    }
+
+    pub async fn read_at(
+        file: &std::fs::File,
+        mut buf: Vec<u8>,
+        pos: u64,
+    ) -> (io::Result<usize>, Vec<u8>) {
+        let entry = opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .offset(pos)
+        .build();
+
+        match unsafe { Op::submit(entry, buf) } {
+            // The buffer is lost when the operation can't be reaped.
+            Ok(op) => match op.await {
+                Ok((raw, buf)) => (result(raw), buf),
+                Err(e) => (Err(e), Vec::new()),
+            },
+            Err((e, buf)) => (Err(e), buf),
+        }
+    }
+
+    pub async fn write_at(
+        file: &std::fs::File,
+        buf: Vec<u8>,
+        pos: u64,
+    ) -> (io::Result<usize>, Vec<u8>) {
+        let entry = opcode::Write::new(types::Fd(file.as_raw_fd()), buf.as_ptr(), buf.len() as u32)
+            .offset(pos)
+            .build();
+
+        match unsafe { Op::submit(entry, buf) } {
+            // The buffer is lost when the operation can't be reaped.
+            Ok(op) => match op.await {
+                Ok((raw, buf)) => (result(raw), buf),
+                Err(e) => (Err(e), Vec::new()),
+            },
+            Err((e, buf)) => (Err(e), buf),
+        }
+    }
+
+    pub async fn fsync(file: &std::fs::File) -> io::Result<()> {
+        let entry = opcode::Fsync::new(types::Fd(file.as_raw_fd())).build();
+        let (raw, _) = unsafe { Op::submit(entry, ()) }
+            .map_err(|(e, _)| e)?
+            .await?;
+        result(raw).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spawn_local, Runtime};
+    use std::sync::mpsc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("runtime-fs-{}-{}", name, std::process::id()))
+    }
+
+    /// Write two buffers to `path` and read them back, whole and in parts.
+    async fn round_trip(path: PathBuf) -> io::Result<()> {
+        let file = File::create(&path).await?;
+        let (written, buf) = file.write_at(b"hello ".to_vec(), 0).await;
+        assert_eq!((written?, buf), (6, b"hello ".to_vec()));
+        let (written, _) = file.write_at(b"world".to_vec(), 6).await;
+        assert_eq!(written?, 5);
+        file.sync_all().await?;
+
+        let file = File::open(&path).await?;
+        let (read, mut buf) = file.read_at(vec![0; 64], 0).await;
+        buf.truncate(read?);
+        assert_eq!(buf, b"hello world");
+        let (read, buf) = file.read_at(vec![0; 5], 6).await;
+        assert_eq!((read?, buf), (5, b"world".to_vec()));
+        let (read, _) = file.read_at(vec![0; 5], 64).await;
+        assert_eq!(read?, 0);
+        // Files opened to read can't be written.
+        let (written, _) = file.write_at(vec![0; 5], 0).await;
+        assert!(written.is_err());
+
+        std::fs::remove_file(&path)?;
+        let missing = File::open(&path).await.err().map(|e| e.kind());
+        assert_eq!(missing, Some(io::ErrorKind::NotFound));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_on_the_blocking_pool() {
+        // Outside workers there is no io_uring driver.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(round_trip(temp_path("blocking"))).unwrap();
+    }
+
+    #[test]
+    fn round_trip_on_a_worker() {
+        let runtime = Runtime::new(&[core_affinity::get_core_ids().unwrap()[0]]);
+        let (tx, rx) = mpsc::channel();
+        runtime.spawn(0, async move {
+            spawn_local(async move {
+                // Through io_uring, when it's enabled and the kernel has it.
+                tx.send(round_trip(temp_path("worker")).await).unwrap();
+            })
+        });
+        rx.recv().unwrap().unwrap();
+    }
+}
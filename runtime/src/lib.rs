@@ -1,4 +1,16 @@
+mod blocking;
+mod broadcast;
+pub mod fs;
+mod metrics;
 mod runtime;
+mod sharded;
+pub mod sync;
 mod task;
+#[cfg(feature = "uring")]
+mod uring;
 
-pub use runtime::Runtime;
+pub use blocking::spawn_blocking;
+pub use broadcast::CoreError;
+pub use metrics::{RuntimeMetrics, WorkerMetrics};
+pub use runtime::{spawn_local, Runtime};
+pub use sharded::{Service, Sharded};
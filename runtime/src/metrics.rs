@@ -0,0 +1,161 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+/// Counters of one worker, updated by the worker thread and by wakers.
+#[derive(Default)]
+pub(crate) struct WorkerCounters {
+    spawned: AtomicU64,
+    polls: AtomicU64,
+    local_wakes: AtomicU64,
+    remote_wakes: AtomicU64,
+    busy_ns: AtomicU64,
+    idle_ns: AtomicU64,
+    max_poll_ns: AtomicU64,
+}
+
+impl WorkerCounters {
+    #[inline]
+    pub fn incr_spawned(&self) {
+        self.spawned.fetch_add(1, Relaxed);
+    }
+
+    #[inline]
+    pub fn incr_polls(&self) {
+        self.polls.fetch_add(1, Relaxed);
+    }
+
+    #[inline]
+    pub fn incr_wakes(&self, local: bool) {
+        if local {
+            self.local_wakes.fetch_add(1, Relaxed);
+        } else {
+            self.remote_wakes.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Only called from the owning worker thread.
+    #[inline]
+    pub fn record_busy(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.busy_ns.fetch_add(nanos, Relaxed);
+        self.max_poll_ns.fetch_max(nanos, Relaxed);
+    }
+
+    /// Only called from the owning worker thread.
+    #[inline]
+    pub fn record_idle(&self, elapsed: Duration) {
+        self.idle_ns.fetch_add(elapsed.as_nanos() as u64, Relaxed);
+    }
+
+    pub fn snapshot(&self, queue_depth: usize) -> WorkerMetrics {
+        WorkerMetrics {
+            spawned: self.spawned.load(Relaxed),
+            polls: self.polls.load(Relaxed),
+            local_wakes: self.local_wakes.load(Relaxed),
+            remote_wakes: self.remote_wakes.load(Relaxed),
+            queue_depth,
+            busy: Duration::from_nanos(self.busy_ns.load(Relaxed)),
+            idle: Duration::from_nanos(self.idle_ns.load(Relaxed)),
+            max_poll: Duration::from_nanos(self.max_poll_ns.load(Relaxed)),
+        }
+    }
+}
+
+/// Point-in-time counters of one worker.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerMetrics {
+    /// Tasks spawned onto this worker.
+    pub spawned: u64,
+    /// Times a future on this worker has been polled.
+    pub polls: u64,
+    /// Wakes issued from this worker's own thread.
+    pub local_wakes: u64,
+    /// Wakes issued from any other thread.
+    pub remote_wakes: u64,
+    /// Tasks waiting in the queue when the snapshot was taken.
+    pub queue_depth: usize,
+    /// Time spent polling tasks.
+    pub busy: Duration,
+    /// Time spent waiting on an empty queue.
+    pub idle: Duration,
+    /// Longest single run of a task.
+    pub max_poll: Duration,
+}
+
+/// Point-in-time counters of all workers, indexed like the `core_ids` the
+/// runtime was built with.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeMetrics {
+    pub workers: Vec<WorkerMetrics>,
+}
+
+impl RuntimeMetrics {
+    /// Sum of all workers. `max_poll` is the maximum over workers.
+    pub fn total(&self) -> WorkerMetrics {
+        self.workers
+            .iter()
+            .fold(WorkerMetrics::default(), |acc, w| WorkerMetrics {
+                spawned: acc.spawned + w.spawned,
+                polls: acc.polls + w.polls,
+                local_wakes: acc.local_wakes + w.local_wakes,
+                remote_wakes: acc.remote_wakes + w.remote_wakes,
+                queue_depth: acc.queue_depth + w.queue_depth,
+                busy: acc.busy + w.busy,
+                idle: acc.idle + w.idle,
+                max_poll: acc.max_poll.max(w.max_poll),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Runtime;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::sync::oneshot;
+
+    /// Wait until `done` holds, counters are updated after each poll.
+    fn wait_until(runtime: &Runtime, done: impl Fn(&super::RuntimeMetrics) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&runtime.metrics()) {
+            assert!(Instant::now() < deadline, "{:?}", runtime.metrics());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn counters_move_with_tasks() {
+        let runtime = Runtime::new(&[core_affinity::get_core_ids().unwrap()[0]; 2]);
+        let (wake_tx, wake_rx) = oneshot::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        runtime.spawn(0, async move {
+            // Woken from this worker, then from the test's thread.
+            tokio::task::yield_now().await;
+            wake_rx.await.unwrap();
+            done_tx.send(()).unwrap();
+        });
+        wait_until(&runtime, |metrics| metrics.workers[0].polls >= 2);
+        wake_tx.send(()).unwrap();
+        done_rx.recv().unwrap();
+        wait_until(&runtime, |metrics| metrics.workers[0].polls >= 3);
+
+        let metrics = runtime.metrics();
+        let worker = &metrics.workers[0];
+        assert_eq!((worker.spawned, worker.polls), (1, 3));
+        assert_eq!((worker.local_wakes, worker.remote_wakes), (1, 1));
+        assert_eq!(worker.queue_depth, 0);
+        assert!(worker.busy > Duration::ZERO);
+        assert!(worker.max_poll <= worker.busy);
+        let idle = &metrics.workers[1];
+        assert_eq!(
+            (idle.spawned, idle.polls, idle.busy),
+            (0, 0, Duration::ZERO)
+        );
+
+        let total = metrics.total();
+        assert_eq!((total.spawned, total.polls), (1, 3));
+        assert_eq!(total.max_poll, worker.max_poll);
+    }
+}
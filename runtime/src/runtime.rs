@@ -1,40 +1,151 @@
 use core_affinity::CoreId;
-use crossbeam::channel::{unbounded, Sender};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::cell::RefCell;
 use std::future::Future;
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
+use crate::metrics::{RuntimeMetrics, WorkerCounters};
 use crate::task::ArcTask;
 
+thread_local! {
+    /// The worker running on current thread, `None` outside workers.
+    static CURRENT_WORKER: RefCell<Option<Worker>> = const { RefCell::new(None) };
+}
+
+/// Everything a task needs to reschedule itself onto its worker.
+#[derive(Clone)]
+pub(crate) struct Worker {
+    pub queue: Sender<ArcTask>,
+    pub counters: Arc<WorkerCounters>,
+}
+
+impl Worker {
+    /// Whether current thread is this worker's thread.
+    #[inline]
+    pub fn is_current(&self) -> bool {
+        CURRENT_WORKER
+            .try_with(|current| match &*current.borrow() {
+                Some(worker) => Arc::ptr_eq(&worker.counters, &self.counters),
+                None => false,
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Spawn a future that is not `Send` onto the worker of current thread. It
+/// will only be polled and dropped on this worker.
+///
+/// # Panics
+///
+/// Panics if current thread is not a runtime worker.
+pub fn spawn_local<F>(task: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    let worker = CURRENT_WORKER
+        .with(|current| current.borrow().clone())
+        .expect("spawn_local called outside of runtime worker");
+    worker.counters.incr_spawned();
+    let task = ArcTask::new_local(task, worker.clone());
+    worker.queue.send(task).unwrap();
+}
+
 pub struct Runtime {
-    queues: Vec<Sender<ArcTask>>,
+    workers: Vec<Worker>,
 }
 
 impl Runtime {
     pub fn new(core_ids: &[CoreId]) -> Self {
-        let mut queues = Vec::with_capacity(core_ids.len());
+        let mut workers = Vec::with_capacity(core_ids.len());
         for core_id in core_ids {
             let (tx, rx) = unbounded::<ArcTask>();
-            queues.push(tx);
+            let counters = Arc::new(WorkerCounters::default());
+            let worker = Worker {
+                queue: tx,
+                counters: counters.clone(),
+            };
+            workers.push(worker.clone());
             let core_id = core_id.to_owned();
             thread::spawn(move || {
                 core_affinity::set_for_current(core_id);
+                CURRENT_WORKER.with(|current| *current.borrow_mut() = Some(worker));
+                #[cfg(feature = "uring")]
+                crate::uring::install();
                 loop {
-                    while let Ok(task) = rx.recv() {
+                    let mut idle_since = Instant::now();
+                    while let Some(task) = next_task(&rx) {
+                        let start = Instant::now();
+                        counters.record_idle(start - idle_since);
                         unsafe { task.poll() }
+                        idle_since = Instant::now();
+                        counters.record_busy(idle_since - start);
                     }
                 }
             });
         }
 
-        Self { queues }
+        Self { workers }
     }
 
     pub fn spawn<F>(&self, index: usize, task: F)
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        self.queues[index]
-            .send(ArcTask::new(task, self.queues[index].clone()))
+        let worker = &self.workers[index];
+        worker.counters.incr_spawned();
+        worker
+            .queue
+            .send(ArcTask::new(task, worker.clone()))
             .unwrap();
     }
+
+    /// Number of workers.
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Take a snapshot of every worker's counters.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        RuntimeMetrics {
+            workers: self
+                .workers
+                .iter()
+                .map(|worker| worker.counters.snapshot(worker.queue.len()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(not(feature = "uring"))]
+#[inline]
+fn next_task(rx: &Receiver<ArcTask>) -> Option<ArcTask> {
+    rx.recv().ok()
+}
+
+/// Wait on the queue in short slices while there are io_uring operations in
+/// flight, reaping completions between them.
+#[cfg(feature = "uring")]
+#[inline]
+fn next_task(rx: &Receiver<ArcTask>) -> Option<ArcTask> {
+    use crossbeam::channel::RecvTimeoutError;
+    use std::time::Duration;
+
+    const REAP_INTERVAL: Duration = Duration::from_micros(50);
+
+    loop {
+        if crate::uring::reap() == 0 {
+            return rx.recv().ok();
+        }
+        match rx.recv_timeout(REAP_INTERVAL) {
+            Ok(task) => return Some(task),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
 }
@@ -0,0 +1,301 @@
+use crossbeam::channel::bounded;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use crate::broadcast::{call, CoreError};
+use crate::runtime::{spawn_local, Runtime};
+
+/// A service that has one instance on each worker of a [`Sharded`].
+pub trait Service: 'static {
+    /// Called on the owning worker by [`Sharded::stop`], before the instance
+    /// is dropped.
+    fn shutdown(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+        Box::pin(async {})
+    }
+}
+
+/// Pointer to an instance. Only dereferenced on the instance's own worker.
+struct Instance<S>(*mut S);
+
+unsafe impl<S> Send for Instance<S> {}
+unsafe impl<S> Sync for Instance<S> {}
+
+impl<S> Clone for Instance<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for Instance<S> {}
+
+impl<S> Instance<S> {
+    /// # Safety
+    ///
+    /// Must be called on the owning worker, and the reference must not be
+    /// held across an await point.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get(&self) -> &mut S {
+        &mut *self.0
+    }
+}
+
+/// One instance of `S` per worker of a [`Runtime`]. Every instance is created,
+/// used and dropped on its own worker, so `S` needs neither `Send` nor `Sync`.
+pub struct Sharded<S: Service> {
+    runtime: Arc<Runtime>,
+    instances: Vec<Instance<S>>,
+}
+
+impl<S: Service> Sharded<S> {
+    /// Build one instance on each worker with `factory`, which takes the
+    /// worker's index. Blocks until all instances are built, so don't call it
+    /// from a worker of `runtime`.
+    pub fn new<F>(runtime: Arc<Runtime>, factory: F) -> Self
+    where
+        F: Fn(usize) -> S + Send + Sync + 'static,
+    {
+        let factory = Arc::new(factory);
+        let (tx, rx) = bounded(runtime.len());
+        for index in 0..runtime.len() {
+            let factory = factory.clone();
+            let tx = tx.clone();
+            runtime.spawn(index, async move {
+                let instance = Instance(Box::into_raw(Box::new(factory(index))));
+                tx.send((index, instance)).unwrap();
+            });
+        }
+
+        let mut instances = vec![None; runtime.len()];
+        for _ in 0..runtime.len() {
+            let (index, instance) = rx.recv().expect("shard worker dropped the task");
+            instances[index] = Some(instance);
+        }
+        let instances = instances.into_iter().map(Option::unwrap).collect();
+
+        Self { runtime, instances }
+    }
+
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+
+    /// Number of instances, equals to the number of workers.
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// Run `f` on the instance of worker `index`.
+    pub async fn invoke_on<F, R>(&self, index: usize, f: F) -> R
+    where
+        F: FnOnce(&mut S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let instance = self.instances[index];
+        self.runtime.spawn(index, async move {
+            let _ = tx.send(f(unsafe { instance.get() }));
+        });
+
+        rx.await.expect("shard worker dropped the task")
+    }
+
+    /// Run `f` on every instance concurrently. Results are returned in worker
+    /// order.
+    pub async fn invoke_on_all<F, R>(&self, f: F) -> Vec<R>
+    where
+        F: Fn(usize, &mut S) -> R + Send + Sync + 'static,
+        R: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let mut rxs = Vec::with_capacity(self.len());
+        for (index, instance) in self.instances.iter().copied().enumerate() {
+            let (tx, rx) = oneshot::channel();
+            let f = f.clone();
+            self.runtime.spawn(index, async move {
+                let _ = tx.send(f(index, unsafe { instance.get() }));
+            });
+            rxs.push(rx);
+        }
+
+        let mut results = Vec::with_capacity(rxs.len());
+        for rx in rxs {
+            results.push(rx.await.expect("shard worker dropped the task"));
+        }
+        results
+    }
+
+    /// Like [`Sharded::invoke_on`], but a panic of `f` or a dropped task is
+    /// returned as an error instead of panicking.
+    pub async fn try_invoke_on<F, T, E>(&self, index: usize, f: F) -> Result<T, CoreError<E>>
+    where
+        F: FnOnce(&mut S) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let instance = self.instances[index];
+        self.runtime.spawn(index, async move {
+            let _ = tx.send(call(|| f(unsafe { instance.get() })));
+        });
+
+        rx.await.unwrap_or(Err(CoreError::Aborted))
+    }
+
+    /// Like [`Sharded::invoke_on_all`], but a panic of `f` or a dropped task
+    /// is returned as the instance's error instead of panicking.
+    pub async fn try_invoke_on_all<F, T, E>(&self, f: F) -> Vec<Result<T, CoreError<E>>>
+    where
+        F: Fn(usize, &mut S) -> Result<T, E> + Send + Sync + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let mut rxs = Vec::with_capacity(self.len());
+        for (index, instance) in self.instances.iter().copied().enumerate() {
+            let (tx, rx) = oneshot::channel();
+            let f = f.clone();
+            self.runtime.spawn(index, async move {
+                let _ = tx.send(call(|| f(index, unsafe { instance.get() })));
+            });
+            rxs.push(rx);
+        }
+
+        let mut results = Vec::with_capacity(rxs.len());
+        for rx in rxs {
+            results.push(rx.await.unwrap_or(Err(CoreError::Aborted)));
+        }
+        results
+    }
+
+    /// Shut down and drop instances one by one in worker order. Each
+    /// instance's [`Service::shutdown`] is finished before the next starts.
+    pub async fn stop(mut self) {
+        for (index, instance) in self.instances.drain(..).enumerate() {
+            let (tx, rx) = oneshot::channel();
+            self.runtime.spawn(index, async move {
+                spawn_local(async move {
+                    let mut instance = unsafe { Box::from_raw(instance.0) };
+                    instance.shutdown().await;
+                    drop(instance);
+                    let _ = tx.send(());
+                });
+            });
+            let _ = rx.await;
+        }
+    }
+}
+
+impl<S: Service> Drop for Sharded<S> {
+    /// Drop instances that are not stopped, without calling their shutdown
+    /// hook.
+    fn drop(&mut self) {
+        for (index, instance) in self.instances.drain(..).enumerate() {
+            self.runtime.spawn(index, async move {
+                drop(unsafe { Box::from_raw(instance.0) });
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Shutdown(usize, ThreadId),
+        Drop(usize, ThreadId),
+    }
+
+    /// Records its lifecycle. Holds an `Rc`, so it's neither `Send` nor
+    /// `Sync`.
+    struct Recorder {
+        index: usize,
+        events: Arc<Mutex<Vec<Event>>>,
+        _local: Rc<()>,
+    }
+
+    impl Service for Recorder {
+        fn shutdown(&mut self) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                let event = Event::Shutdown(self.index, thread::current().id());
+                self.events.lock().unwrap().push(event);
+            })
+        }
+    }
+
+    impl Drop for Recorder {
+        fn drop(&mut self) {
+            let event = Event::Drop(self.index, thread::current().id());
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    /// Instances of `Recorder` on 3 workers, and the thread of each worker.
+    fn recorders(events: &Arc<Mutex<Vec<Event>>>) -> (Sharded<Recorder>, Vec<ThreadId>) {
+        let runtime = Arc::new(Runtime::new(
+            &[core_affinity::get_core_ids().unwrap()[0]; 3],
+        ));
+        let events = events.clone();
+        let sharded = Sharded::new(runtime, move |index| Recorder {
+            index,
+            events: events.clone(),
+            _local: Rc::new(()),
+        });
+        let threads = block_on(sharded.invoke_on_all(|index, recorder| {
+            assert_eq!(recorder.index, index);
+            thread::current().id()
+        }));
+        (sharded, threads)
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn stop_shuts_down_and_drops_in_worker_order() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let (sharded, threads) = recorders(&events);
+        let thread = block_on(sharded.invoke_on(1, |_| thread::current().id()));
+        assert_eq!(thread, threads[1]);
+        assert!(events.lock().unwrap().is_empty());
+
+        block_on(sharded.stop());
+        let expected: Vec<_> = (0..3)
+            .flat_map(|i| vec![Event::Shutdown(i, threads[i]), Event::Drop(i, threads[i])])
+            .collect();
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn drop_drops_on_each_worker_without_shutdown() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let (sharded, threads) = recorders(&events);
+        drop(sharded);
+        while events.lock().unwrap().len() < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut events = std::mem::take(&mut *events.lock().unwrap());
+        events.sort_by_key(|event| match event {
+            Event::Shutdown(i, _) | Event::Drop(i, _) => *i,
+        });
+        let expected: Vec<_> = (0..3).map(|i| Event::Drop(i, threads[i])).collect();
+        assert_eq!(events, expected);
+    }
+}
@@ -0,0 +1,58 @@
+//! Synchronization primitives for tasks on the same worker. They are `!Send`
+//! and built on `Cell`/`RefCell`, so they cost no atomic operation.
+//!
+//! They only rely on [`Waker`](std::task::Waker), and work with any executor
+//! as long as all users stay on one thread.
+
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+
+/// The other half of a channel is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+/// The receiver is dropped. Carries back the value that is not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[cfg(test)]
+mod testing {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    /// A waker counting how many times it's woken.
+    #[derive(Default)]
+    pub struct Wakes(AtomicUsize);
+
+    impl Wakes {
+        pub fn new() -> Arc<Self> {
+            Arc::default()
+        }
+
+        pub fn count(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Poll `future` once with `wakes` as its waker.
+    pub fn poll<F: Future + Unpin>(future: &mut F, wakes: &Arc<Wakes>) -> Poll<F::Output> {
+        let waker = Waker::from(wakes.clone());
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+}
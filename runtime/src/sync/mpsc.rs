@@ -0,0 +1,180 @@
+//! An unbounded multi-producer, single-consumer channel.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use super::SendError;
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    rx_waker: Option<Waker>,
+    senders: usize,
+    rx_dropped: bool,
+}
+
+pub struct UnboundedSender<T> {
+    inner: Rc<RefCell<Inner<T>>>,
+}
+
+pub struct UnboundedReceiver<T> {
+    inner: Rc<RefCell<Inner<T>>>,
+}
+
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, UnboundedReceiver<T>) {
+    let inner = Rc::new(RefCell::new(Inner {
+        queue: VecDeque::new(),
+        rx_waker: None,
+        senders: 1,
+        rx_dropped: false,
+    }));
+
+    (
+        UnboundedSender {
+            inner: inner.clone(),
+        },
+        UnboundedReceiver { inner },
+    )
+}
+
+impl<T> UnboundedSender<T> {
+    /// Send `value`, or give it back if the receiver is dropped.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let waker = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.rx_dropped {
+                return Err(SendError(value));
+            }
+            inner.queue.push_back(value);
+            inner.rx_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().rx_dropped
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.inner.borrow_mut().senders += 1;
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.inner.borrow_mut();
+            inner.senders -= 1;
+            if inner.senders == 0 {
+                inner.rx_waker.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> UnboundedReceiver<T> {
+    /// Receive the next value. Returns `None` once all senders are dropped
+    /// and the queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(value) = inner.queue.pop_front() {
+            Poll::Ready(Some(value))
+        } else if inner.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            inner.rx_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Receive a value without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.inner.borrow_mut().queue.pop_front()
+    }
+}
+
+impl<T> Drop for UnboundedReceiver<T> {
+    fn drop(&mut self) {
+        let queue = {
+            let mut inner = self.inner.borrow_mut();
+            inner.rx_dropped = true;
+            inner.rx_waker = None;
+            std::mem::take(&mut inner.queue)
+        };
+        // Values may hold senders of this channel, drop them out of borrow.
+        drop(queue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::Wakes;
+
+    fn poll_recv<T>(
+        rx: &mut UnboundedReceiver<T>,
+        wakes: &std::sync::Arc<Wakes>,
+    ) -> Poll<Option<T>> {
+        let waker = Waker::from(wakes.clone());
+        rx.poll_recv(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn values_come_in_order() {
+        let (tx, mut rx) = unbounded_channel();
+        let wakes = Wakes::new();
+        assert!(poll_recv(&mut rx, &wakes).is_pending());
+        let other = tx.clone();
+        tx.send(0).unwrap();
+        other.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(wakes.count(), 1);
+        assert_eq!(poll_recv(&mut rx, &wakes), Poll::Ready(Some(0)));
+        assert_eq!(rx.try_recv(), Some(1));
+        assert_eq!(poll_recv(&mut rx, &wakes), Poll::Ready(Some(2)));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn channel_closes_once_every_sender_is_dropped() {
+        let (tx, mut rx) = unbounded_channel();
+        let wakes = Wakes::new();
+        let other = tx.clone();
+        tx.send(0).unwrap();
+        drop(tx);
+        assert_eq!(poll_recv(&mut rx, &wakes), Poll::Ready(Some(0)));
+        assert!(poll_recv(&mut rx, &wakes).is_pending());
+        drop(other);
+        assert_eq!(wakes.count(), 1);
+        assert_eq!(poll_recv(&mut rx, &wakes), Poll::Ready(None));
+    }
+
+    #[test]
+    fn dropped_receiver_closes_the_channel() {
+        let (tx, rx) = unbounded_channel();
+        tx.send(0).unwrap();
+        assert!(!tx.is_closed());
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+}
@@ -0,0 +1,98 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+
+use super::Semaphore;
+
+/// An async mutex, whose guard can be held across await points.
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.semaphore.acquire(1).await.forget();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire(1)?.forget();
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{poll, Wakes};
+    use std::task::Poll;
+
+    #[test]
+    fn lock_is_handed_over_in_fifo_order() {
+        let mutex = Mutex::new(vec![]);
+        let mut guard = mutex.try_lock().unwrap();
+        let wakes: Vec<_> = (0..3).map(|_| Wakes::new()).collect();
+        let mut locks: Vec<_> = (0..3).map(|_| Box::pin(mutex.lock())).collect();
+        for (lock, wakes) in locks.iter_mut().zip(&wakes) {
+            assert!(poll(lock, wakes).is_pending());
+        }
+        // A waiting lock dropped gives up its turn.
+        drop(locks.remove(1));
+
+        guard.push(0);
+        drop(guard);
+        assert_eq!(
+            wakes.iter().map(|w| w.count()).collect::<Vec<_>>(),
+            [1, 0, 0]
+        );
+        let mut guard = match poll(&mut locks[0], &wakes[0]) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("first lock is not granted"),
+        };
+        assert!(mutex.try_lock().is_none());
+        guard.push(1);
+        drop(guard);
+        assert_eq!(wakes[2].count(), 1);
+        match poll(&mut locks[1], &wakes[2]) {
+            Poll::Ready(mut guard) => guard.push(2),
+            Poll::Pending => panic!("last lock is not granted"),
+        }
+        drop(locks);
+        assert_eq!(mutex.into_inner(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+struct Waiter {
+    notified: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Set by `notify_one` when nobody is waiting, consumed by the next
+    /// `notified`.
+    permit: bool,
+    waiters: VecDeque<Rc<Waiter>>,
+}
+
+/// Notify one or all waiting tasks.
+#[derive(Default)]
+pub struct Notify {
+    state: RefCell<State>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+            done: false,
+        }
+    }
+
+    /// Wake the first waiting task. If none is waiting, the next call to
+    /// `notified` completes immediately.
+    pub fn notify_one(&self) {
+        let waker = {
+            let mut state = self.state.borrow_mut();
+            match state.waiters.pop_front() {
+                Some(waiter) => {
+                    waiter.notified.set(true);
+                    waiter.waker.borrow_mut().take()
+                }
+                None => {
+                    state.permit = true;
+                    None
+                }
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake all tasks that are waiting now. Leaves no permit behind.
+    pub fn notify_waiters(&self) {
+        let waiters = std::mem::take(&mut self.state.borrow_mut().waiters);
+        for waiter in waiters {
+            waiter.notified.set(true);
+            if let Some(waker) = waiter.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Future returned by [`Notify::notified`].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Rc<Waiter>>,
+    done: bool,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.waiter {
+            Some(waiter) => {
+                if !waiter.notified.get() {
+                    *waiter.waker.borrow_mut() = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+            None => {
+                let mut state = self.notify.state.borrow_mut();
+                if !state.permit {
+                    let waiter = Rc::new(Waiter {
+                        notified: Cell::new(false),
+                        waker: RefCell::new(Some(cx.waker().clone())),
+                    });
+                    state.waiters.push_back(waiter.clone());
+                    drop(state);
+                    self.waiter = Some(waiter);
+                    return Poll::Pending;
+                }
+                state.permit = false;
+            }
+        }
+
+        self.done = true;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) if !self.done => waiter,
+            _ => return,
+        };
+
+        if waiter.notified.get() {
+            // Pass the notification on instead of losing it.
+            self.notify.notify_one();
+        } else {
+            self.notify
+                .state
+                .borrow_mut()
+                .waiters
+                .retain(|w| !Rc::ptr_eq(w, &waiter));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{poll, Wakes};
+
+    #[test]
+    fn notify_one_wakes_waiters_in_fifo_order() {
+        let notify = Notify::new();
+        let wakes: Vec<_> = (0..3).map(|_| Wakes::new()).collect();
+        let mut waiters: Vec<_> = (0..3).map(|_| notify.notified()).collect();
+        for (waiter, wakes) in waiters.iter_mut().zip(&wakes) {
+            assert!(poll(waiter, wakes).is_pending());
+        }
+        for i in 0..3 {
+            notify.notify_one();
+            assert_eq!(wakes[i].count(), 1);
+            assert!(poll(&mut waiters[i], &wakes[i]).is_ready());
+            assert!(wakes[i + 1..].iter().all(|w| w.count() == 0));
+        }
+    }
+
+    #[test]
+    fn notifications_are_kept_until_waited() {
+        let notify = Notify::new();
+        let wakes = Wakes::new();
+        notify.notify_one();
+        assert!(poll(&mut notify.notified(), &wakes).is_ready());
+        assert!(poll(&mut notify.notified(), &wakes).is_pending());
+
+        // notify_waiters leaves nothing for later waiters.
+        let mut waiter = notify.notified();
+        assert!(poll(&mut waiter, &wakes).is_pending());
+        notify.notify_waiters();
+        assert!(poll(&mut waiter, &wakes).is_ready());
+        assert!(poll(&mut notify.notified(), &wakes).is_pending());
+    }
+
+    #[test]
+    fn dropped_waiters_pass_notifications_on() {
+        let notify = Notify::new();
+        let (a_wakes, b_wakes) = (Wakes::new(), Wakes::new());
+        let mut a = notify.notified();
+        let mut b = notify.notified();
+        assert!(poll(&mut a, &a_wakes).is_pending());
+        assert!(poll(&mut b, &b_wakes).is_pending());
+        notify.notify_one();
+        drop(a);
+        assert_eq!(b_wakes.count(), 1);
+        assert!(poll(&mut b, &b_wakes).is_ready());
+
+        // A waiter dropped before it's notified is forgotten.
+        let mut c = notify.notified();
+        assert!(poll(&mut c, &a_wakes).is_pending());
+        drop(c);
+        notify.notify_one();
+        assert!(poll(&mut notify.notified(), &a_wakes).is_ready());
+    }
+}
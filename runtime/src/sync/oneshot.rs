@@ -0,0 +1,138 @@
+//! A channel sending a single value.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use super::{RecvError, SendError};
+
+struct Inner<T> {
+    value: Option<T>,
+    rx_waker: Option<Waker>,
+    tx_dropped: bool,
+    rx_dropped: bool,
+}
+
+pub struct Sender<T> {
+    inner: Rc<RefCell<Inner<T>>>,
+}
+
+pub struct Receiver<T> {
+    inner: Rc<RefCell<Inner<T>>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Rc::new(RefCell::new(Inner {
+        value: None,
+        rx_waker: None,
+        tx_dropped: false,
+        rx_dropped: false,
+    }));
+
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, or give it back if the receiver is dropped.
+    pub fn send(self, value: T) -> Result<(), SendError<T>> {
+        let waker = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.rx_dropped {
+                return Err(SendError(value));
+            }
+            inner.value = Some(value);
+            inner.rx_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().rx_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.inner.borrow_mut();
+            inner.tx_dropped = true;
+            inner.rx_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        } else if inner.tx_dropped {
+            Poll::Ready(Err(RecvError))
+        } else {
+            inner.rx_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = {
+            let mut inner = self.inner.borrow_mut();
+            inner.rx_dropped = true;
+            inner.rx_waker = None;
+            inner.value.take()
+        };
+        drop(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{poll, Wakes};
+
+    #[test]
+    fn value_wakes_the_receiver() {
+        let (tx, mut rx) = channel();
+        let wakes = Wakes::new();
+        assert!(poll(&mut rx, &wakes).is_pending());
+        tx.send(1).unwrap();
+        assert_eq!(wakes.count(), 1);
+        assert_eq!(poll(&mut rx, &wakes), Poll::Ready(Ok(1)));
+    }
+
+    #[test]
+    fn dropped_sender_fails_the_receiver() {
+        let (tx, mut rx) = channel::<u32>();
+        let wakes = Wakes::new();
+        assert!(poll(&mut rx, &wakes).is_pending());
+        drop(tx);
+        assert_eq!(wakes.count(), 1);
+        assert_eq!(poll(&mut rx, &wakes), Poll::Ready(Err(RecvError)));
+    }
+
+    #[test]
+    fn dropped_receiver_gives_the_value_back() {
+        let (tx, rx) = channel();
+        assert!(!tx.is_closed());
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+}
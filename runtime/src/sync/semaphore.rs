@@ -0,0 +1,222 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+struct Waiter {
+    needed: usize,
+    granted: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+struct State {
+    permits: usize,
+    /// Served in FIFO order.
+    waiters: VecDeque<Rc<Waiter>>,
+}
+
+/// A counting semaphore that grants permits in FIFO order.
+pub struct Semaphore {
+    state: RefCell<State>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: RefCell::new(State {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.borrow().permits
+    }
+
+    /// Wait until `n` permits are available.
+    pub fn acquire(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            waiter: None,
+            done: false,
+        }
+    }
+
+    /// Take `n` permits if they are available and nobody is waiting.
+    pub fn try_acquire(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.borrow_mut();
+        if state.waiters.is_empty() && state.permits >= n {
+            state.permits -= n;
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Return `n` permits and grant them to waiters.
+    pub fn add_permits(&self, n: usize) {
+        let mut wakers = vec![];
+        {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+            state.permits += n;
+            while let Some(waiter) = state.waiters.front() {
+                if waiter.needed > state.permits {
+                    break;
+                }
+                state.permits -= waiter.needed;
+                waiter.granted.set(true);
+                if let Some(waker) = waiter.waker.borrow_mut().take() {
+                    wakers.push(waker);
+                }
+                state.waiters.pop_front();
+            }
+        }
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`].
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    waiter: Option<Rc<Waiter>>,
+    done: bool,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &self.waiter {
+            Some(waiter) => {
+                if !waiter.granted.get() {
+                    *waiter.waker.borrow_mut() = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+            None => {
+                let mut state = self.semaphore.state.borrow_mut();
+                if !state.waiters.is_empty() || state.permits < self.needed {
+                    let waiter = Rc::new(Waiter {
+                        needed: self.needed,
+                        granted: Cell::new(false),
+                        waker: RefCell::new(Some(cx.waker().clone())),
+                    });
+                    state.waiters.push_back(waiter.clone());
+                    drop(state);
+                    self.waiter = Some(waiter);
+                    return Poll::Pending;
+                }
+                state.permits -= self.needed;
+            }
+        }
+
+        self.done = true;
+        Poll::Ready(SemaphorePermit {
+            semaphore: self.semaphore,
+            permits: self.needed,
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) if !self.done => waiter,
+            _ => return,
+        };
+
+        if waiter.granted.get() {
+            self.semaphore.add_permits(self.needed);
+        } else {
+            let mut state = self.semaphore.state.borrow_mut();
+            state.waiters.retain(|w| !Rc::ptr_eq(w, &waiter));
+            drop(state);
+            // The removed waiter may have blocked smaller ones behind it.
+            self.semaphore.add_permits(0);
+        }
+    }
+}
+
+/// Permits taken from a [`Semaphore`], returned on drop.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keep the permits out of the semaphore.
+    pub fn forget(self) {
+        std::mem::forget(self)
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::testing::{poll, Wakes};
+
+    #[test]
+    fn permits_are_granted_in_fifo_order() {
+        let semaphore = Semaphore::new(0);
+        let (a_wakes, b_wakes) = (Wakes::new(), Wakes::new());
+        let mut a = semaphore.acquire(2);
+        let mut b = semaphore.acquire(1);
+        assert!(poll(&mut a, &a_wakes).is_pending());
+        assert!(poll(&mut b, &b_wakes).is_pending());
+
+        // b could take it, but waits behind a.
+        semaphore.add_permits(1);
+        assert_eq!(b_wakes.count(), 0);
+        assert!(poll(&mut b, &b_wakes).is_pending());
+        assert!(semaphore.try_acquire(1).is_none());
+
+        semaphore.add_permits(1);
+        assert_eq!((a_wakes.count(), b_wakes.count()), (1, 0));
+        let permit = match poll(&mut a, &a_wakes) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("a is not granted"),
+        };
+        drop(permit);
+        assert_eq!(b_wakes.count(), 1);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(poll(&mut b, &b_wakes).is_ready());
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn dropped_waiters_release_their_place_and_permits() {
+        let semaphore = Semaphore::new(1);
+        let (a_wakes, b_wakes) = (Wakes::new(), Wakes::new());
+        let mut a = semaphore.acquire(2);
+        let mut b = semaphore.acquire(1);
+        assert!(poll(&mut a, &a_wakes).is_pending());
+        assert!(poll(&mut b, &b_wakes).is_pending());
+        // Dropping a lets b take the permit it was blocking.
+        drop(a);
+        assert_eq!(b_wakes.count(), 1);
+        assert_eq!(semaphore.available_permits(), 0);
+
+        // b is granted but dropped before it's polled again: the permit
+        // goes back.
+        drop(b);
+        assert_eq!(semaphore.available_permits(), 1);
+        let permit = semaphore.try_acquire(1).unwrap();
+        permit.forget();
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+}
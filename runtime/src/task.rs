@@ -1,13 +1,14 @@
-use crossbeam::channel::Sender;
 use std::cell::UnsafeCell;
 use std::future::Future;
-use std::mem::{forget, ManuallyDrop};
+use std::mem::{forget, replace, transmute, ManuallyDrop};
 use std::pin::Pin;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering::{self, Relaxed};
 use std::sync::Arc;
 use std::task::{Context, RawWaker, RawWakerVTable, Waker};
 
+use crate::runtime::Worker;
+
 type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 const WAITING: u8 = 0; // --> POLLING
@@ -18,10 +19,25 @@ const COMPLETE: u8 = 3; // No transitions out
 /// default ordering
 const ORDERING: Ordering = Relaxed;
 
+type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
 struct Task {
     task: UnsafeCell<BoxFuture<'static, ()>>,
-    queue: Sender<ArcTask>,
+    worker: Worker,
     status: AtomicU8,
+    /// The future is not `Send` and must stay on `worker`'s thread.
+    local: bool,
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        // The last reference of a pending local task may be a waker held by
+        // other thread. Leak the future rather than drop it there.
+        if self.local && !self.worker.is_current() {
+            let future = replace(self.task.get_mut(), Box::pin(async {}));
+            forget(future);
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -31,14 +47,35 @@ unsafe impl Send for ArcTask {}
 
 impl ArcTask {
     #[inline]
-    pub fn new<F>(future: F, queue: Sender<ArcTask>) -> Self
+    pub(crate) fn new<F>(future: F, worker: Worker) -> Self
     where
         F: Future<Output = ()> + Send + 'static,
     {
+        Self::from_boxed(Box::pin(future), worker, false)
+    }
+
+    /// Create a task whose future is not `Send`. Must be called on `worker`'s
+    /// thread.
+    #[inline]
+    pub(crate) fn new_local<F>(future: F, worker: Worker) -> Self
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        debug_assert!(worker.is_current());
+        let future: LocalBoxFuture<'static, ()> = Box::pin(future);
+        // Safety: a task is only polled by the thread of its worker, and
+        // `Task::drop` never drops a local future on other threads.
+        let future: BoxFuture<'static, ()> = unsafe { transmute(future) };
+        Self::from_boxed(future, worker, true)
+    }
+
+    #[inline]
+    fn from_boxed(future: BoxFuture<'static, ()>, worker: Worker, local: bool) -> Self {
         let future = Arc::new(Task {
-            task: UnsafeCell::new(Box::pin(future)),
-            queue,
+            task: UnsafeCell::new(future),
+            worker,
             status: AtomicU8::new(WAITING),
+            local,
         });
         let future: *const Task = Arc::into_raw(future) as *const Task;
         unsafe { task(future) }
@@ -50,7 +87,11 @@ impl ArcTask {
         let waker = ManuallyDrop::new(waker(&*self.0));
         let mut cx = Context::from_waker(&waker);
         loop {
+            self.0.worker.counters.incr_polls();
             if Pin::new(&mut *self.0.task.get()).poll(&mut cx).is_ready() {
+                // Release the finished future here on the worker, wakers may
+                // keep the task alive for long.
+                *self.0.task.get() = Box::pin(async {});
                 break self.0.status.store(COMPLETE, ORDERING);
             }
             match self
@@ -90,6 +131,8 @@ unsafe fn drop_raw(this: *const ()) {
 #[inline]
 unsafe fn wake_raw(this: *const ()) {
     let task = task(this as *const Task);
+    let worker = &task.0.worker;
+    worker.counters.incr_wakes(worker.is_current());
     let mut status = task.0.status.load(ORDERING);
     loop {
         match status {
@@ -100,7 +143,7 @@ unsafe fn wake_raw(this: *const ()) {
                     .compare_exchange(WAITING, POLLING, ORDERING, ORDERING)
                 {
                     Ok(_) => {
-                        task.0.queue.send(clone_task(&*task.0)).unwrap();
+                        task.0.worker.queue.send(clone_task(&*task.0)).unwrap();
                         break;
                     }
                     Err(cur) => status = cur,
@@ -124,6 +167,8 @@ unsafe fn wake_raw(this: *const ()) {
 #[inline]
 unsafe fn wake_ref_raw(this: *const ()) {
     let task = ManuallyDrop::new(task(this as *const Task));
+    let worker = &task.0.worker;
+    worker.counters.incr_wakes(worker.is_current());
     let mut status = task.0.status.load(ORDERING);
     loop {
         match status {
@@ -134,7 +179,7 @@ unsafe fn wake_ref_raw(this: *const ()) {
                     .compare_exchange(WAITING, POLLING, ORDERING, ORDERING)
                 {
                     Ok(_) => {
-                        task.0.queue.send(clone_task(&*task.0)).unwrap();
+                        task.0.worker.queue.send(clone_task(&*task.0)).unwrap();
                         break;
                     }
                     Err(cur) => status = cur,
@@ -0,0 +1,341 @@
+//! Per-worker io_uring driver. Operations are submitted from tasks on the
+//! worker and their completions are reaped by the worker loop.
+
+use io_uring::{squeue, IoUring};
+use std::any::Any;
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::mem::forget;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+const RING_ENTRIES: u32 = 256;
+
+thread_local! {
+    static DRIVER: RefCell<Option<Driver>> = const { RefCell::new(None) };
+}
+
+enum Lifecycle {
+    Submitted,
+    Waiting(Waker),
+    Completed(i32),
+    /// The future is dropped. Keeps the resources alive until completion.
+    Ignored(#[allow(dead_code)] Box<dyn Any>),
+}
+
+struct Driver {
+    /// Tells the drivers of different workers apart.
+    id: usize,
+    ring: IoUring,
+    ops: Vec<Option<Lifecycle>>,
+    free: Vec<usize>,
+    inflight: usize,
+}
+
+impl Driver {
+    /// Once `entry` is queued it's in flight, even if submitting it fails:
+    /// the kernel may still pick it up, so [`Driver::reap`] submits it again.
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<usize> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.ops.push(None);
+                self.ops.len() - 1
+            }
+        };
+        let entry = entry.user_data(index as u64);
+
+        while unsafe { self.ring.submission().push(&entry).is_err() } {
+            if let Err(e) = self.ring.submit() {
+                self.free.push(index);
+                return Err(e);
+            }
+        }
+        self.ops[index] = Some(Lifecycle::Submitted);
+        self.inflight += 1;
+        let _ = self.ring.submit();
+        Ok(index)
+    }
+
+    fn reap(&mut self) {
+        if !self.ring.submission().is_empty() {
+            let _ = self.ring.submit();
+        }
+        let mut completion = self.ring.completion();
+        completion.sync();
+        for cqe in completion {
+            let index = cqe.user_data() as usize;
+            self.inflight -= 1;
+            match self.ops[index].take() {
+                Some(Lifecycle::Waiting(waker)) => {
+                    self.ops[index] = Some(Lifecycle::Completed(cqe.result()));
+                    waker.wake();
+                }
+                Some(Lifecycle::Submitted) => {
+                    self.ops[index] = Some(Lifecycle::Completed(cqe.result()))
+                }
+                Some(Lifecycle::Ignored(_)) => self.free.push(index),
+                Some(Lifecycle::Completed(_)) | None => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Set up a driver for current worker. Leaves it absent if io_uring is not
+/// available, in which case file I/O falls back to the blocking pool.
+pub(crate) fn install() {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    if let Ok(ring) = IoUring::new(RING_ENTRIES) {
+        DRIVER.with(|driver| {
+            *driver.borrow_mut() = Some(Driver {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                ring,
+                ops: vec![],
+                free: vec![],
+                inflight: 0,
+            })
+        });
+    }
+}
+
+/// Whether current thread has a usable driver.
+pub(crate) fn is_available() -> bool {
+    DRIVER
+        .try_with(|driver| driver.borrow().is_some())
+        .unwrap_or(false)
+}
+
+/// Reap completed operations. Returns the number of operations still in
+/// flight.
+pub(crate) fn reap() -> usize {
+    DRIVER.with(|driver| match &mut *driver.borrow_mut() {
+        Some(driver) => {
+            driver.reap();
+            driver.inflight
+        }
+        None => 0,
+    })
+}
+
+/// Run `f` with the driver of current thread, if it's the one `id` is of.
+fn with_driver<R>(id: usize, f: impl FnOnce(&mut Driver) -> R) -> Option<R> {
+    DRIVER
+        .try_with(|driver| match &mut *driver.borrow_mut() {
+            Some(driver) if driver.id == id => Some(f(driver)),
+            _ => None,
+        })
+        .ok()
+        .flatten()
+}
+
+/// An in-flight operation. `T` holds the resources (buffers, paths) the kernel
+/// is referring to and is given back on completion.
+pub(crate) struct Op<T: 'static> {
+    /// The driver the operation is submitted to.
+    driver: usize,
+    index: usize,
+    data: Option<T>,
+}
+
+impl<T: 'static> Op<T> {
+    /// Gives `data` back if the operation cannot be submitted.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer in `entry` must point into `data` or stay valid until
+    /// the operation completes.
+    pub unsafe fn submit(entry: squeue::Entry, data: T) -> Result<Self, (io::Error, T)> {
+        let pushed = DRIVER.with(|driver| match &mut *driver.borrow_mut() {
+            Some(driver) => driver.push(entry).map(|index| (driver.id, index)),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring is not available on current thread",
+            )),
+        });
+
+        match pushed {
+            Ok((driver, index)) => Ok(Self {
+                driver,
+                index,
+                data: Some(data),
+            }),
+            Err(e) => Err((e, data)),
+        }
+    }
+}
+
+impl<T: 'static + Unpin> Future for Op<T> {
+    /// Raw result of the operation and the resources. Fails if polled off the
+    /// worker that submitted it, the resources are then kept until the
+    /// operation is dropped, and leaked since the kernel may still refer to
+    /// them.
+    type Output = io::Result<(i32, T)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let index = self.index;
+        let result = with_driver(self.driver, |driver| match driver.ops[index].take() {
+            Some(Lifecycle::Completed(result)) => {
+                driver.free.push(index);
+                Some(result)
+            }
+            Some(Lifecycle::Submitted) | Some(Lifecycle::Waiting(_)) => {
+                driver.ops[index] = Some(Lifecycle::Waiting(cx.waker().clone()));
+                None
+            }
+            Some(Lifecycle::Ignored(_)) | None => unreachable!(),
+        });
+
+        match result {
+            Some(Some(result)) => Poll::Ready(Ok((result, self.data.take().unwrap()))),
+            Some(None) => Poll::Pending,
+            None => Poll::Ready(Err(io::Error::other(
+                "io_uring operation polled off the worker that submitted it",
+            ))),
+        }
+    }
+}
+
+impl<T: 'static> Drop for Op<T> {
+    fn drop(&mut self) {
+        let mut data = match self.data.take() {
+            Some(data) => Some(data),
+            None => return,
+        };
+        let index = self.index;
+        with_driver(self.driver, |driver| match driver.ops[index].take() {
+            Some(Lifecycle::Completed(_)) => {
+                driver.free.push(index);
+                drop(data.take());
+            }
+            _ => {
+                let data = Box::new(data.take().unwrap());
+                driver.ops[index] = Some(Lifecycle::Ignored(data));
+            }
+        });
+        // The driver is gone with its thread, or is not on current thread:
+        // the kernel may still refer to the resources.
+        forget(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io_uring::{opcode, types};
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::task::Waker;
+    use std::thread;
+
+    /// Poll `op` to completion, reaping between polls like a worker does.
+    fn wait<T: Unpin>(mut op: Op<T>) -> io::Result<(i32, T)> {
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(result) = Pin::new(&mut op).poll(&mut cx) {
+                return result;
+            }
+            reap();
+        }
+    }
+
+    /// Install a driver for current thread. Fails if the kernel has no
+    /// io_uring, and the test is skipped.
+    fn install_driver() -> bool {
+        install();
+        if !is_available() {
+            eprintln!("io_uring is not available, skipping");
+        }
+        is_available()
+    }
+
+    fn temp_file(name: &str) -> File {
+        let path =
+            std::env::temp_dir().join(format!("runtime-uring-{}-{}", name, std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        file
+    }
+
+    fn write(file: &File, buf: Vec<u8>, pos: u64) -> Op<Vec<u8>> {
+        let entry = opcode::Write::new(types::Fd(file.as_raw_fd()), buf.as_ptr(), buf.len() as u32)
+            .offset(pos)
+            .build();
+        unsafe { Op::submit(entry, buf) }
+            .map_err(|(e, _)| e)
+            .unwrap()
+    }
+
+    fn read(file: &File, mut buf: Vec<u8>, pos: u64) -> Op<Vec<u8>> {
+        let entry = opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .offset(pos)
+        .build();
+        unsafe { Op::submit(entry, buf) }
+            .map_err(|(e, _)| e)
+            .unwrap()
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        if !install_driver() {
+            return;
+        }
+        let file = temp_file("round-trip");
+        let (written, _) = wait(write(&file, b"hello world".to_vec(), 0)).unwrap();
+        assert_eq!(written, 11);
+        let (read, buf) = wait(read(&file, vec![0; 5], 6)).unwrap();
+        assert_eq!((read, buf), (5, b"world".to_vec()));
+        assert_eq!(reap(), 0);
+    }
+
+    #[test]
+    fn dropped_operations_free_their_slot_once_reaped() {
+        if !install_driver() {
+            return;
+        }
+        let file = temp_file("dropped");
+        wait(write(&file, vec![1; 4096], 0)).unwrap();
+        let ops: Vec<_> = (0..8).map(|i| read(&file, vec![0; 512], i * 512)).collect();
+        drop(ops);
+        while reap() > 0 {}
+
+        // Every slot is free again, and reused.
+        let free = DRIVER.with(|driver| driver.borrow().as_ref().unwrap().free.len());
+        assert_eq!(free, 8);
+        let (read, buf) = wait(read(&file, vec![0; 4], 0)).unwrap();
+        assert_eq!((read, buf), (4, vec![1; 4]));
+    }
+
+    #[test]
+    fn polling_off_the_submitting_thread_fails() {
+        if !install_driver() {
+            return;
+        }
+        let file = temp_file("off-thread");
+        let op = write(&file, vec![1; 16], 0);
+        let result = thread::spawn(move || {
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut op = op;
+            let result = Pin::new(&mut op).poll(&mut cx);
+            // The other thread's driver may still refer to the buffer.
+            forget(op);
+            result
+        })
+        .join()
+        .unwrap();
+        assert!(matches!(result, Poll::Ready(Err(_))));
+        while reap() > 0 {}
+    }
+}
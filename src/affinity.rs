@@ -44,6 +44,10 @@ fn main() {
     }
     println!("read cost {} ms", now.elapsed().as_millis());
 
+    for (core, metrics) in load.metrics().workers.iter().enumerate() {
+        println!("core {}: {:?}", core, metrics);
+    }
+
     if let Ok(report) = prof_guard.report().build() {
         let _ = std::fs::create_dir("flamegraph");
         let file = std::fs::File::create("flamegraph/affinity.svg").unwrap();
@@ -0,0 +1,68 @@
+//! Gorilla Compression Runner
+//!
+//! Encodes a few kinds of realistic series into `SeriesItem`, checks they
+//! decode back unchanged and prints the compression ratio of each.
+
+use cache::{Point, SeriesItem};
+use rand::random;
+use std::time::Instant;
+
+const POINT_NUM: usize = 1024 * 64;
+/// Scrape interval in milliseconds.
+const INTERVAL: i64 = 10_000;
+
+fn main() {
+    let start = 1_600_000_000_000;
+    let mut timestamp = start;
+    let mut gauge = 50.0;
+    let mut counter = 0.0;
+
+    let mut series: Vec<(&str, Vec<Point>)> = vec![
+        ("constant", vec![]),
+        ("counter", vec![]),
+        ("gauge", vec![]),
+        ("jittered gauge", vec![]),
+        ("random", vec![]),
+    ];
+    for i in 0..POINT_NUM {
+        // Scrapes are mostly on time, a few are late by some milliseconds.
+        let jitter = if random::<u8>() < 8 {
+            random::<i64>().rem_euclid(200)
+        } else {
+            0
+        };
+        gauge = (gauge + random::<f64>() - 0.5_f64).clamp(0.0, 100.0);
+        counter += (random::<u32>() % 100) as f64;
+
+        let rounded = (gauge * 100.0).round() / 100.0;
+        series[0].1.push(Point::new(timestamp, 1.0));
+        series[1].1.push(Point::new(timestamp, counter));
+        series[2].1.push(Point::new(timestamp, rounded));
+        series[3].1.push(Point::new(timestamp + jitter, rounded));
+        series[4].1.push(Point::new(start + i as i64, random()));
+        timestamp += INTERVAL;
+    }
+
+    for (name, points) in series {
+        let mut item = SeriesItem::default();
+        let now = Instant::now();
+        for point in &points {
+            item.append(point.timestamp, point.value);
+        }
+        let encode_cost = now.elapsed().as_millis();
+
+        let now = Instant::now();
+        let decoded: Vec<Point> = item.points().expect("corrupted block").collect();
+        let decode_cost = now.elapsed().as_millis();
+        assert_eq!(decoded, points, "{} does not round trip", name);
+
+        println!(
+            "{}: {:.2} bytes/point, ratio {:.3}, encode {} ms, decode {} ms",
+            name,
+            item.encoded_bytes() as f64 / item.len() as f64,
+            item.compression_ratio(),
+            encode_cost,
+            decode_cost,
+        );
+    }
+}
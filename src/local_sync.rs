@@ -0,0 +1,183 @@
+//! Core-local Sync Primitives Runner
+//!
+//! Compares `runtime::sync` against the tokio primitives used by
+//! `load::local_set`, with all tasks on one thread.
+
+use std::rc::Rc;
+use std::time::Instant;
+use tokio::runtime::Builder;
+use tokio::task::LocalSet;
+
+use shard_affinity::*;
+
+const MESSAGE_NUM: usize = CONCURRENT_NUM * WRITE_LOOP_NUM;
+/// Fewer than the tasks, so they contend for permits.
+const PERMIT_NUM: usize = 4;
+
+fn main() {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        for i in 0..MESSAGE_NUM {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            tokio::task::spawn_local(async move { tx.send(i).unwrap() });
+            rx.await.unwrap();
+        }
+    }));
+    println!("tokio oneshot cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        for i in 0..MESSAGE_NUM {
+            let (tx, rx) = runtime::sync::oneshot::channel();
+            tokio::task::spawn_local(async move { tx.send(i).unwrap() });
+            rx.await.unwrap();
+        }
+    }));
+    println!("local oneshot cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::task::spawn_local(async move {
+            for i in 0..MESSAGE_NUM {
+                tx.send(i).unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        while rx.recv().await.is_some() {}
+    }));
+    println!("tokio mpsc cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let (tx, mut rx) = runtime::sync::mpsc::unbounded_channel();
+        tokio::task::spawn_local(async move {
+            for i in 0..MESSAGE_NUM {
+                tx.send(i).unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        while rx.recv().await.is_some() {}
+    }));
+    println!("local mpsc cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let mutex = Rc::new(tokio::sync::Mutex::new(0));
+        let handles: Vec<_> = (0..CONCURRENT_NUM)
+            .map(|_| {
+                let mutex = mutex.clone();
+                tokio::task::spawn_local(async move {
+                    for _ in 0..WRITE_LOOP_NUM {
+                        *mutex.lock().await += 1;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }));
+    println!("tokio mutex cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let mutex = Rc::new(runtime::sync::Mutex::new(0));
+        let handles: Vec<_> = (0..CONCURRENT_NUM)
+            .map(|_| {
+                let mutex = mutex.clone();
+                tokio::task::spawn_local(async move {
+                    for _ in 0..WRITE_LOOP_NUM {
+                        *mutex.lock().await += 1;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }));
+    println!("local mutex cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let semaphore = Rc::new(tokio::sync::Semaphore::new(PERMIT_NUM));
+        let handles: Vec<_> = (0..CONCURRENT_NUM)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                tokio::task::spawn_local(async move {
+                    for _ in 0..WRITE_LOOP_NUM {
+                        let _permit = semaphore.acquire().await.unwrap();
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }));
+    println!("tokio semaphore cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let semaphore = Rc::new(runtime::sync::Semaphore::new(PERMIT_NUM));
+        let handles: Vec<_> = (0..CONCURRENT_NUM)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                tokio::task::spawn_local(async move {
+                    for _ in 0..WRITE_LOOP_NUM {
+                        let _permit = semaphore.acquire(1).await;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }));
+    println!("local semaphore cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let ping = Rc::new(tokio::sync::Notify::new());
+        let pong = Rc::new(tokio::sync::Notify::new());
+        let (notify, notified) = (ping.clone(), pong.clone());
+        let handle = tokio::task::spawn_local(async move {
+            for _ in 0..MESSAGE_NUM {
+                notify.notified().await;
+                notified.notify_one();
+            }
+        });
+        for _ in 0..MESSAGE_NUM {
+            ping.notify_one();
+            pong.notified().await;
+        }
+        handle.await.unwrap();
+    }));
+    println!("tokio notify cost {} ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    rt.block_on(LocalSet::new().run_until(async {
+        let ping = Rc::new(runtime::sync::Notify::new());
+        let pong = Rc::new(runtime::sync::Notify::new());
+        let (notify, notified) = (ping.clone(), pong.clone());
+        let handle = tokio::task::spawn_local(async move {
+            for _ in 0..MESSAGE_NUM {
+                notify.notified().await;
+                notified.notify_one();
+            }
+        });
+        for _ in 0..MESSAGE_NUM {
+            ping.notify_one();
+            pong.notified().await;
+        }
+        handle.await.unwrap();
+    }));
+    println!("local notify cost {} ms", now.elapsed().as_millis());
+}
@@ -1,10 +1,12 @@
 //! Threading Load Runner
 #![feature(test)]
 
+use cache::{BlockPool, Cache, CacheCell, LocalCache, LocalPool, SharedPool, SyncMode};
 use futures::future::join_all;
 use load::ThreadingLoad;
 use rand::random;
 use std::sync::Arc;
+use std::thread;
 use std::time::Instant;
 use tokio::runtime::Builder;
 
@@ -27,12 +29,21 @@ fn main() {
             let load = load.clone();
             write_handles.push(rt.spawn(async move {
                 let id = random::<usize>() % MAX_ID;
-                load.append(id, bytes);
+                load.append(id, bytes).unwrap();
             }));
         }
         rt.block_on(join_all(write_handles));
     }
     println!("write cost {} ms", now.elapsed().as_millis());
+    let stats = load.stats().unwrap().total();
+    println!(
+        "holding {} items in {} blocks, {} bytes used of {} allocated, fragmentation {:.3}",
+        stats.fragmentation.items,
+        stats.fragmentation.blocks,
+        stats.fragmentation.used,
+        stats.memory,
+        stats.fragmentation_ratio()
+    );
 
     let prof_guard = pprof::ProfilerGuard::new(100).unwrap();
     let now = Instant::now();
@@ -54,4 +65,123 @@ fn main() {
         let file = std::fs::File::create("flamegraph/threading.svg").unwrap();
         report.flamegraph(file).unwrap();
     };
+
+    shard_write_bench();
+    block_pool_bench();
+    local_cache_bench();
+}
+
+const SHARD_WRITERS: usize = 16;
+const SHARD_APPEND_NUM: usize = 1024 * 4;
+const SHARD_APPEND_SIZE: usize = 1024;
+
+/// Append to existing ids of one shard from many threads, once with every
+/// thread on the same id, once with each thread on ids of its own. Only the
+/// first contends on an item's lock.
+fn shard_write_bench() {
+    let cache = Cache::default();
+    for id in 0..MAX_ID {
+        cache.append(id, vec![0; SHARD_APPEND_SIZE]).unwrap();
+    }
+
+    for (name, shared) in [("one shared id", true), ("ids per thread", false)] {
+        let now = Instant::now();
+        thread::scope(|scope| {
+            for writer in 0..SHARD_WRITERS {
+                let cache = &cache;
+                scope.spawn(move || {
+                    let bytes = vec![1; SHARD_APPEND_SIZE];
+                    let ids = MAX_ID / SHARD_WRITERS;
+                    for _ in 0..SHARD_APPEND_NUM {
+                        let id = if shared {
+                            0
+                        } else {
+                            writer * ids + random::<usize>() % ids
+                        };
+                        cache.append(id, bytes.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        let elapsed = now.elapsed();
+        let appends = SHARD_WRITERS * SHARD_APPEND_NUM;
+        println!(
+            "shard write to {name}: {} ms, {:.0} appends/s",
+            elapsed.as_millis(),
+            appends as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+const POOL_ROUNDS: usize = 64;
+const POOL_IDS: usize = 256;
+const POOL_APPEND_SIZE: usize = 4096 * 16;
+
+/// Fill and drop items over and over, taking blocks from the global allocator
+/// and from pools.
+fn block_pool_bench() {
+    fn churn<P: BlockPool>(name: &str, cache: Cache<usize, Vec<u8>, P>) {
+        let bytes = vec![1; POOL_APPEND_SIZE];
+        let now = Instant::now();
+        for _ in 0..POOL_ROUNDS {
+            for id in 0..POOL_IDS {
+                cache.append(id, bytes.clone()).unwrap();
+            }
+            for id in 0..POOL_IDS {
+                cache.remove(&id).unwrap();
+            }
+        }
+        println!(
+            "block churn with {name}: {} ms, {:?}",
+            now.elapsed().as_millis(),
+            cache.block_pool().stats()
+        );
+    }
+
+    churn("heap", Cache::default());
+    churn(
+        "shared pool",
+        Cache::default().with_block_pool(SharedPool::default()),
+    );
+    churn(
+        "shared pool on huge pages",
+        Cache::default().with_block_pool(SharedPool::default().with_huge_pages()),
+    );
+    churn(
+        "local pool",
+        Cache::default().with_block_pool(LocalPool::default()),
+    );
+}
+
+const LOCAL_OPS: usize = 1024 * 1024;
+const LOCAL_IDS: usize = 1024;
+const LOCAL_APPEND_SIZE: usize = 64;
+
+/// Small appends and lookups from one thread, as a shard does, on a cache taking
+/// locks and on one that doesn't.
+fn local_cache_bench() {
+    fn ops<S: SyncMode>(name: &str, cache: CacheCell<usize, Vec<u8>, LocalPool, S>) {
+        let bytes = vec![1; LOCAL_APPEND_SIZE];
+        let now = Instant::now();
+        for i in 0..LOCAL_OPS {
+            let id = i % LOCAL_IDS;
+            cache.append(id, bytes.clone()).unwrap();
+            cache.len(&id).unwrap();
+        }
+        let elapsed = now.elapsed();
+        println!(
+            "single thread ops on {name}: {} ms, {:.0} ops/s",
+            elapsed.as_millis(),
+            (LOCAL_OPS * 2) as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    ops(
+        "cache",
+        Cache::default().with_block_pool(LocalPool::default()),
+    );
+    ops(
+        "local cache",
+        LocalCache::default().with_block_pool(LocalPool::default()),
+    );
 }